@@ -1,19 +1,31 @@
 use crossbeam_channel::{select_biased, Receiver, Sender};
+use wg_2024::network::NodeId;
 use wg_2024::packet::{Packet, PacketType};
 
+// 8 MiB of message, well above the biggest upload. A corrupted header can't make the node
+// allocate more than this for one session
+pub const MAX_FRAGMENTS: u64 = 65_536;
+
 pub struct DataAssembly {
     session_id: u64,
-    data: Vec<u8>,
-    total_fragments: u64,
-    current_fragment_index: u64,
+    route: Vec<NodeId>,
+    fragments: Vec<Option<Vec<u8>>>, // indexed by fragment_index, so the arrival order doesn't matter
+    received_fragments: u64,
+}
+
+// A whole message, together with where it came from
+pub struct AssembledMessage {
+    pub session_id: u64,
+    pub route: Vec<NodeId>, // hops of the first fragment, the sender is the first one
+    pub data: Vec<u8>,
 }
 
 pub struct Assembler {
     pub assemblies: Vec<DataAssembly>,
     pub packet_send: Sender<Packet>,
     pub packet_recv: Receiver<Packet>,
-    pub result_send: Sender<Vec<u8>>,
-    pub result_recv: Receiver<Vec<u8>>,
+    pub result_send: Sender<AssembledMessage>,
+    pub result_recv: Receiver<AssembledMessage>,
 }
 
 impl Assembler {
@@ -21,8 +33,8 @@ impl Assembler {
         assemblies: Vec<DataAssembly>,
         packet_send: Sender<Packet>,
         packet_recv: Receiver<Packet>,
-        result_send: Sender<Vec<u8>>,
-        result_recv: Receiver<Vec<u8>>,
+        result_send: Sender<AssembledMessage>,
+        result_recv: Receiver<AssembledMessage>,
     ) -> Self {
         Self {
             assemblies,
@@ -55,87 +67,85 @@ impl Assembler {
 
         match packet.pack_type {
             PacketType::MsgFragment(fragment) => {
-                if fragment.total_n_fragments == 0 {
-                    debug!("Received fragment with total_n_fragments == 0, ignoring");
+                if fragment.total_n_fragments == 0 || fragment.total_n_fragments > MAX_FRAGMENTS {
+                    debug!(
+                        "Received fragment with total_n_fragments == {}, ignoring",
+                        fragment.total_n_fragments
+                    );
+                    return;
+                }
+                if fragment.length as usize > fragment.data.len() {
+                    debug!(
+                        "Received fragment with length {} for session_id: {}, ignoring",
+                        fragment.length, session_id
+                    );
+                    return;
+                }
+                if fragment.fragment_index >= fragment.total_n_fragments {
+                    debug!(
+                        "Received fragment {} of {} for session_id: {}, ignoring",
+                        fragment.fragment_index, fragment.total_n_fragments, session_id
+                    );
+                    return;
+                }
+
+                // Check if the fragment has an assembly in progress, or create a new one
+                let index = match self
+                    .assemblies
+                    .iter()
+                    .position(|a| a.session_id == session_id)
+                {
+                    Some(index) => index,
+                    None => {
+                        self.assemblies.push(DataAssembly {
+                            session_id,
+                            route: packet.routing_header.hops.clone(),
+                            fragments: vec![None; fragment.total_n_fragments as usize],
+                            received_fragments: 0,
+                        });
+                        debug!("New assembly created for session_id: {}", session_id);
+                        self.assemblies.len() - 1
+                    }
+                };
+
+                let assembly = &mut self.assemblies[index];
+                if assembly.fragments.len() as u64 != fragment.total_n_fragments {
+                    debug!(
+                        "Fragment for session_id: {} disagrees on total_n_fragments, ignoring",
+                        session_id
+                    );
                     return;
                 }
-                // If total_n_fragments is 1, we can directly send back the data
-                if fragment.total_n_fragments == 1 {
-                    match self.result_send.send(fragment.data.to_vec().clone()) {
+
+                // Duplicates of an already received fragment are ignored
+                let slot = &mut assembly.fragments[fragment.fragment_index as usize];
+                if slot.is_none() {
+                    *slot = Some(fragment.data[..fragment.length as usize].to_vec());
+                    assembly.received_fragments += 1;
+                }
+
+                if assembly.received_fragments == fragment.total_n_fragments {
+                    // All fragments received, process the data
+                    let assembly = self.assemblies.remove(index);
+                    let assembled = AssembledMessage {
+                        session_id,
+                        route: assembly.route,
+                        data: assembly.fragments.into_iter().flatten().flatten().collect(),
+                    };
+                    match self.result_send.send(assembled) {
                         Ok(_) => {
                             debug!(
-                                "Assembled data sent successfully for session_id: {}",
+                                "Assembled data for session_id: {} sent successfully",
                                 session_id
                             );
                         }
                         Err(_e) => {
                             debug!(
-                                "Failed to send single fragment data for session_id: {}: {}",
+                                "Failed to send assembled data for session_id: {}: {}",
                                 session_id, _e
                             );
                         }
                     }
-                    return;
-                }
-
-                // Check if the fragment has an assembly in progress
-                let assembly = self
-                    .assemblies
-                    .iter_mut()
-                    .find(|a| a.session_id == session_id);
-
-                if let Some(assembly) = assembly {
-                    assembly.data.extend(fragment.data.clone());
-                    assembly.current_fragment_index += 1;
-
-                    if assembly.current_fragment_index == assembly.total_fragments {
-                        // All fragments received, process the data
-                        match self.result_send.send(assembly.data.clone()) {
-                            Ok(_) => {
-                                debug!(
-                                    "Assembled data for session_id: {} sent successfully",
-                                    session_id
-                                );
-                            }
-                            Err(_e) => {
-                                debug!(
-                                    "Failed to send assembled data for session_id: {}: {}",
-                                    session_id, _e
-                                );
-                            }
-                        }
-                    }
-                } else {
-                    // No assembly in progress, create a new one
-                    let mut new_assembly = DataAssembly {
-                        session_id,
-                        data: Vec::new(),
-                        total_fragments: fragment.total_n_fragments,
-                        current_fragment_index: 1,
-                    };
-                    new_assembly.data.extend(fragment.data.clone());
-
-                    // Check if this is the last fragment
-                    if new_assembly.current_fragment_index == new_assembly.total_fragments {
-                        // All fragments received, process the data
-                        match self.result_send.send(new_assembly.data.clone()) {
-                            Ok(_) => {
-                                debug!(
-                                    "Assembled data for session_id: {} sent successfully",
-                                    session_id
-                                );
-                            }
-                            Err(_e) => {
-                                debug!(
-                                    "Failed to send assembled data for session_id: {}: {}",
-                                    session_id, _e
-                                );
-                            }
-                        }
-                    }
-
-                    self.assemblies.push(new_assembly);
-                    debug!("New assembly created for session_id: {}", session_id);
                 }
             }
             _ => {
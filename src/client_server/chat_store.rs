@@ -0,0 +1,277 @@
+#[cfg(feature = "debug")]
+use crate::debug;
+
+use crate::client_server::network_core::{ChatMessage, DEFAULT_ROOM};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use wg_2024::network::NodeId;
+
+// One entry of the chat log, replayed in order to rebuild the server state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChatRecord {
+    Registered(NodeId), // also joins the default room
+    Unregistered(NodeId), // also leaves every room
+    Message(ChatMessage),
+    Edited { id: u64, content: String },
+    Deleted(u64),
+    RoomCreated(String),
+    Joined { client: NodeId, room: String },
+    Left { client: NodeId, room: String },
+    AttachmentStored(u64), // written once its bytes are saved
+}
+
+// What a CommunicationServer starts from
+#[derive(Debug, Default)]
+pub struct ChatState {
+    pub registered_clients: HashSet<NodeId>,
+    pub rooms: HashMap<String, HashSet<NodeId>>, // room name -> members
+    pub messages: Vec<ChatMessage>,
+    pub attachments: HashSet<u64>,
+}
+
+impl ChatState {
+    pub fn new() -> Self {
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), HashSet::new());
+        Self {
+            registered_clients: HashSet::new(),
+            rooms,
+            messages: Vec::new(),
+            attachments: HashSet::new(),
+        }
+    }
+}
+
+// Where a CommunicationServer keeps its chat across restarts
+pub trait ChatStore: Send {
+    fn load(&mut self) -> ChatState;
+    fn append(&mut self, record: &ChatRecord) -> Result<(), String>;
+    fn store_attachment(&mut self, id: u64, data: &[u8]) -> Result<(), String>;
+    fn load_attachment(&self, id: u64) -> Option<Vec<u8>>;
+}
+
+// Append-only log, one JSON record per line, one file per server
+// Attachments are kept as files in a directory next to the log
+pub struct JsonLinesChatStore {
+    path: PathBuf,
+    attachments_dir: PathBuf,
+}
+
+impl JsonLinesChatStore {
+    pub fn new(dir: &Path, server_id: NodeId) -> Self {
+        Self {
+            path: dir.join(format!("chat_server_{}.jsonl", server_id)),
+            attachments_dir: dir.join(format!("chat_server_{}_attachments", server_id)),
+        }
+    }
+
+    fn attachment_path(&self, id: u64) -> PathBuf {
+        self.attachments_dir.join(format!("{}.img", id))
+    }
+}
+
+impl ChatStore for JsonLinesChatStore {
+    fn load(&mut self) -> ChatState {
+        let mut state = ChatState::new();
+
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(_e) => {
+                debug!("No chat history loaded from {:?}: {}", self.path, _e);
+                return state;
+            }
+        };
+
+        let mut next_id = 0;
+        for line in BufReader::new(file).lines() {
+            let Ok(line) = line else { break };
+            // A line cut short by a crash is skipped, the rest of the log is still good
+            match serde_json::from_str::<ChatRecord>(&line) {
+                Ok(ChatRecord::Registered(client_id)) => {
+                    state.registered_clients.insert(client_id);
+                    state
+                        .rooms
+                        .entry(DEFAULT_ROOM.to_string())
+                        .or_default()
+                        .insert(client_id);
+                }
+                Ok(ChatRecord::Unregistered(client_id)) => {
+                    state.registered_clients.remove(&client_id);
+                    for members in state.rooms.values_mut() {
+                        members.remove(&client_id);
+                    }
+                }
+                Ok(ChatRecord::Message(mut message)) => {
+                    // Logs written before messages had ids get them in order
+                    if message.id < next_id {
+                        message.id = next_id;
+                    }
+                    next_id = message.id + 1;
+                    state.messages.push(message);
+                }
+                Ok(ChatRecord::Edited { id, content }) => {
+                    if let Some(message) = state.messages.iter_mut().find(|m| m.id == id) {
+                        message.content = content;
+                        message.edited = true;
+                    }
+                }
+                Ok(ChatRecord::Deleted(id)) => {
+                    if let Some(message) = state.messages.iter_mut().find(|m| m.id == id) {
+                        message.content.clear();
+                        message.deleted = true;
+                    }
+                }
+                Ok(ChatRecord::RoomCreated(room)) => {
+                    state.rooms.entry(room).or_default();
+                }
+                Ok(ChatRecord::Joined { client, room }) => {
+                    state.rooms.entry(room).or_default().insert(client);
+                }
+                Ok(ChatRecord::Left { client, room }) => {
+                    if let Some(members) = state.rooms.get_mut(&room) {
+                        members.remove(&client);
+                    }
+                }
+                Ok(ChatRecord::AttachmentStored(id)) => {
+                    state.attachments.insert(id);
+                }
+                Err(_e) => {
+                    debug!("Skipping chat record in {:?}: {}", self.path, _e);
+                }
+            }
+        }
+
+        debug!(
+            "Loaded {} clients, {} rooms and {} messages from {:?}",
+            state.registered_clients.len(),
+            state.rooms.len(),
+            state.messages.len(),
+            self.path
+        );
+        state
+    }
+
+    fn append(&mut self, record: &ChatRecord) -> Result<(), String> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        let line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+        writeln!(file, "{}", line).map_err(|e| e.to_string())
+    }
+
+    fn store_attachment(&mut self, id: u64, data: &[u8]) -> Result<(), String> {
+        fs::create_dir_all(&self.attachments_dir).map_err(|e| e.to_string())?;
+        fs::write(self.attachment_path(id), data).map_err(|e| e.to_string())?;
+        self.append(&ChatRecord::AttachmentStored(id))
+    }
+
+    fn load_attachment(&self, id: u64) -> Option<Vec<u8>> {
+        fs::read(self.attachment_path(id)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, room: &str, sender_id: NodeId, content: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            room: room.to_string(),
+            sender_id,
+            content: content.to_string(),
+            sent_at: None,
+            stored_at: 0,
+            edited: false,
+            deleted: false,
+            origin: None,
+            attachment: None,
+        }
+    }
+
+    fn temp_store() -> (PathBuf, JsonLinesChatStore) {
+        let dir = std::env::temp_dir().join(format!("chat_store_test_{}", rand::random::<u64>()));
+        let store = JsonLinesChatStore::new(&dir, 1);
+        (dir, store)
+    }
+
+    #[test]
+    fn replay_rebuilds_the_chat() {
+        let (dir, mut store) = temp_store();
+        let records = [
+            ChatRecord::Registered(10),
+            ChatRecord::Registered(11),
+            ChatRecord::Registered(12),
+            ChatRecord::RoomCreated("rust".to_string()),
+            ChatRecord::Joined { client: 10, room: "rust".to_string() },
+            ChatRecord::Joined { client: 11, room: "rust".to_string() },
+            ChatRecord::Message(message(0, DEFAULT_ROOM, 10, "hello")),
+            ChatRecord::Message(message(1, "rust", 11, "typo")),
+            ChatRecord::Message(message(2, "rust", 10, "secret")),
+            ChatRecord::Edited { id: 1, content: "fixed".to_string() },
+            ChatRecord::Deleted(2),
+            ChatRecord::Left { client: 11, room: "rust".to_string() },
+            ChatRecord::Unregistered(12),
+        ];
+        for record in &records {
+            store.append(record).unwrap();
+        }
+        store.store_attachment(0, &[1, 2, 3]).unwrap();
+
+        let state = JsonLinesChatStore::new(&dir, 1).load();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(state.registered_clients, HashSet::from([10, 11]));
+        assert_eq!(state.rooms[DEFAULT_ROOM], HashSet::from([10, 11]));
+        assert_eq!(state.rooms["rust"], HashSet::from([10]));
+        assert_eq!(state.attachments, HashSet::from([0]));
+
+        let contents: Vec<(&str, bool, bool)> = state
+            .messages
+            .iter()
+            .map(|m| (m.content.as_str(), m.edited, m.deleted))
+            .collect();
+        assert_eq!(
+            contents,
+            vec![("hello", false, false), ("fixed", true, false), ("", false, true)]
+        );
+    }
+
+    #[test]
+    fn replay_skips_a_truncated_record() {
+        let (dir, mut store) = temp_store();
+        store.append(&ChatRecord::Registered(10)).unwrap();
+        store.append(&ChatRecord::Message(message(0, DEFAULT_ROOM, 10, "kept"))).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&store.path).unwrap();
+        write!(file, "{{\"Message\":{{\"sender_id\":10,").unwrap();
+
+        let state = store.load();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(state.messages.len(), 1);
+        assert_eq!(state.messages[0].content, "kept");
+        assert!(state.registered_clients.contains(&10));
+    }
+
+    #[test]
+    fn replay_numbers_messages_of_old_logs() {
+        let (dir, mut store) = temp_store();
+        for content in ["first", "second"] {
+            store.append(&ChatRecord::Message(message(0, DEFAULT_ROOM, 10, content))).unwrap();
+        }
+
+        let state = store.load();
+        fs::remove_dir_all(&dir).ok();
+
+        let ids: Vec<u64> = state.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![0, 1]);
+    }
+}
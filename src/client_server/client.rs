@@ -1,25 +1,35 @@
 #[cfg(feature = "debug")]
 use crate::debug;
 
+use crate::assembler::assembler::AssembledMessage;
 use crate::client_server::network_core::{
-    ClientEvent, ClientServerCommand, ContentType, NetworkNode, ServerType,
+    default_room, finish_key_exchange, key_exchange_public_key, new_key_exchange, Attachment, HEARTBEAT_INTERVAL,
+    ClientEvent, ClientServerCommand, ContentType, NetworkNode, NodeState, ProtocolFeature, SentMessages, SessionKey,
+    ServerCapabilities, ServerType,
 };
 use crate::message::message::{
-    ChatRequest, ChatResponse, MediaRequest, MediaResponse, MediaResponseForMessageContent,
-    Message, MessageContent, ServerTypeRequest, ServerTypeResponse, TextRequest, TextResponse,
+    AttachmentData, ChatRequest, ChatResponse, DroneSend, Envelope, HistoryRange, MediaRequest, MediaResponse, Message,
+    MessageContent, ProtocolMessage, RequestMessage, ResponseMessage, ServerTypeRequest,
+    TextRequest, TextResponse, PROTOCOL_VERSION,
 };
-use crossbeam_channel::{select_biased, Receiver, Sender};
+use chrono::Utc;
+use crossbeam_channel::{select_biased, tick, Receiver, Sender};
 use rand::random;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::thread;
+use std::time::{Duration, Instant};
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{
     FloodRequest, NodeType, Packet, PacketType,
 };
+use x25519_dalek::EphemeralSecret;
 
 const MAX_FAILED_TRY: u8 = 50;
 const FLOOD_DELAY: u64 = 300;
+const CHAT_HISTORY_ON_REGISTER: usize = 50;
+const FILE_REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+const FILE_REQUEST_CHECK_INTERVAL: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerTypeWithSessionId {
@@ -28,6 +38,13 @@ pub enum ServerTypeWithSessionId {
     CommunicationServer,
 }
 
+// A text or media request waiting for an answer, moved to another replica if it fails
+struct FileRequest {
+    server_id: NodeId,
+    tried: HashSet<NodeId>,
+    sent_at: Instant,
+}
+
 pub struct Client {
     id: NodeId,
     connected_drone_ids: HashSet<NodeId>,
@@ -37,10 +54,21 @@ pub struct Client {
     packet_send: HashMap<NodeId, Sender<Packet>>,
     packet_recv: Receiver<Packet>,
     topology_map: HashSet<(NodeId, Vec<NodeId>)>,
-    server_type_map: HashMap<NodeId, Option<ServerType>>,
+    server_type_map: HashMap<NodeId, Option<ServerCapabilities>>, // filled by the handshake
     failed_server_type: (HashSet<u64>, HashMap<NodeId, u8>), // (failed server type session id, (NodeId, n. failures))
     assembler_send: Sender<Packet>,
-    assembler_res_recv: Receiver<Vec<u8>>,
+    assembler_res_recv: Receiver<AssembledMessage>,
+    sent_messages: SentMessages, // kept to answer resend requests
+    session_keys: HashMap<NodeId, SessionKey>, // agreed in the handshake, kept across floods
+    pending_key_exchanges: HashMap<NodeId, EphemeralSecret>, // handshakes waiting for the server key, retries included
+    registered_servers: HashSet<NodeId>, // communication servers that get a heartbeat
+    pending_uploads: HashMap<u64, (String, String)>, // upload id -> (room, caption) to send once the image is stored
+    replicas: HashMap<(ContentType, u64), HashSet<NodeId>>, // servers known to hold each file
+    file_requests: HashMap<(ContentType, u64), FileRequest>,
+    downloaded_media: HashSet<u64>,
+    pending_media_lists: HashMap<NodeId, Instant>, // media servers whose list was requested, and when
+    unindexed_images: HashSet<u64>, // images waiting for a media list that says where they are
+    unresolved_texts: HashMap<(NodeId, u64), (Vec<u64>, HashSet<u64>)>, // (server, text) -> (images, images still missing)
 }
 
 impl NetworkNode for Client {
@@ -56,12 +84,30 @@ impl NetworkNode for Client {
     fn topology_map_mut(&mut self) -> &mut HashSet<(NodeId, Vec<NodeId>)> {
         &mut self.topology_map
     }
+    fn sent_messages_mut(&mut self) -> &mut SentMessages {
+        &mut self.sent_messages
+    }
+    fn session_key(&self, node_id: NodeId) -> Option<&SessionKey> {
+        self.session_keys.get(&node_id)
+    }
+    fn peer_version(&self, node_id: NodeId) -> Option<u16> {
+        match self.server_type_map.get(&node_id) {
+            Some(Some(capabilities)) => Some(capabilities.protocol_version),
+            _ => None,
+        }
+    }
 
     fn run(&mut self) {
         debug!("Client: {:?} started and waiting for packets", self.id);
+        let heartbeat = tick(HEARTBEAT_INTERVAL);
+        let file_request_check = tick(FILE_REQUEST_CHECK_INTERVAL);
         loop {
             select_biased! {
                 recv(self.controller_recv) -> command => {
+                    if let Ok(ClientServerCommand::Shutdown) = command {
+                        debug!("Client: {:?} shutting down", self.id);
+                        return;
+                    }
                     if let Ok(command) = command {
                         self.handle_command(command);
                     }
@@ -76,6 +122,12 @@ impl NetworkNode for Client {
                         self.handle_assembler_data(data);
                     }
                 },
+                recv(heartbeat) -> _ => {
+                    self.send_heartbeats();
+                },
+                recv(file_request_check) -> _ => {
+                    self.check_file_requests();
+                },
             }
         }
     }
@@ -106,6 +158,17 @@ impl NetworkNode for Client {
             })
             .expect("this is fine 🔥☕");
     }
+    fn send_integrity_failure_to_sc(&mut self, session_id: u64, route: Vec<NodeId>) {
+        self.controller_send
+            .send(ClientEvent::IntegrityCheckFailed { session_id, route })
+            .expect("this is fine 🔥☕");
+    }
+    fn use_compression(&self, target_node_id: NodeId) -> bool {
+        match self.server_type_map.get(&target_node_id) {
+            Some(Some(capabilities)) => capabilities.supports(ProtocolFeature::Compression),
+            _ => false,
+        }
+    }
 }
 
 impl Client {
@@ -118,10 +181,10 @@ impl Client {
         packet_send: HashMap<NodeId, Sender<Packet>>,
         packet_recv: Receiver<Packet>,
         topology_map: HashSet<(NodeId, Vec<NodeId>)>,
-        server_type_map: HashMap<NodeId, Option<ServerType>>,
+        server_type_map: HashMap<NodeId, Option<ServerCapabilities>>,
         session_ids_for_request_server_type: (HashSet<u64>, HashMap<NodeId, u8>),
         assembler_send: Sender<Packet>,
-        assembler_res_recv: Receiver<Vec<u8>>,
+        assembler_res_recv: Receiver<AssembledMessage>,
     ) -> Self {
         Self {
             id,
@@ -136,14 +199,31 @@ impl Client {
             failed_server_type: session_ids_for_request_server_type,
             assembler_send,
             assembler_res_recv,
+            sent_messages: SentMessages::new(),
+            session_keys: HashMap::new(),
+            pending_key_exchanges: HashMap::new(),
+            registered_servers: HashSet::new(),
+            pending_uploads: HashMap::new(),
+            replicas: HashMap::new(),
+            file_requests: HashMap::new(),
+            downloaded_media: HashSet::new(),
+            pending_media_lists: HashMap::new(),
+            unindexed_images: HashSet::new(),
+            unresolved_texts: HashMap::new(),
         }
     }
 
     fn run(&mut self) {
         debug!("Client: {:?} started and waiting for packets", self.id);
+        let heartbeat = tick(HEARTBEAT_INTERVAL);
+        let file_request_check = tick(FILE_REQUEST_CHECK_INTERVAL);
         loop {
             select_biased! {
                 recv(self.controller_recv) -> command => {
+                    if let Ok(ClientServerCommand::Shutdown) = command {
+                        debug!("Client: {:?} shutting down", self.id);
+                        return;
+                    }
                     if let Ok(command) = command {
                         self.handle_command(command);
                     }
@@ -158,6 +238,12 @@ impl Client {
                         self.handle_assembler_data(data);
                     }
                 },
+                recv(heartbeat) -> _ => {
+                    self.send_heartbeats();
+                },
+                recv(file_request_check) -> _ => {
+                    self.check_file_requests();
+                },
             }
         }
     }
@@ -235,11 +321,86 @@ impl Client {
                     self.failed_server_type
                 );
             },
+            ClientServerCommand::QueryNodeState => {
+                let state = NodeState {
+                    node_id: self.id,
+                    routes: self.known_routes(),
+                    server_types: self
+                        .server_type_map
+                        .iter()
+                        .map(|(server_id, capabilities)| {
+                            (*server_id, capabilities.as_ref().map(|c| c.server_type.clone()))
+                        })
+                        .collect(),
+                    registered_servers: self.registered_servers.clone(),
+                    registered_clients: HashSet::new(),
+                    connected_drones: self.connected_drone_ids.clone(),
+                    assembler_backlog: self.assembler_send.len() + self.assembler_res_recv.len(),
+                };
+                self.controller_send
+                    .send(ClientEvent::NodeState(state))
+                    .expect("this is fine 🔥☕");
+            },
+            ClientServerCommand::Shutdown => {}, // run returns before handling it
             
-            ClientServerCommand::SendChatMessage(node_id, msg) => {
+            ClientServerCommand::SendChatMessage(node_id, room, msg) => {
                 debug!("Client: {:?} received SendChatMessage command", self.id);
 
-                self.send_chat_message(node_id, msg);
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    // An [image_N] in the text is shown as the message image
+                    let attachment = Self::media_reference(&msg).map(Attachment::Media);
+                    self.send_chat_message(node_id, room, msg, attachment);
+                }
+            },
+            ClientServerCommand::SendChatAttachment(node_id, room, caption, data) => {
+                debug!("Client: {:?} received SendChatAttachment command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    // The message is sent once the server says where it stored the image
+                    let upload_id = random::<u64>();
+                    self.pending_uploads.insert(upload_id, (room, caption));
+                    self.send_chat_request(
+                        node_id,
+                        ChatRequest::UploadAttachment {
+                            upload_id,
+                            data: AttachmentData(data),
+                        },
+                    );
+                }
+            },
+            ClientServerCommand::RequestAttachment(node_id, attachment) => {
+                debug!("Client: {:?} received RequestAttachment command", self.id);
+
+                match attachment {
+                    Attachment::Upload(attachment_id) => {
+                        if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                            self.send_chat_request(node_id, ChatRequest::GetAttachment(attachment_id));
+                        }
+                    }
+                    Attachment::Media(image_id) => {
+                        self.request_images(vec![image_id]);
+                    }
+                }
+            },
+            ClientServerCommand::EditChatMessage(node_id, message_id, msg) => {
+                debug!("Client: {:?} received EditChatMessage command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(
+                        node_id,
+                        ChatRequest::Edit {
+                            id: message_id,
+                            message: msg,
+                        },
+                    );
+                }
+            },
+            ClientServerCommand::DeleteChatMessage(node_id, message_id) => {
+                debug!("Client: {:?} received DeleteChatMessage command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(node_id, ChatRequest::Delete(message_id));
+                }
             },
             ClientServerCommand::RequestServerType => {
                 debug!(
@@ -275,40 +436,101 @@ impl Client {
             ClientServerCommand::RegistrationRequest(node_id) => {
                 debug!("Client: {:?} received RegistrationRequest command", node_id);
 
-                self.send_registration_request(node_id);
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_registration_request(node_id);
+                }
+            },
+            ClientServerCommand::UnregistrationRequest(node_id) => {
+                debug!("Client: {:?} received UnregistrationRequest command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(node_id, ChatRequest::Unregister);
+                }
             },
             ClientServerCommand::RequestTextList(node_id) => {
                 debug!(
                     "Client: {:?} received RequestFileList, Server id: {:?}",
                     self.id, node_id
                 );
-                self.send_text_request_text_list(node_id);
+                if self.check_server_type(node_id, ServerType::ContentServer(ContentType::Text)) {
+                    self.send_text_request_text_list(node_id);
+                }
             },
             ClientServerCommand::RequestText(node_id, file_id) => {
                 debug!(
                     "Client: {:?} received RequestFile, Server id: {:?} file id: {:?}",
                     self.id, node_id, file_id
                 );
-                self.send_text_request_text(node_id, file_id);
+                if self.check_server_type(node_id, ServerType::ContentServer(ContentType::Text)) {
+                    self.request_file(ContentType::Text, file_id, node_id);
+                }
             },
             ClientServerCommand::RequestImage(node_id, image_id) => {
                 debug!(
                     "Client: {:?} received RequestImage, Server id: {:?} image id: {:?}",
                     self.id, node_id, image_id
                 );
-                self.send_image_request(node_id, image_id);
+                if self.check_server_type(node_id, ServerType::ContentServer(ContentType::Media)) {
+                    self.request_file(ContentType::Media, image_id, node_id);
+                }
             },
             ClientServerCommand::RequestImageList(node_id) => {
                 debug!(
                     "Client: {:?} received RequestImageList command, Server id: {:?}",
                     self.id, node_id
                 );
-                self.send_image_list_request(node_id);
+                if self.check_server_type(node_id, ServerType::ContentServer(ContentType::Media)) {
+                    self.send_image_list_request(node_id);
+                }
+            },
+            ClientServerCommand::UploadText(node_id, text) => {
+                debug!("Client: {:?} received UploadText command, Server id: {:?}", self.id, node_id);
+
+                if self.check_server_type(node_id, ServerType::ContentServer(ContentType::Text)) {
+                    self.send_content_request(node_id, TextRequest::Upload(text));
+                }
+            },
+            ClientServerCommand::UploadMedia(node_id, data) => {
+                debug!("Client: {:?} received UploadMedia command, Server id: {:?}", self.id, node_id);
+
+                if self.check_server_type(node_id, ServerType::ContentServer(ContentType::Media)) {
+                    self.send_content_request(node_id, MediaRequest::Upload(AttachmentData(data)));
+                }
             },
             ClientServerCommand::ClientListRequest(node_id) => {
                 debug!("Client: {:?} received ClientListRequest command", node_id);
 
-                self.send_client_list_request(node_id);
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_client_list_request(node_id);
+                }
+            },
+            ClientServerCommand::RoomListRequest(node_id) => {
+                debug!("Client: {:?} received RoomListRequest command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(node_id, ChatRequest::ListRooms);
+                }
+            },
+            ClientServerCommand::CreateRoom(node_id, room) => {
+                debug!("Client: {:?} received CreateRoom command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(node_id, ChatRequest::CreateRoom(room));
+                }
+            },
+            ClientServerCommand::JoinRoom(node_id, room) => {
+                debug!("Client: {:?} received JoinRoom command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(node_id, ChatRequest::JoinRoom(room));
+                }
+            },
+            ClientServerCommand::LeaveRoom(node_id, room) => {
+                debug!("Client: {:?} received LeaveRoom command", self.id);
+
+                if self.check_server_type(node_id, ServerType::CommunicationServer) {
+                    self.send_chat_request(node_id, ChatRequest::LeaveRoom(room));
+                }
             },
         }
     }
@@ -390,7 +612,7 @@ impl Client {
                     "Client: {:?} received a FloodResponse {:?}",
                     self.id, _flood_response
                 );
-                self.update_topology_with_flood_response(_flood_response, true);
+                self.update_topology_with_flood_response(_flood_response, &[NodeType::Server]);
 
                 // if it's a server add it to the server_type_map
                 let &(node_id, _node_type) = _flood_response.path_trace.last().unwrap();
@@ -400,151 +622,292 @@ impl Client {
             }
         }
     }
-    fn handle_assembler_data(&mut self, data: Vec<u8>) {
-        if let Ok(str_data_raw) = String::from_utf8(data.clone()) {
-            debug!(
-                "Client {:?} received assembled message: {:?}",
-                self.id, str_data_raw
-            );
+    fn handle_assembler_data(&mut self, assembled: AssembledMessage) {
+        if !Envelope::verify_checksum(&assembled.data) {
+            self.handle_corrupted_message(assembled);
+            return;
+        }
+        let envelope = match Envelope::decode(&assembled.data)
+            .and_then(|envelope| self.open_envelope(envelope))
+        {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                self.reply_to_protocol_error(e);
+                return;
+            }
+        };
+        debug!(
+            "Client {:?} received assembled message: {:?}",
+            self.id, envelope
+        );
 
-            if let Some(str_data) = str_data_raw.split("\0").nth(0) {
-                // Try to parse as ServerTypeResponse
-                if let Ok(message) = serde_json::from_str::<Message<ServerTypeResponse>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
-                    }
-
-                    match &message.content {
-                        ServerTypeResponse::ServerType(server_type) => {
-                            debug!(
-                                "Client: {:?} received server type {:?} from {:?}",
-                                self.id, server_type, message.source_id
-                            );
-                            self.server_type_map.insert(message.source_id, Some(server_type.clone()));
-
-                            // remove the session id from the session_ids
-                            self.failed_server_type.0.remove(&message.session_id);
-                        }
+        // Send to SC
+        self.send_message_received_to_sc(MessageContent::from_protocol(&envelope.payload));
+
+        let source_id = envelope.source_id;
+        let session_id = envelope.session_id;
+        match envelope.payload {
+            ProtocolMessage::Response(ResponseMessage::ServerType(server_type_response)) => {
+                let server_public_key = server_type_response.public_key();
+                let answered_key = server_type_response.client_public_key();
+                let capabilities = server_type_response.into_capabilities();
+                debug!(
+                    "Client: {:?} received capabilities {:?} from {:?}",
+                    self.id, capabilities, source_id
+                );
+
+                // Only the answer to the pending key pair replaces the key, a late answer to an
+                // older handshake would give a key the server no longer has
+                let answers_pending = self
+                    .pending_key_exchanges
+                    .get(&source_id)
+                    .is_some_and(|secret| Some(key_exchange_public_key(secret)) == answered_key);
+                if answers_pending {
+                    self.session_keys.remove(&source_id);
+                    let secret = self.pending_key_exchanges.remove(&source_id);
+                    if let (true, Some(secret), Some(server_public_key)) = (
+                        capabilities.supports(ProtocolFeature::Encryption),
+                        secret,
+                        server_public_key,
+                    ) {
+                        self.session_keys
+                            .insert(source_id, finish_key_exchange(secret, server_public_key));
                     }
                 }
-                // Try to parse as TextResponse
-                else if let Ok(message) = serde_json::from_str::<Message<TextResponse>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
+                self.server_type_map.insert(source_id, Some(capabilities.clone()));
+                // Content servers tell where else their files can be found
+                match &capabilities.server_type {
+                    _ if !capabilities.is_compatible() => {
+                        debug!(
+                            "Client: {:?} can't talk to {:?}, it only speaks protocol v{}",
+                            self.id, source_id, capabilities.protocol_version
+                        );
+                    }
+                    ServerType::ContentServer(ContentType::Text) => {
+                        self.send_content_request(source_id, TextRequest::Replicas);
                     }
+                    ServerType::ContentServer(ContentType::Media) => {
+                        self.send_content_request(source_id, MediaRequest::Replicas);
+                    }
+                    ServerType::CommunicationServer => {}
+                }
+                self.send_message_received_to_sc(MessageContent::CapabilitiesWithServer(
+                    source_id,
+                    capabilities,
+                ));
 
-                    match message.content {
-                        TextResponse::TextList(_file_list) => {
-                            debug!("Client: {:?} received TextResponse::TextList from {:?} file list: {:?}", self.id, message.source_id, _file_list);
-                            self.send_message_received_to_sc(MessageContent::TextListWithServer(
-                                message.source_id,
-                                _file_list.clone(),
-                            ));
-                        }
-                        TextResponse::Text(_file) => {
-                            debug!(
-                                "Client: {:?} received TextResponse::Text from {:?} file: {:?}",
-                                self.id, message.source_id, _file
-                            );
-                            self.send_message_received_to_sc(MessageContent::TextIdWithServer(
-                                message.source_id,
-                                _file.0,
-                            ));
-                            self.extract_and_request_images(_file.1);
-                        }
-                        TextResponse::NotFound => {
-                            debug!(
-                                "Client: {:?} received TextResponse::NotFound from {:?}",
-                                self.id, message.source_id
-                            );
+                // remove the session id from the session_ids
+                self.failed_server_type.0.remove(&session_id);
+            }
+            ProtocolMessage::Response(ResponseMessage::Text(text_response)) => {
+                match text_response {
+                    TextResponse::TextList(_file_list) => {
+                        debug!("Client: {:?} received TextResponse::TextList from {:?} file list: {:?}", self.id, source_id, _file_list);
+                        self.add_replicas(ContentType::Text, source_id, &_file_list);
+                        self.send_message_received_to_sc(MessageContent::TextListWithServer(
+                            source_id,
+                            _file_list,
+                        ));
+                    }
+                    TextResponse::Text(_file) => {
+                        debug!(
+                            "Client: {:?} received TextResponse::Text from {:?} file: {:?}",
+                            self.id, source_id, _file
+                        );
+                        self.file_requests.remove(&(ContentType::Text, _file.0));
+                        self.send_message_received_to_sc(MessageContent::TextIdWithServer(
+                            source_id,
+                            _file.0,
+                        ));
+                        self.extract_and_request_images(source_id, _file.0, &_file.1);
+                    }
+                    TextResponse::NotFound(file_id) => {
+                        debug!(
+                            "Client: {:?} received TextResponse::NotFound from {:?} file id: {:?}",
+                            self.id, source_id, file_id
+                        );
+                        self.file_not_found(ContentType::Text, file_id, source_id);
+                    }
+                    TextResponse::Replicas(replicas) => {
+                        for (file_id, holders) in &replicas {
+                            self.add_replicas(ContentType::Text, source_id, &[*file_id]);
+                            for holder in holders {
+                                self.add_replicas(ContentType::Text, *holder, &[*file_id]);
+                            }
                         }
+                        self.send_message_received_to_sc(MessageContent::ReplicasWithServer(
+                            source_id, replicas,
+                        ));
+                    }
+                    // Refresh the list so the new file shows up
+                    TextResponse::Uploaded(_file_id) => {
+                        self.send_text_request_text_list(source_id);
+                    }
+                    TextResponse::UploadRejected(_reason) => {
+                        debug!(
+                            "Client: {:?} upload rejected by {:?}: {}",
+                            self.id, source_id, _reason
+                        );
                     }
                 }
-                // Try to parse as ChatRequest
-                else if let Ok(message) = serde_json::from_str::<Message<ChatResponse>>(&str_data)
-                {
-                    match &message.content {
-                        ChatResponse::ClientNotRegistered => {
-                            debug!("Client: {:?} received a ClientNotRegistered", self.id);
-                            self.send_message_received_to_sc(MessageContent::ChatResponse(
-                                ChatResponse::ClientNotRegistered,
-                            ));
-                        }
-                        ChatResponse::ClientRegistered(node_id) => {
-                            debug!("Client: {:?} received a ClientRegistered", self.id);
-                            self.send_message_received_to_sc(MessageContent::ChatResponse(
-                                ChatResponse::ClientRegistered(*node_id),
-                            ));
-                        }
-                        ChatResponse::ClientList(c) => {
-                            debug!("Client: {:?} received a ClientList", self.id);
-                            self.send_message_received_to_sc(MessageContent::ChatResponse(
-                                ChatResponse::ClientList(c.clone()),
-                            ));
+            }
+            ProtocolMessage::Response(ResponseMessage::Chat(chat_response)) => {
+                debug!(
+                    "Client: {:?} received {:?} from {:?}",
+                    self.id, chat_response, source_id
+                );
+                match chat_response {
+                    // Catch up with what was said before registering
+                    ChatResponse::ClientRegistered(server_id) => {
+                        self.registered_servers.insert(server_id);
+                        self.send_chat_history_request(
+                            server_id,
+                            default_room(),
+                            HistoryRange::Last(CHAT_HISTORY_ON_REGISTER),
+                        );
+                        self.send_chat_request(server_id, ChatRequest::ListRooms);
+                    }
+                    // Same for a room joined later
+                    ChatResponse::RoomJoined(room) => {
+                        self.send_chat_history_request(
+                            source_id,
+                            room,
+                            HistoryRange::Last(CHAT_HISTORY_ON_REGISTER),
+                        );
+                        self.send_chat_request(source_id, ChatRequest::ListRooms);
+                    }
+                    ChatResponse::ClientUnregistered(server_id) => {
+                        self.registered_servers.remove(&server_id);
+                    }
+                    // The server forgot about us, stop the heartbeat
+                    ChatResponse::ClientNotRegistered => {
+                        self.registered_servers.remove(&source_id);
+                    }
+                    ChatResponse::RoomLeft(_) => {
+                        self.send_chat_request(source_id, ChatRequest::ListRooms);
+                    }
+                    ChatResponse::RoomList(room_list) => {
+                        self.send_message_received_to_sc(MessageContent::RoomListWithServer(
+                            source_id, room_list,
+                        ));
+                    }
+                    ChatResponse::History(history) => {
+                        self.send_message_received_to_sc(MessageContent::ChatHistoryWithServer(
+                            source_id, history,
+                        ));
+                    }
+                    ChatResponse::AttachmentUploaded { upload_id, id } => {
+                        if let Some((room, caption)) = self.pending_uploads.remove(&upload_id) {
+                            self.send_chat_message(source_id, room, caption, Some(Attachment::Upload(id)));
                         }
-                        _ => {}
                     }
+                    ChatResponse::AttachmentRejected { upload_id, .. } => {
+                        self.pending_uploads.remove(&upload_id);
+                    }
+                    ChatResponse::Attachment(attachment_id, data) => {
+                        self.send_message_received_to_sc(MessageContent::AttachmentWithServer(
+                            source_id,
+                            attachment_id,
+                            data,
+                        ));
+                    }
+                    _ => {}
                 }
-                // try to parse as media response
-                else if let Ok(message) =
-                    serde_json::from_str::<Message<MediaResponse>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
+            }
+            ProtocolMessage::Response(ResponseMessage::Media(media_response)) => {
+                match media_response {
+                    MediaResponse::MediaList(media_list) => {
+                        self.add_replicas(ContentType::Media, source_id, &media_list);
+                        self.media_list_received(source_id);
+                        self.send_message_received_to_sc(MessageContent::MediaListWithServer(
+                            source_id,
+                            media_list,
+                        ));
                     }
-
-                    match message.content {
-                        MediaResponse::MediaList(media_list) => {
-                            self.send_message_received_to_sc(MessageContent::MediaListWithServer(
-                                message.source_id,
-                                media_list.clone(),
-                            ));
-                        }
-                        MediaResponse::Media(media_id, _media) => {
-                            self.send_message_received_to_sc(MessageContent::MediaResponse(
-                                MediaResponseForMessageContent::Media(media_id),
-                            ));
-                            debug!(
-                                "Client: {:?} received full media from media id {:?}: {:?}",
-                                self.id, message.source_id, media_id
-                            );
-                            self.send_message_received_to_sc(MessageContent::MediaIdWithServer(
-                                message.source_id,
-                                media_id,
-                            ));
-                        }
-                        MediaResponse::NotFound => {
-                            self.send_message_received_to_sc(MessageContent::MediaResponse(
-                                MediaResponseForMessageContent::NotFound,
-                            ));
-                            debug!(
-                                "Client: {:?} received NotFound from {:?}",
-                                self.id, message.source_id
-                            );
+                    MediaResponse::Media(media_id, _media) => {
+                        debug!(
+                            "Client: {:?} received full media from media id {:?}: {:?}",
+                            self.id, source_id, media_id
+                        );
+                        self.file_requests.remove(&(ContentType::Media, media_id));
+                        self.media_downloaded(media_id);
+                        self.send_message_received_to_sc(MessageContent::MediaIdWithServer(
+                            source_id,
+                            media_id,
+                        ));
+                    }
+                    MediaResponse::NotFound(media_id) => {
+                        debug!(
+                            "Client: {:?} received NotFound from {:?} media id: {:?}",
+                            self.id, source_id, media_id
+                        );
+                        self.file_not_found(ContentType::Media, media_id, source_id);
+                    }
+                    MediaResponse::Replicas(replicas) => {
+                        for (file_id, holders) in &replicas {
+                            self.add_replicas(ContentType::Media, source_id, &[*file_id]);
+                            for holder in holders {
+                                self.add_replicas(ContentType::Media, *holder, &[*file_id]);
+                            }
                         }
+                        self.send_message_received_to_sc(MessageContent::ReplicasWithServer(
+                            source_id, replicas,
+                        ));
+                    }
+                    MediaResponse::Uploaded(_media_id) => {
+                        self.send_image_list_request(source_id);
+                    }
+                    MediaResponse::UploadRejected(_reason) => {
+                        debug!(
+                            "Client: {:?} upload rejected by {:?}: {}",
+                            self.id, source_id, _reason
+                        );
                     }
-                } else {
-                    debug!(
-                        "Client: {:?} received unknown data: {:?}",
-                        self.id, str_data
-                    );
                 }
             }
+            ProtocolMessage::Response(ResponseMessage::Error(_error)) => {
+                debug!(
+                    "Client: {:?} received error {:?} from {:?}",
+                    self.id, _error, source_id
+                );
+            }
+            ProtocolMessage::Request(RequestMessage::Resend(resend_request)) => {
+                debug!(
+                    "Client: {:?} received resend request for session {:?} from {:?}",
+                    self.id, resend_request.session_id, source_id
+                );
+                self.resend_message(resend_request.session_id);
+            }
+            payload @ (ProtocolMessage::Request(_) | ProtocolMessage::Encrypted(_)) => {
+                debug!(
+                    "Client: {:?} received unexpected request: {:?}",
+                    self.id, payload
+                );
+                self.reply_unexpected_message(source_id, session_id, &payload);
+            }
         }
     }
 
     fn send_server_type_request(&mut self, server_id: NodeId) {
         // Create a server type request with random session ID
         let session_id = random::<u64>();
+        // Retries reuse the pending key pair, the server answers them all with the same key
+        let public_key = match self.pending_key_exchanges.get(&server_id) {
+            Some(secret) => key_exchange_public_key(secret),
+            None => {
+                let (secret, public_key) = new_key_exchange();
+                self.pending_key_exchanges.insert(server_id, secret);
+                public_key
+            }
+        };
         let message = Message {
             source_id: self.id,
             session_id,
-            content: ServerTypeRequest::GetServerType,
+            content: ServerTypeRequest::Handshake {
+                protocol_version: PROTOCOL_VERSION,
+                features: Self::supported_features(),
+                public_key: Some(public_key),
+            },
         };
         self.failed_server_type.0.insert(session_id);
         debug!(
@@ -553,6 +916,19 @@ impl Client {
         );
         self.send_message_in_fragments(server_id, session_id, message);
     }
+    fn send_chat_history_request(&mut self, server_id: NodeId, room: String, range: HistoryRange) {
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: ChatRequest::History { room, range },
+        };
+        debug!(
+            "Client: {:?} sending msg to server {:?}, msg: {:?}",
+            self.id, server_id, message
+        );
+        self.send_message_in_fragments(server_id, session_id, message);
+    }
     fn send_text_request_text_list(&mut self, server_id: NodeId) {
         let session_id = random::<u64>();
         let message = Message {
@@ -608,6 +984,20 @@ impl Client {
         self.send_message_in_fragments(server_id, session_id, message);
     }
 
+    fn send_content_request<T: DroneSend>(&mut self, server_id: NodeId, request: T) {
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: request,
+        };
+        debug!(
+            "Client: {:?} uploading to server {:?}, msg: {:?}",
+            self.id, server_id, message
+        );
+        self.send_message_in_fragments(server_id, session_id, message);
+    }
+
     fn send_registration_request(&mut self, server_id: NodeId) {
         debug!(
             "Client: {:?} requesting registration to server {:?}",
@@ -625,6 +1015,114 @@ impl Client {
         self.send_message_in_fragments(server_id, session_id, message);
     }
 
+    fn add_replicas(&mut self, content_type: ContentType, server_id: NodeId, file_ids: &[u64]) {
+        for file_id in file_ids {
+            self.replicas
+                .entry((content_type.clone(), *file_id))
+                .or_default()
+                .insert(server_id);
+        }
+    }
+
+    fn request_file(&mut self, content_type: ContentType, file_id: u64, server_id: NodeId) {
+        self.file_requests.insert(
+            (content_type.clone(), file_id),
+            FileRequest {
+                server_id,
+                tried: HashSet::from([server_id]),
+                sent_at: Instant::now(),
+            },
+        );
+        self.send_file_request(content_type, file_id, server_id);
+    }
+
+    fn send_file_request(&mut self, content_type: ContentType, file_id: u64, server_id: NodeId) {
+        match content_type {
+            ContentType::Text => self.send_text_request_text(server_id, file_id),
+            ContentType::Media => self.send_image_request(server_id, file_id),
+        }
+    }
+
+    fn file_not_found(&mut self, content_type: ContentType, file_id: u64, server_id: NodeId) {
+        let key = (content_type, file_id);
+        if let Some(request) = self.file_requests.get(&key) {
+            // An old answer from a replica already given up on
+            if request.server_id == server_id {
+                self.retry_file_request(key);
+            }
+        }
+    }
+
+    fn check_file_requests(&mut self) {
+        let timed_out: Vec<(ContentType, u64)> = self
+            .file_requests
+            .iter()
+            .filter(|(_, request)| request.sent_at.elapsed() > FILE_REQUEST_TIMEOUT)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in timed_out {
+            debug!("Client: {:?} request for {:?} timed out", self.id, key);
+            self.retry_file_request(key);
+        }
+
+        // A lost list, or its answer, must not keep the images waiting forever
+        let list_count = self.pending_media_lists.len();
+        self.pending_media_lists
+            .retain(|_, sent_at| sent_at.elapsed() <= FILE_REQUEST_TIMEOUT);
+        if self.pending_media_lists.len() < list_count {
+            self.give_up_unindexed_images();
+        }
+    }
+
+    // Sends the request to a replica not tried yet, or gives up if there is none
+    fn retry_file_request(&mut self, key: (ContentType, u64)) {
+        let Some(request) = self.file_requests.get_mut(&key) else {
+            return;
+        };
+        let expected = ServerType::ContentServer(key.0.clone());
+        let next_server = self
+            .replicas
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .filter(|server_id| !request.tried.contains(server_id))
+            .filter(|server_id| {
+                matches!(
+                    self.server_type_map.get(server_id),
+                    Some(Some(capabilities)) if capabilities.server_type == expected
+                )
+            })
+            .min()
+            .cloned();
+
+        match next_server {
+            Some(server_id) => {
+                debug!(
+                    "Client: {:?} asking replica {:?} for {:?}",
+                    self.id, server_id, key
+                );
+                request.server_id = server_id;
+                request.tried.insert(server_id);
+                request.sent_at = Instant::now();
+                self.send_file_request(key.0, key.1, server_id);
+            }
+            None => {
+                debug!("Client: {:?} no replica left for {:?}", self.id, key);
+                self.file_requests.remove(&key);
+                if key.0 == ContentType::Media {
+                    self.image_settled(key.1);
+                }
+            }
+        }
+    }
+
+    fn send_heartbeats(&mut self) {
+        let servers: Vec<NodeId> = self.registered_servers.iter().cloned().collect();
+        for server_id in servers {
+            self.send_chat_request(server_id, ChatRequest::Heartbeat);
+        }
+    }
+
     fn send_client_list_request(&mut self, server_id: NodeId) {
         debug!(
             "Client: {:?} requesting client list to server {:?}",
@@ -642,21 +1140,37 @@ impl Client {
         self.send_message_in_fragments(server_id, session_id, message);
     }
 
-    fn send_chat_message(&mut self, server_id: NodeId, content: String) {
+    fn send_chat_message(
+        &mut self,
+        server_id: NodeId,
+        room: String,
+        content: String,
+        attachment: Option<Attachment>,
+    ) {
         debug!(
-            "Client: {:?} sending message to server {:?}: {:?}",
-            self.id, server_id, content
+            "Client: {:?} sending message to room {:?} of server {:?}: {:?}",
+            self.id, room, server_id, content
         );
 
         // Create a chat message request
+        self.send_chat_request(
+            server_id,
+            ChatRequest::SendMessage {
+                from: self.id,
+                message: content,
+                sent_at: Some(Utc::now().timestamp_millis()),
+                room,
+                attachment,
+            },
+        );
+    }
+
+    fn send_chat_request(&mut self, server_id: NodeId, request: ChatRequest) {
         let session_id = random::<u64>();
         let message = Message {
             source_id: self.id,
             session_id,
-            content: ChatRequest::SendMessage {
-                from: self.id,
-                message: content,
-            },
+            content: request,
         };
 
         self.send_message_in_fragments(server_id, session_id, message);
@@ -679,29 +1193,177 @@ impl Client {
         }
     }
 
-    fn extract_and_request_images(&mut self, text: String) {
-        // Find all media servers
-        let media_servers: Vec<NodeId> = self.server_type_map
+    // Fetches the images a text refers to, each only once and from a server that has it
+    fn extract_and_request_images(&mut self, server_id: NodeId, text_id: u64, text: &str) {
+        let re = regex::Regex::new(r"\[image_(\d+)]").unwrap();
+        let mut images: Vec<u64> = Vec::new();
+        for cap in re.captures_iter(text) {
+            if let Some(image_id) = cap.get(1).and_then(|id| id.as_str().parse::<u64>().ok()) {
+                if !images.contains(&image_id) {
+                    images.push(image_id);
+                }
+            }
+        }
+
+        let missing: HashSet<u64> = images
             .iter()
-            .filter_map(|(id, server_type)| {
-                if let Some(ServerType::ContentServer(ContentType::Media)) = server_type {
-                    Some(*id)
-                } else {
-                    None
+            .filter(|image_id| !self.downloaded_media.contains(image_id))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            self.send_text_resolved(server_id, text_id, images);
+            return;
+        }
+        self.unresolved_texts
+            .insert((server_id, text_id), (images, missing.clone()));
+        self.request_images(missing);
+    }
+
+    fn request_images(&mut self, image_ids: impl IntoIterator<Item = u64>) {
+        let mut unindexed = false;
+        for image_id in image_ids {
+            // Already here or on its way
+            if self.downloaded_media.contains(&image_id)
+                || self.file_requests.contains_key(&(ContentType::Media, image_id))
+            {
+                continue;
+            }
+            match self.media_host(image_id) {
+                Some(server_id) => self.request_file(ContentType::Media, image_id, server_id),
+                None => {
+                    self.unindexed_images.insert(image_id);
+                    unindexed = true;
                 }
+            }
+        }
+        if unindexed {
+            self.request_missing_media_lists();
+        }
+    }
+
+    // Lowest id among the media servers known to host the image
+    fn media_host(&self, image_id: u64) -> Option<NodeId> {
+        let media_servers = self.media_servers();
+        self.replicas
+            .get(&(ContentType::Media, image_id))?
+            .iter()
+            .filter(|server_id| media_servers.contains(server_id))
+            .min()
+            .cloned()
+    }
+
+    fn media_servers(&self) -> Vec<NodeId> {
+        self.server_type_map
+            .iter()
+            .filter(|(_, capabilities)| {
+                capabilities.as_ref().is_some_and(|c| {
+                    c.server_type == ServerType::ContentServer(ContentType::Media)
+                })
             })
+            .map(|(server_id, _)| *server_id)
+            .collect()
+    }
+
+    // Lists are asked again every time, images may have been uploaded since the last one
+    fn request_missing_media_lists(&mut self) {
+        for server_id in self.media_servers() {
+            if !self.pending_media_lists.contains_key(&server_id) {
+                self.pending_media_lists.insert(server_id, Instant::now());
+                self.send_image_list_request(server_id);
+            }
+        }
+        self.give_up_unindexed_images();
+    }
+
+    fn media_list_received(&mut self, server_id: NodeId) {
+        self.pending_media_lists.remove(&server_id);
+
+        let indexed: Vec<u64> = self
+            .unindexed_images
+            .iter()
+            .filter(|image_id| self.media_host(**image_id).is_some())
+            .cloned()
+            .collect();
+        for image_id in &indexed {
+            self.unindexed_images.remove(image_id);
+        }
+        self.request_images(indexed);
+        self.give_up_unindexed_images();
+    }
+
+    // Every list is in, or timed out, and nobody has these images
+    fn give_up_unindexed_images(&mut self) {
+        if self.pending_media_lists.is_empty() && !self.unindexed_images.is_empty() {
+            debug!(
+                "Client: {:?} no media server has images {:?}",
+                self.id, self.unindexed_images
+            );
+            let given_up: Vec<u64> = self.unindexed_images.drain().collect();
+            for image_id in given_up {
+                self.image_settled(image_id);
+            }
+        }
+    }
+
+    fn media_downloaded(&mut self, media_id: u64) {
+        self.downloaded_media.insert(media_id);
+        self.image_settled(media_id);
+    }
+
+    // The image arrived or was given up, texts that waited only for it are resolved
+    fn image_settled(&mut self, image_id: u64) {
+        let mut resolved = Vec::new();
+        for (key, (_, missing)) in self.unresolved_texts.iter_mut() {
+            if missing.remove(&image_id) && missing.is_empty() {
+                resolved.push(*key);
+            }
+        }
+        for key in resolved {
+            if let Some((images, _)) = self.unresolved_texts.remove(&key) {
+                self.send_text_resolved(key.0, key.1, images);
+            }
+        }
+    }
+
+    fn send_text_resolved(&mut self, server_id: NodeId, text_id: u64, images: Vec<u64>) {
+        let missing = images
+            .iter()
+            .filter(|image_id| !self.downloaded_media.contains(image_id))
+            .cloned()
             .collect();
+        self.controller_send
+            .send(ClientEvent::TextResolved {
+                client_id: self.id,
+                server_id,
+                text_id,
+                images,
+                missing,
+            })
+            .expect("this is fine 🔥☕");
+    }
 
-        // Extract and request each image
+    // First [image_N] of a text, if any
+    fn media_reference(text: &str) -> Option<u64> {
         let re = regex::Regex::new(r"\[image_(\d+)]").unwrap();
-        for cap in re.captures_iter(&text) {
-            if let Some(image_id_str) = cap.get(1) {
-                if let Ok(image_id) = image_id_str.as_str().parse::<u64>() {
-                    // Request the image from all media servers
-                    for server_id in &media_servers {
-                        self.send_image_request(*server_id, image_id);
-                    }
-                }
+        re.captures(text)
+            .and_then(|cap| cap.get(1))
+            .and_then(|image_id| image_id.as_str().parse::<u64>().ok())
+    }
+
+    fn supported_features() -> HashSet<ProtocolFeature> {
+        HashSet::from([ProtocolFeature::Compression, ProtocolFeature::Encryption])
+    }
+
+    // Only let a command through if the handshake said the server can answer it
+    fn check_server_type(&self, server_id: NodeId, expected: ServerType) -> bool {
+        match self.server_type_map.get(&server_id) {
+            Some(Some(capabilities)) if capabilities.server_type == expected && capabilities.is_compatible() => true,
+            _server_type => {
+                debug!(
+                    "Client: {:?} refused command, server {:?} is {:?} but {:?} is needed",
+                    self.id, server_id, _server_type, expected
+                );
+                false
             }
         }
     }
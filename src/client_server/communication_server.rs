@@ -1,15 +1,26 @@
 #[cfg(feature = "debug")]
 use crate::debug;
 
+use crate::assembler::assembler::AssembledMessage;
+use crate::client_server::chat_store::{ChatRecord, ChatState, ChatStore};
 use crate::client_server::network_core::{
-    ChatMessage, ClientServerCommand, NetworkNode, ServerEvent, ServerType,
+    Attachment,
+    ChatMessage, ClientServerCommand, NetworkNode, NodeState, DEFAULT_ROOM, PRESENCE_TIMEOUT, ProtocolFeature, SentMessages, ServerCapabilities, ServerSessionKeys, SessionKey,
+    ServerEvent, ServerType,
 };
 use crate::message::message::*;
-use crossbeam_channel::{select_biased, Receiver, Sender};
+use chrono::Utc;
+use crossbeam_channel::{select_biased, tick, Receiver, Sender};
 use rand::random;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 use wg_2024::network::{NodeId, SourceRoutingHeader};
-use wg_2024::packet::{FloodRequest, NodeType, Packet, PacketType};
+use wg_2024::packet::{FloodRequest, NackType, NodeType, Packet, PacketType};
+
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_SEND_FAILURES: u8 = 3;
+const MAX_PENDING_DELIVERIES: usize = 100; // per client, the oldest are dropped first
+const MAX_ATTACHMENT_SIZE: usize = 256 * 1024;
 
 pub struct CommunicationServer {
     id: NodeId,
@@ -20,9 +31,24 @@ pub struct CommunicationServer {
     packet_send: HashMap<NodeId, Sender<Packet>>,
     packet_recv: Receiver<Packet>,
     assembler_send: Sender<Packet>,
-    assembler_res_recv: Receiver<Vec<u8>>,
+    assembler_res_recv: Receiver<AssembledMessage>,
+    sent_messages: SentMessages, // kept to answer resend requests
+    session_keys: ServerSessionKeys, // agreed in the handshake
     registered_clients: HashSet<NodeId>,
-    messages_stored: Vec<ChatMessage>,
+    offline_clients: HashSet<NodeId>, // registered but unreachable or silent
+    last_seen: HashMap<NodeId, Instant>, // last message from each registered client
+    send_failures: HashMap<NodeId, u8>, // consecutive failed deliveries to each client
+    pending_deliveries: HashMap<NodeId, VecDeque<ChatResponse>>, // messages with no path to the client yet
+    federation_peers: HashSet<NodeId>, // other communication servers, room messages are relayed to them
+    probed_servers: HashSet<NodeId>, // servers asked for their type since the last flood
+    rooms: HashMap<String, HashSet<NodeId>>, // room name -> members, the default room always exists
+    messages_stored: Vec<ChatMessage>, // all rooms, in id order
+    next_message_id: u64,
+    attachments: HashSet<u64>, // ids of the images kept by the chat store
+    next_attachment_id: u64,
+    chat_store: Box<dyn ChatStore>, // registrations and messages are appended here
+    client_features: HashMap<NodeId, HashSet<ProtocolFeature>>, // negotiated in the handshake
+    client_versions: HashMap<NodeId, u16>, // negotiated in the handshake
 }
 
 impl NetworkNode for CommunicationServer {
@@ -38,15 +64,29 @@ impl NetworkNode for CommunicationServer {
     fn topology_map_mut(&mut self) -> &mut HashSet<(NodeId, Vec<NodeId>)> {
         &mut self.topology_map
     }
+    fn sent_messages_mut(&mut self) -> &mut SentMessages {
+        &mut self.sent_messages
+    }
+    fn session_key(&self, node_id: NodeId) -> Option<&SessionKey> {
+        self.session_keys.get(node_id)
+    }
+    fn peer_version(&self, node_id: NodeId) -> Option<u16> {
+        self.client_versions.get(&node_id).copied()
+    }
 
     fn run(&mut self) {
         debug!(
             "Communication Server: {:?} started and waiting for packets",
             self.id
         );
+        let presence_tick = tick(PRESENCE_CHECK_INTERVAL);
         loop {
             select_biased! {
                 recv(self.controller_recv) -> command => {
+                    if let Ok(ClientServerCommand::Shutdown) = command {
+                        debug!("Communication Server: {:?} shutting down", self.id);
+                        return;
+                    }
                     if let Ok(command) = command {
                         self.handle_command(command);
                     }
@@ -62,6 +102,9 @@ impl NetworkNode for CommunicationServer {
                         self.handle_assembler_data(data);
                     }
                 },
+                recv(presence_tick) -> _ => {
+                    self.check_presence();
+                },
             }
         }
     }
@@ -92,6 +135,16 @@ impl NetworkNode for CommunicationServer {
             })
             .expect("this is fine 🔥☕");
     }
+    fn send_integrity_failure_to_sc(&mut self, session_id: u64, route: Vec<NodeId>) {
+        self.controller_send
+            .send(ServerEvent::IntegrityCheckFailed { session_id, route })
+            .expect("this is fine 🔥☕");
+    }
+    fn use_compression(&self, target_node_id: NodeId) -> bool {
+        self.client_features
+            .get(&target_node_id)
+            .is_some_and(|features| features.contains(&ProtocolFeature::Compression))
+    }
 }
 
 impl CommunicationServer {
@@ -104,10 +157,18 @@ impl CommunicationServer {
         packet_recv: Receiver<Packet>,
         topology_map: HashSet<(NodeId, Vec<NodeId>)>,
         assembler_send: Sender<Packet>,
-        assembler_res_recv: Receiver<Vec<u8>>,
-        registered_clients: HashSet<NodeId>,
-        messages_stored: Vec<ChatMessage>,
+        assembler_res_recv: Receiver<AssembledMessage>,
+        chat_state: ChatState,
+        chat_store: Box<dyn ChatStore>,
     ) -> Self {
+        let ChatState {
+            registered_clients,
+            mut rooms,
+            messages: messages_stored,
+            attachments,
+        } = chat_state;
+        rooms.entry(DEFAULT_ROOM.to_string()).or_default();
+
         Self {
             id,
             connected_drone_ids,
@@ -118,8 +179,24 @@ impl CommunicationServer {
             topology_map,
             assembler_send,
             assembler_res_recv,
+            // Nobody was seen yet, clients from previous runs come back online with their first message
+            offline_clients: registered_clients.clone(),
             registered_clients,
+            last_seen: HashMap::new(),
+            send_failures: HashMap::new(),
+            pending_deliveries: HashMap::new(),
+            federation_peers: HashSet::new(),
+            probed_servers: HashSet::new(),
+            rooms,
+            next_message_id: messages_stored.last().map_or(0, |m| m.id + 1),
+            next_attachment_id: attachments.iter().max().map_or(0, |id| id + 1),
+            attachments,
             messages_stored,
+            chat_store,
+            client_features: HashMap::new(),
+            client_versions: HashMap::new(),
+            sent_messages: SentMessages::new(),
+            session_keys: ServerSessionKeys::default(),
         }
     }
 
@@ -130,6 +207,8 @@ impl CommunicationServer {
 
                 // clear the hashmap
                 self.topology_map.clear();
+                // Peers are kept, the servers that didn't answer are asked again
+                self.probed_servers.clear();
 
                 // Generate a unique flood ID using current time
                 let timestamp = std::time::SystemTime::now()
@@ -164,9 +243,11 @@ impl CommunicationServer {
             ClientServerCommand::AddDrone(drone_id, sender) => {
                 self.connected_drone_ids.insert(drone_id);
                 self.packet_send.insert(drone_id, sender);
+                self.retry_pending_deliveries();
             },
             ClientServerCommand::RemoveDrone(drone_id) => {
                 self.connected_drone_ids.retain(|&id| id != drone_id);
+                self.retry_pending_deliveries();
             },
             ClientServerCommand::PrintAllNodeData => {
                 debug!(
@@ -174,22 +255,49 @@ impl CommunicationServer {
                     \nChat Server: {:?}\
                     \ntopology_map: {:?}\
                     \nregistered_clients: {:?}\
+                    \nrooms: {:?}\
+                    \nfederation_peers: {:?}\
                     \nmessage_store: {:?}\
                     \n",
-                    self.id, self.topology_map, self.registered_clients, self.messages_stored
+                    self.id, self.topology_map, self.registered_clients, self.rooms, self.federation_peers, self.messages_stored
                 );
             },
+            ClientServerCommand::QueryNodeState => {
+                let state = NodeState {
+                    node_id: self.id,
+                    routes: self.known_routes(),
+                    server_types: HashMap::new(),
+                    registered_servers: HashSet::new(),
+                    registered_clients: self.registered_clients.clone(),
+                    connected_drones: self.connected_drone_ids.clone(),
+                    assembler_backlog: self.assembler_send.len() + self.assembler_res_recv.len(),
+                };
+                self.controller_send
+                    .send(ServerEvent::NodeState(state))
+                    .expect("this is fine 🔥☕");
+            },
             _ => {}
         }
     }
     
     fn handle_packet(&mut self, packet: Packet) {
         match &packet.pack_type {
-            PacketType::Nack(_nack) => {
-                debug!("Server: {:?} received a Ack {:?}", self.id, _nack);
+            PacketType::Nack(nack) => {
+                debug!("Server: {:?} received a Nack {:?}", self.id, nack);
+
+                // A dropped fragment is bad luck, any other nack means the route to the client is broken
+                if !matches!(nack.nack_type, NackType::Dropped) {
+                    if let Some(client_id) = self.sent_messages.target(packet.session_id) {
+                        self.record_send_failure(client_id);
+                    }
+                }
             }
             PacketType::Ack(_ack) => {
                 debug!("Server: {:?} received a Ack {:?}", self.id, _ack);
+
+                if let Some(client_id) = self.sent_messages.target(packet.session_id) {
+                    self.send_failures.remove(&client_id);
+                }
             }
             PacketType::MsgFragment(_fragment) => {
                 debug!(
@@ -247,131 +355,308 @@ impl CommunicationServer {
                     "Server: {:?} received a FloodResponse {:?}",
                     self.id, _flood_response
                 );
-                self.update_topology_with_flood_response(_flood_response, false);
+                // Other servers are kept too, communication servers among them are federated
+                self.update_topology_with_flood_response(
+                    _flood_response,
+                    &[NodeType::Client, NodeType::Server],
+                );
+                if let Some(&(node_id, NodeType::Server)) = _flood_response.path_trace.last() {
+                    if node_id != self.id && self.probed_servers.insert(node_id) {
+                        self.send_server_type_probe(node_id);
+                    }
+                }
+
+                // A new path may reach clients that had none
+                self.retry_pending_deliveries();
             }
         }
     }
     
-    fn handle_assembler_data(&mut self, data: Vec<u8>) {
-        if let Ok(str_data_raw) = String::from_utf8(data.clone()) {
-            debug!(
-                "Server {:?} received assembled message: {:?}",
-                self.id, str_data_raw
-            );
+    fn handle_assembler_data(&mut self, assembled: AssembledMessage) {
+        if !Envelope::verify_checksum(&assembled.data) {
+            self.handle_corrupted_message(assembled);
+            return;
+        }
+        let envelope = match Envelope::decode(&assembled.data)
+            .and_then(|envelope| self.open_envelope(envelope))
+        {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                self.reply_to_protocol_error(e);
+                return;
+            }
+        };
+        debug!(
+            "Server {:?} received assembled message: {:?}",
+            self.id, envelope
+        );
 
-            // Try to parse as ServerTypeRequest
-            if let Some(str_data) = str_data_raw.split("\0").nth(0) {
-                if let Ok(message) = serde_json::from_str::<Message<ServerTypeRequest>>(&str_data) {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
-                    }
+        // Send to SC
+        self.send_message_received_to_sc(MessageContent::from_protocol(&envelope.payload));
 
-                    match message.content {
-                        ServerTypeRequest::GetServerType => {
-                            debug!(
-                                "Server: {:?} received ServerTypeRequest from {:?}",
-                                self.id, message.source_id
-                            );
-                            self.send_server_type_response(message.source_id, message.session_id);
-                        }
+        let source_id = envelope.source_id;
+        let session_id = envelope.session_id;
+        self.client_seen(source_id);
+        match envelope.payload {
+            ProtocolMessage::Request(RequestMessage::ServerType(server_type_request)) => {
+                match server_type_request {
+                    ServerTypeRequest::GetServerType => {
+                        debug!(
+                            "Server: {:?} received ServerTypeRequest from {:?}",
+                            self.id, source_id
+                        );
+                        self.send_server_type_response(source_id, session_id);
                     }
-                }
-                // Try to parse as TextRequest
-                else if let Ok(message) = serde_json::from_str::<Message<TextRequest>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
+                    ServerTypeRequest::Handshake {
+                        protocol_version,
+                        features,
+                        public_key,
+                    } => {
+                        debug!(
+                            "Server: {:?} received Handshake from {:?}, version: {:?}, features: {:?}",
+                            self.id, source_id, protocol_version, features
+                        );
+                        self.send_handshake_response(
+                            source_id,
+                            session_id,
+                            protocol_version,
+                            &features,
+                            public_key,
+                        );
                     }
+                }
+            }
+            ProtocolMessage::Request(RequestMessage::Text(text_request)) => match text_request {
+                TextRequest::TextList => {
+                    debug!(
+                        "Server: {:?} received TextRequest::TextList from {:?}",
+                        self.id, source_id
+                    );
+                    self.send_text_response(source_id, TextResponse::TextList(vec![]));
+                }
+                TextRequest::Text(file_id) => {
+                    debug!(
+                        "Server: {:?} received TextRequest::Text from {:?} file id: {:?}",
+                        self.id, source_id, file_id
+                    );
+                    self.send_text_response(source_id, TextResponse::NotFound(file_id));
+                }
+                TextRequest::Replicas => {
+                    self.send_text_response(source_id, TextResponse::Replicas(HashMap::new()));
+                }
+                TextRequest::Upload(_) => {
+                    debug!(
+                        "Server: {:?} received TextRequest::Upload from {:?}",
+                        self.id, source_id
+                    );
+                    self.send_text_response(
+                        source_id,
+                        TextResponse::UploadRejected("this is a chat server".to_string()),
+                    );
+                }
+            },
+            ProtocolMessage::Request(RequestMessage::Chat(chat_request)) => match chat_request {
+                ChatRequest::Register(client_id) => {
+                    debug!(
+                        "Server: {:?} received registration request from client {:?}",
+                        self.id, client_id
+                    );
 
-                    match message.content {
-                        TextRequest::TextList => {
-                            debug!(
-                                "Server: {:?} received TextRequest::TextList from {:?}",
-                                self.id, message.source_id
-                            );
-                            self.send_text_response(message.source_id, message.session_id);
-                        }
-                        TextRequest::Text(_file_id) => {
-                            debug!(
-                                "Server: {:?} received TextRequest::Text from {:?} file id: {:?}",
-                                self.id, message.source_id, _file_id
-                            );
-                            self.send_text_response(message.source_id, message.session_id);
-                        }
+                    // Insert client in registered_clients, registering also joins the default room.
+                    if self.registered_clients.insert(client_id) {
+                        self.store_chat_record(ChatRecord::Registered(client_id));
                     }
+                    self.rooms
+                        .entry(DEFAULT_ROOM.to_string())
+                        .or_default()
+                        .insert(client_id);
+
+                    self.store_new_message(
+                        DEFAULT_ROOM.to_string(),
+                        client_id,
+                        String::from(format!("Client {} has entered the chatroom", client_id)),
+                        None,
+                    );
+
+                    // Registering again brings an offline client back
+                    self.offline_clients.remove(&client_id);
+                    self.last_seen.insert(client_id, Instant::now());
+
+                    // Sends to simulation controller the whole chatroom.
+                    self.send_chatroom_to_sc();
+
+                    // Respond to client with ClientRegistered
+                    self.send_chat_response(client_id, ChatResponse::ClientRegistered(self.id));
+
+                    debug!(
+                        "Server: {:?} now has registered client: {:?}",
+                        self.id, client_id
+                    );
                 }
-                // Then try to parse as ChatRequest
-                else if let Ok(message) = serde_json::from_str::<Message<ChatRequest>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
-                    }
 
-                    match message.content {
-                        ChatRequest::Register(client_id) => {
-                            debug!(
-                                "Server: {:?} received registration request from client {:?}",
-                                self.id, client_id
-                            );
-
-                            self.registered_clients.insert(client_id); // Insert client in registered_clients.
-
-                            let chat_message = ChatMessage {
-                                sender_id: client_id,
-                                content: String::from(format!(
-                                    "Client {} has entered the chatroom",
-                                    client_id
-                                )),
-                            };
-
-                            self.messages_stored.push(chat_message);
-
-                            // Sends to simulation controller the whole chatroom.
-                            self.send_message_received_to_sc(MessageContent::WholeChatVecResponse(
-                                Chatroom {
-                                    server_id: self.id,
-                                    chatroom_messages: self.messages_stored.clone(),
-                                },
-                            ));
-
-                            // Respond to client with ClientRegistered
-                            let session_id = random::<u64>();
-                            let message = Message {
-                                source_id: self.id,
-                                session_id,
-                                content: ChatResponse::ClientRegistered(self.id),
-                            };
-
-                            self.send_message_in_fragments(client_id, session_id, message);
-
-                            debug!(
-                                "Server: {:?} now has registered client: {:?}",
-                                self.id, client_id
-                            );
-                        }
-
-                        ChatRequest::ClientList => {
-                            debug!(
-                                "Server: {:?} received ClientList request from {:?}",
-                                self.id, message.source_id
-                            );
-
-                            self.send_server_client_list(message.source_id);
-                        }
-
-                        ChatRequest::SendMessage { from, message } => {
-                            debug!(
-                                "Server: {:?} received SendMessage request from {:?}",
-                                self.id, from
-                            );
-
-                            self.handle_incoming_message(from, message);
-                        }
-                    }
+                ChatRequest::Unregister => {
+                    debug!(
+                        "Server: {:?} received unregistration request from client {:?}",
+                        self.id, source_id
+                    );
+
+                    self.handle_unregister(source_id);
+                }
+
+                ChatRequest::Heartbeat => {
+                    debug!(
+                        "Server: {:?} received Heartbeat from {:?}",
+                        self.id, source_id
+                    );
+                }
+
+                ChatRequest::ClientList => {
+                    debug!(
+                        "Server: {:?} received ClientList request from {:?}",
+                        self.id, source_id
+                    );
+
+                    self.send_server_client_list(source_id);
+                }
+
+                ChatRequest::SendMessage {
+                    from,
+                    message,
+                    sent_at,
+                    room,
+                    attachment,
+                } => {
+                    debug!(
+                        "Server: {:?} received SendMessage request for room {:?} from {:?}",
+                        self.id, room, from
+                    );
+
+                    self.handle_incoming_message(from, room, message, sent_at, attachment);
+                }
+
+                ChatRequest::UploadAttachment { upload_id, data } => {
+                    debug!(
+                        "Server: {:?} received UploadAttachment {:?} of {:?} from {:?}",
+                        self.id, upload_id, data, source_id
+                    );
+
+                    self.handle_upload_attachment(source_id, upload_id, data);
+                }
+
+                ChatRequest::GetAttachment(id) => {
+                    debug!(
+                        "Server: {:?} received GetAttachment {:?} from {:?}",
+                        self.id, id, source_id
+                    );
+
+                    self.send_attachment(source_id, id);
+                }
+
+                ChatRequest::Edit { id, message } => {
+                    debug!(
+                        "Server: {:?} received Edit request for message {:?} from {:?}",
+                        self.id, id, source_id
+                    );
+
+                    self.handle_edit_message(source_id, id, Some(message));
+                }
+
+                ChatRequest::Delete(id) => {
+                    debug!(
+                        "Server: {:?} received Delete request for message {:?} from {:?}",
+                        self.id, id, source_id
+                    );
+
+                    self.handle_edit_message(source_id, id, None);
+                }
+
+                ChatRequest::History { room, range } => {
+                    debug!(
+                        "Server: {:?} received History request {:?} for room {:?} from {:?}",
+                        self.id, range, room, source_id
+                    );
+
+                    self.send_chat_history(source_id, room, range);
+                }
+
+                ChatRequest::Relay {
+                    message,
+                    via,
+                    attachment,
+                } => {
+                    debug!(
+                        "Server: {:?} received relayed message {:?} from {:?}",
+                        self.id, message.origin, source_id
+                    );
+
+                    self.handle_relayed_message(source_id, message, via, attachment);
+                }
+
+                ChatRequest::ListRooms => {
+                    debug!(
+                        "Server: {:?} received ListRooms request from {:?}",
+                        self.id, source_id
+                    );
+
+                    self.send_room_list(source_id);
                 }
+
+                ChatRequest::CreateRoom(room) => {
+                    debug!(
+                        "Server: {:?} received CreateRoom {:?} from {:?}",
+                        self.id, room, source_id
+                    );
+
+                    self.handle_create_room(source_id, room);
+                }
+
+                ChatRequest::JoinRoom(room) => {
+                    debug!(
+                        "Server: {:?} received JoinRoom {:?} from {:?}",
+                        self.id, room, source_id
+                    );
+
+                    self.handle_join_room(source_id, room);
+                }
+
+                ChatRequest::LeaveRoom(room) => {
+                    debug!(
+                        "Server: {:?} received LeaveRoom {:?} from {:?}",
+                        self.id, room, source_id
+                    );
+
+                    self.handle_leave_room(source_id, room);
+                }
+            },
+            ProtocolMessage::Response(ResponseMessage::ServerType(
+                ServerTypeResponse::ServerType(server_type),
+            )) => {
+                debug!(
+                    "Server: {:?} received server type {:?} from {:?}",
+                    self.id, server_type, source_id
+                );
+                if server_type == ServerType::CommunicationServer
+                    && self.federation_peers.insert(source_id)
+                {
+                    debug!(
+                        "Server: {:?} now federated with {:?}",
+                        self.id, source_id
+                    );
+                }
+            }
+            ProtocolMessage::Request(RequestMessage::Resend(resend_request)) => {
+                debug!(
+                    "Server: {:?} received resend request for session {:?} from {:?}",
+                    self.id, resend_request.session_id, source_id
+                );
+                self.resend_message(resend_request.session_id);
+            }
+            payload => {
+                debug!(
+                    "Server: {:?} received unexpected message: {:?}",
+                    self.id, payload
+                );
+                self.reply_unexpected_message(source_id, session_id, &payload);
             }
         }
     }
@@ -391,14 +676,76 @@ impl CommunicationServer {
         self.send_message_in_fragments(client_id, session_id, message);
     }
 
-    fn send_text_response(&mut self, client_id: NodeId, _session_id: u64) {
+    fn send_handshake_response(
+        &mut self,
+        client_id: NodeId,
+        session_id: u64,
+        client_version: u16,
+        client_features: &HashSet<ProtocolFeature>,
+        client_public_key: Option<[u8; 32]>,
+    ) {
+        let capabilities = ServerCapabilities::negotiate(
+            ServerType::CommunicationServer,
+            client_version,
+            client_features,
+            &self.supported_features(),
+        );
+        self.client_features
+            .insert(client_id, capabilities.features.clone());
+        self.client_versions
+            .insert(client_id, capabilities.protocol_version);
+
+        // A client too old to talk to only learns the server type, in the legacy answer it can read
+        if !capabilities.is_compatible() {
+            debug!(
+                "Server: {:?} can't talk to {:?}, it only speaks protocol v{}",
+                self.id, client_id, capabilities.protocol_version
+            );
+            self.client_features.remove(&client_id);
+            self.session_keys.exchange(client_id, None);
+            let message = Message {
+                source_id: self.id,
+                session_id,
+                content: ServerTypeResponse::ServerType(capabilities.server_type),
+            };
+            self.send_message_in_fragments(client_id, session_id, message);
+            return;
+        }
+
+        // A new client key pair replaces the old key, a retry of the same handshake keeps it
+        let public_key = self.session_keys.exchange(
+            client_id,
+            client_public_key.filter(|_| capabilities.supports(ProtocolFeature::Encryption)),
+        );
+
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: ServerTypeResponse::Handshake {
+                capabilities,
+                public_key,
+                client_public_key,
+            },
+        };
+        debug!(
+            "Server: {:?} sending msg to client {:?}, msg: {:?}",
+            self.id, client_id, message
+        );
+        self.send_message_in_fragments(client_id, session_id, message);
+    }
+
+    fn supported_features(&self) -> HashSet<ProtocolFeature> {
+        HashSet::from([ProtocolFeature::Compression, ProtocolFeature::Encryption])
+    }
+
+    fn send_text_response(&mut self, client_id: NodeId, response: TextResponse) {
         debug!("Server: {:?} is a chat server!", self.id);
 
         let session_id = random::<u64>();
         let message = Message {
             source_id: self.id,
             session_id,
-            content: TextResponse::NotFound,
+            content: response,
         };
 
         debug!(
@@ -414,7 +761,7 @@ impl CommunicationServer {
         let message = Message {
             source_id: self.id,
             session_id,
-            content: ChatResponse::ClientList(self.registered_clients.clone()),
+            content: ChatResponse::ClientList(self.client_presence()),
         };
 
         debug!(
@@ -424,7 +771,14 @@ impl CommunicationServer {
         self.send_message_in_fragments(client_id, session_id, message);
     }
 
-    fn handle_incoming_message(&mut self, client_id: NodeId, content: String) {
+    fn handle_incoming_message(
+        &mut self,
+        client_id: NodeId,
+        room: String,
+        content: String,
+        sent_at: Option<i64>,
+        attachment: Option<Attachment>,
+    ) {
         // Check if the sender is registered
         if !self.registered_clients.contains(&client_id) {
             debug!(
@@ -433,38 +787,718 @@ impl CommunicationServer {
             );
 
             //If not registered send message with ClientNotRegistered
+            self.send_client_not_registered(client_id);
+            return;
+        }
+        if !self.check_room_member(client_id, &room) {
+            return;
+        }
+        if let Some(Attachment::Upload(attachment_id)) = attachment {
+            if !self.attachments.contains(&attachment_id) {
+                self.send_chat_response(client_id, ChatResponse::AttachmentNotFound(attachment_id));
+                return;
+            }
+        }
+
+        // A retransmitted message keeps the id it got the first time
+        let duplicate = self.messages_stored.iter().rev().find(|m| {
+            sent_at.is_some()
+                && m.room == room
+                && m.sender_id == client_id
+                && m.sent_at == sent_at
+                && m.content == content
+        });
+        let id = match duplicate {
+            Some(chat_message) => {
+                debug!(
+                    "Server: {:?} already stored message {:?} from {:?}",
+                    self.id, chat_message.id, client_id
+                );
+                chat_message.id
+            }
+            None => {
+                // If client is registered, store the message.
+                debug!("Server: {:?} storing message from {:?}", self.id, client_id);
+                let id = self.store_chat_message(ChatMessage {
+                    id: 0,
+                    room,
+                    sender_id: client_id,
+                    content,
+                    sent_at,
+                    stored_at: 0,
+                    edited: false,
+                    deleted: false,
+                    origin: None,
+                    attachment,
+                });
+
+                // Sends to simulation controller the whole chatroom.
+                self.send_chatroom_to_sc();
+
+                if let Some(chat_message) = self.messages_stored.last().cloned() {
+                    self.forward_to_room(&chat_message);
+                    self.relay_to_peers(chat_message, Vec::new());
+                }
+                id
+            }
+        };
+
+        self.send_chat_response(client_id, ChatResponse::MessageSent(id));
+    }
+
+    // None as new content deletes the message, only its sender can change it. The client is the
+    // source of the request, not an id it claims
+    fn handle_edit_message(&mut self, client_id: NodeId, id: u64, new_content: Option<String>) {
+        let index = self
+            .messages_stored
+            .iter()
+            .position(|m| m.id == id && m.sender_id == client_id && !m.deleted);
+        let Some(index) = index else {
+            self.send_chat_response(client_id, ChatResponse::EditRejected(id));
+            return;
+        };
+
+        let chat_message = self.change_message(index, new_content);
+        let response = if chat_message.deleted {
+            ChatResponse::MessageDeleted(id)
+        } else {
+            ChatResponse::MessageEdited(chat_message.clone())
+        };
+        self.send_chat_response(client_id, response);
+        self.relay_to_peers(chat_message, Vec::new());
+    }
+
+    // Edits, or deletes with None, a stored message and tells the rest of its room
+    fn change_message(&mut self, index: usize, new_content: Option<String>) -> ChatMessage {
+        let chat_message = &mut self.messages_stored[index];
+        let id = chat_message.id;
+        let record = match new_content {
+            Some(content) => {
+                chat_message.content = content.clone();
+                chat_message.edited = true;
+                ChatRecord::Edited { id, content }
+            }
+            None => {
+                chat_message.content.clear();
+                chat_message.deleted = true;
+                ChatRecord::Deleted(id)
+            }
+        };
+        let chat_message = chat_message.clone();
+
+        self.store_chat_record(record);
+        self.send_chatroom_to_sc();
+        self.forward_change_to_room(&chat_message);
+        chat_message
+    }
+
+    // Replies RoomNotFound or NotInRoom when the client can't use the room
+    fn check_room_member(&mut self, client_id: NodeId, room: &str) -> bool {
+        let response = match self.rooms.get(room) {
+            Some(members) if members.contains(&client_id) => return true,
+            Some(_) => ChatResponse::NotInRoom(room.to_string()),
+            None => ChatResponse::RoomNotFound(room.to_string()),
+        };
+        debug!(
+            "Server: {:?} client {:?} can't use room {:?}",
+            self.id, client_id, room
+        );
+        self.send_chat_response(client_id, response);
+        false
+    }
+
+    fn handle_create_room(&mut self, client_id: NodeId, room: String) {
+        if !self.registered_clients.contains(&client_id) {
+            self.send_client_not_registered(client_id);
+            return;
+        }
+        let room = room.trim().to_string();
+        if room.is_empty() {
+            self.send_chat_response(client_id, ChatResponse::RoomNotFound(room));
+            return;
+        }
+
+        // Creating a room that already exists just joins it
+        if !self.rooms.contains_key(&room) {
+            self.rooms.insert(room.clone(), HashSet::new());
+            self.store_chat_record(ChatRecord::RoomCreated(room.clone()));
+        }
+        self.handle_join_room(client_id, room);
+    }
+
+    fn handle_join_room(&mut self, client_id: NodeId, room: String) {
+        if !self.registered_clients.contains(&client_id) {
+            self.send_client_not_registered(client_id);
+            return;
+        }
+        let Some(members) = self.rooms.get_mut(&room) else {
+            self.send_chat_response(client_id, ChatResponse::RoomNotFound(room));
+            return;
+        };
+
+        if members.insert(client_id) {
+            self.store_chat_record(ChatRecord::Joined {
+                client: client_id,
+                room: room.clone(),
+            });
+            self.store_new_message(
+                room.clone(),
+                client_id,
+                format!("Client {} has joined the room", client_id),
+                None,
+            );
+            self.send_chatroom_to_sc();
+        }
+        self.send_chat_response(client_id, ChatResponse::RoomJoined(room));
+    }
+
+    fn handle_leave_room(&mut self, client_id: NodeId, room: String) {
+        if !self.check_room_member(client_id, &room) {
+            return;
+        }
+        if let Some(members) = self.rooms.get_mut(&room) {
+            members.remove(&client_id);
+        }
+
+        self.store_chat_record(ChatRecord::Left {
+            client: client_id,
+            room: room.clone(),
+        });
+        self.store_new_message(
+            room.clone(),
+            client_id,
+            format!("Client {} has left the room", client_id),
+            None,
+        );
+        self.send_chatroom_to_sc();
+        self.send_chat_response(client_id, ChatResponse::RoomLeft(room));
+    }
+
+    fn send_room_list(&mut self, client_id: NodeId) {
+        let mut room_list: Vec<RoomInfo> = self
+            .rooms
+            .iter()
+            .map(|(name, members)| RoomInfo {
+                name: name.clone(),
+                members: members.clone(),
+            })
+            .collect();
+        room_list.sort_by(|a, b| a.name.cmp(&b.name));
+
+        self.send_chat_response(client_id, ChatResponse::RoomList(room_list));
+    }
+
+    fn store_new_message(
+        &mut self,
+        room: String,
+        sender_id: NodeId,
+        content: String,
+        sent_at: Option<i64>,
+    ) -> u64 {
+        self.store_chat_message(ChatMessage {
+            id: 0,
+            room,
+            sender_id,
+            content,
+            sent_at,
+            stored_at: 0,
+            edited: false,
+            deleted: false,
+            origin: None,
+            attachment: None,
+        })
+    }
+
+    fn handle_upload_attachment(&mut self, client_id: NodeId, upload_id: u64, data: AttachmentData) {
+        if !self.registered_clients.contains(&client_id) {
+            self.send_client_not_registered(client_id);
+            return;
+        }
+
+        let rejected = if data.0.len() > MAX_ATTACHMENT_SIZE {
+            Some(format!(
+                "image is {} bytes, the limit is {}",
+                data.0.len(),
+                MAX_ATTACHMENT_SIZE
+            ))
+        } else if let Err(e) = image::load_from_memory(&data.0) {
+            Some(format!("not a readable image: {}", e))
+        } else {
+            None
+        };
+        let response = match rejected.map_or_else(|| self.store_attachment(&data.0), Err) {
+            Ok(id) => ChatResponse::AttachmentUploaded { upload_id, id },
+            Err(reason) => {
+                debug!(
+                    "Server: {:?} rejected attachment {:?} from {:?}: {}",
+                    self.id, upload_id, client_id, reason
+                );
+                ChatResponse::AttachmentRejected { upload_id, reason }
+            }
+        };
+
+        self.send_chat_response(client_id, response);
+    }
+
+    fn store_attachment(&mut self, data: &[u8]) -> Result<u64, String> {
+        let id = self.next_attachment_id;
+        self.chat_store.store_attachment(id, data)?;
+        self.next_attachment_id += 1;
+        self.attachments.insert(id);
+        Ok(id)
+    }
+
+    fn send_attachment(&mut self, client_id: NodeId, id: u64) {
+        if !self.registered_clients.contains(&client_id) {
+            self.send_client_not_registered(client_id);
+            return;
+        }
+
+        let response = match self.chat_store.load_attachment(id) {
+            Some(data) => ChatResponse::Attachment(id, AttachmentData(data)),
+            None => ChatResponse::AttachmentNotFound(id),
+        };
+        self.send_chat_response(client_id, response);
+    }
+
+    // Gives the message its local id and storing time
+    fn store_chat_message(&mut self, mut chat_message: ChatMessage) -> u64 {
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+        chat_message.id = id;
+        chat_message.stored_at = Utc::now().timestamp_millis();
+
+        self.messages_stored.push(chat_message.clone());
+        self.store_chat_record(ChatRecord::Message(chat_message));
+        id
+    }
+
+    fn send_server_type_probe(&mut self, server_id: NodeId) {
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: ServerTypeRequest::GetServerType,
+        };
+        debug!(
+            "Server: {:?} asking {:?} for its server type",
+            self.id, server_id
+        );
+        self.send_message_in_fragments(server_id, session_id, message);
+    }
+
+    // Sends the message to every peer that didn't see it yet
+    fn relay_to_peers(&mut self, mut chat_message: ChatMessage, mut via: Vec<NodeId>) {
+        if chat_message.origin.is_none() {
+            chat_message.origin = Some((self.id, chat_message.id));
+        }
+        via.push(self.id);
+        let attachment = match chat_message.attachment {
+            Some(Attachment::Upload(id)) => self.chat_store.load_attachment(id).map(AttachmentData),
+            _ => None,
+        };
+
+        let peers: Vec<NodeId> = self
+            .federation_peers
+            .iter()
+            .filter(|peer| !via.contains(peer))
+            .cloned()
+            .collect();
+        for peer in peers {
             let session_id = random::<u64>();
             let message = Message {
                 source_id: self.id,
                 session_id,
-                content: ChatResponse::ClientNotRegistered,
+                content: ChatRequest::Relay {
+                    message: chat_message.clone(),
+                    via: via.clone(),
+                    attachment: attachment.clone(),
+                },
             };
+            if !self.send_message_in_fragments(peer, session_id, message) {
+                debug!(
+                    "Server: {:?} could not relay message to peer {:?}",
+                    self.id, peer
+                );
+            }
+        }
+    }
 
+    fn handle_relayed_message(
+        &mut self,
+        peer_id: NodeId,
+        mut chat_message: ChatMessage,
+        via: Vec<NodeId>,
+        attachment: Option<AttachmentData>,
+    ) {
+        // Only servers that told us they're communication servers can relay. One our flood
+        // didn't find is asked, its next relays are accepted once it answers
+        if !self.federation_peers.contains(&peer_id) {
             debug!(
-                "Server: {:?} sending ClientNotRegistered to client {:?}, msg: {:?}",
-                self.id, client_id, message
+                "Server: {:?} ignoring relayed message from {:?}, not a federation peer",
+                self.id, peer_id
             );
-            self.send_message_in_fragments(client_id, session_id, message);
+            self.send_server_type_probe(peer_id);
             return;
         }
-
-        // If client is registered, store the message.
-        let chat_message = ChatMessage {
-            sender_id: client_id,
-            content,
+        let Some((origin_server, origin_id)) = chat_message.origin else {
+            debug!(
+                "Server: {:?} ignoring relayed message without origin from {:?}",
+                self.id, peer_id
+            );
+            return;
         };
 
-        debug!("Server: {:?} storing message from {:?}", self.id, client_id);
+        // The same message can come back through another peer, or come again after being
+        // edited or deleted at its origin
+        let stored = self.messages_stored.iter().position(|m| {
+            m.origin == Some((origin_server, origin_id))
+                || (origin_server == self.id && m.origin.is_none() && m.id == origin_id)
+        });
+        if let Some(index) = stored {
+            let local = &self.messages_stored[index];
+            let changed = local.sender_id == chat_message.sender_id
+                && !local.deleted
+                && (chat_message.deleted || (chat_message.edited && local.content != chat_message.content));
+            if !changed {
+                debug!(
+                    "Server: {:?} already stored message {:?} of server {:?}",
+                    self.id, origin_id, origin_server
+                );
+                return;
+            }
+            let new_content = (!chat_message.deleted).then_some(chat_message.content);
+            let chat_message = self.change_message(index, new_content);
+            self.relay_to_peers(chat_message, via);
+            return;
+        }
+
+        // Rooms are federated by name
+        let room = chat_message.room.clone();
+        if !self.rooms.contains_key(&room) {
+            self.rooms.insert(room.clone(), HashSet::new());
+            self.store_chat_record(ChatRecord::RoomCreated(room));
+        }
+
+        // An uploaded image gets an id of this server, so local clients can fetch it
+        if let Some(Attachment::Upload(_)) = chat_message.attachment {
+            chat_message.attachment = attachment
+                .and_then(|data| self.store_attachment(&data.0).ok())
+                .map(Attachment::Upload);
+        }
 
-        self.messages_stored.push(chat_message);
+        self.store_chat_message(chat_message);
+        self.send_chatroom_to_sc();
+
+        if let Some(chat_message) = self.messages_stored.last().cloned() {
+            self.forward_to_room(&chat_message);
+            self.relay_to_peers(chat_message, via);
+        }
+    }
 
-        // Sends to simulation controller the whole chatroom.
+    fn send_chatroom_to_sc(&mut self) {
         self.send_message_received_to_sc(MessageContent::WholeChatVecResponse(Chatroom {
             server_id: self.id,
             chatroom_messages: self.messages_stored.clone(),
         }));
     }
 
+    fn send_chat_response(&mut self, client_id: NodeId, response: ChatResponse) {
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: response,
+        };
+
+        debug!(
+            "Server: {:?} sending msg to client {:?}, msg: {:?}",
+            self.id, client_id, message
+        );
+        if !self.send_message_in_fragments(client_id, session_id, message) {
+            self.record_send_failure(client_id);
+        }
+    }
+
+    fn handle_unregister(&mut self, client_id: NodeId) {
+        if !self.registered_clients.remove(&client_id) {
+            self.send_client_not_registered(client_id);
+            return;
+        }
+        for members in self.rooms.values_mut() {
+            members.remove(&client_id);
+        }
+        self.store_chat_record(ChatRecord::Unregistered(client_id));
+
+        // An offline client already got its notice
+        if !self.offline_clients.remove(&client_id) {
+            self.store_new_message(
+                DEFAULT_ROOM.to_string(),
+                client_id,
+                format!("Client {} has left the chatroom", client_id),
+                None,
+            );
+            self.send_chatroom_to_sc();
+        }
+        self.last_seen.remove(&client_id);
+        self.send_failures.remove(&client_id);
+        if self.pending_deliveries.remove(&client_id).is_some() {
+            self.send_pending_deliveries_to_sc();
+        }
+
+        self.send_chat_response(client_id, ChatResponse::ClientUnregistered(self.id));
+    }
+
+    fn client_presence(&self) -> HashMap<NodeId, Presence> {
+        self.registered_clients
+            .iter()
+            .map(|client_id| {
+                let presence = if self.offline_clients.contains(client_id) {
+                    Presence::Offline
+                } else {
+                    Presence::Online
+                };
+                (*client_id, presence)
+            })
+            .collect()
+    }
+
+    // Any message from a registered client proves it is online
+    fn client_seen(&mut self, client_id: NodeId) {
+        if !self.registered_clients.contains(&client_id) {
+            return;
+        }
+        self.last_seen.insert(client_id, Instant::now());
+        self.send_failures.remove(&client_id);
+
+        if self.offline_clients.remove(&client_id) {
+            debug!("Server: {:?} client {:?} is back online", self.id, client_id);
+            self.store_new_message(
+                DEFAULT_ROOM.to_string(),
+                client_id,
+                format!("Client {} has entered the chatroom", client_id),
+                None,
+            );
+            self.send_chatroom_to_sc();
+
+            if self.pending_deliveries.contains_key(&client_id) {
+                self.flush_pending_deliveries(client_id);
+                self.send_pending_deliveries_to_sc();
+            }
+        }
+    }
+
+    // Sends the message to the other members of the room
+    fn forward_to_room(&mut self, chat_message: &ChatMessage) {
+        let members: Vec<NodeId> = self
+            .rooms
+            .get(&chat_message.room)
+            .into_iter()
+            .flatten()
+            .filter(|member| **member != chat_message.sender_id)
+            .cloned()
+            .collect();
+
+        for member in members {
+            let response = ChatResponse::MessageFrom {
+                from: chat_message.sender_id,
+                message: chat_message.content.clone().into_bytes(),
+                room: chat_message.room.clone(),
+                id: chat_message.id,
+                attachment: chat_message.attachment,
+            };
+            self.deliver_or_queue(member, response);
+        }
+    }
+
+    // Sends the edit or the delete to the other members of the room
+    fn forward_change_to_room(&mut self, chat_message: &ChatMessage) {
+        let members: Vec<NodeId> = self
+            .rooms
+            .get(&chat_message.room)
+            .into_iter()
+            .flatten()
+            .filter(|member| **member != chat_message.sender_id)
+            .cloned()
+            .collect();
+
+        for member in members {
+            let response = if chat_message.deleted {
+                ChatResponse::MessageDeleted(chat_message.id)
+            } else {
+                ChatResponse::MessageEdited(chat_message.clone())
+            };
+            self.deliver_or_queue(member, response);
+        }
+    }
+
+    fn deliver_or_queue(&mut self, client_id: NodeId, response: ChatResponse) {
+        // Nothing overtakes the messages already waiting for the client
+        let queue_empty = self
+            .pending_deliveries
+            .get(&client_id)
+            .map_or(true, |queue| queue.is_empty());
+        if queue_empty && !self.offline_clients.contains(&client_id) {
+            if self.try_deliver(client_id, response.clone()) {
+                return;
+            }
+            self.record_send_failure(client_id);
+        }
+
+        debug!(
+            "Server: {:?} queueing message for unreachable client {:?}",
+            self.id, client_id
+        );
+        let queue = self.pending_deliveries.entry(client_id).or_default();
+        queue.push_back(response);
+        if queue.len() > MAX_PENDING_DELIVERIES {
+            queue.pop_front();
+        }
+        self.send_pending_deliveries_to_sc();
+    }
+
+    fn try_deliver(&mut self, client_id: NodeId, response: ChatResponse) -> bool {
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: response,
+        };
+        self.send_message_in_fragments(client_id, session_id, message)
+    }
+
+    fn retry_pending_deliveries(&mut self) {
+        if self.pending_deliveries.is_empty() {
+            return;
+        }
+
+        let queued_before: usize = self.pending_deliveries.values().map(VecDeque::len).sum();
+        let clients: Vec<NodeId> = self.pending_deliveries.keys().cloned().collect();
+        for client_id in clients {
+            self.flush_pending_deliveries(client_id);
+        }
+
+        let queued_after: usize = self.pending_deliveries.values().map(VecDeque::len).sum();
+        if queued_after != queued_before {
+            self.send_pending_deliveries_to_sc();
+        }
+    }
+
+    // Sends the queued messages in order, stops at the first one that still has no path
+    fn flush_pending_deliveries(&mut self, client_id: NodeId) {
+        while let Some(response) = self
+            .pending_deliveries
+            .get_mut(&client_id)
+            .and_then(VecDeque::pop_front)
+        {
+            if !self.try_deliver(client_id, response.clone()) {
+                if let Some(queue) = self.pending_deliveries.get_mut(&client_id) {
+                    queue.push_front(response);
+                }
+                return;
+            }
+        }
+
+        debug!(
+            "Server: {:?} delivered all queued messages to {:?}",
+            self.id, client_id
+        );
+        self.pending_deliveries.remove(&client_id);
+    }
+
+    fn send_pending_deliveries_to_sc(&mut self) {
+        let queued = self
+            .pending_deliveries
+            .iter()
+            .map(|(client_id, queue)| (*client_id, queue.len()))
+            .collect();
+        self.controller_send
+            .send(ServerEvent::PendingDeliveries {
+                server_id: self.id,
+                queued,
+            })
+            .expect("this is fine 🔥☕");
+    }
+
+    fn record_send_failure(&mut self, client_id: NodeId) {
+        if !self.registered_clients.contains(&client_id) {
+            return;
+        }
+        let failures = self.send_failures.entry(client_id).or_insert(0);
+        *failures += 1;
+        if *failures >= MAX_SEND_FAILURES {
+            self.mark_offline(client_id);
+        }
+    }
+
+    fn check_presence(&mut self) {
+        let silent: Vec<NodeId> = self
+            .last_seen
+            .iter()
+            .filter(|(_, seen)| seen.elapsed() > PRESENCE_TIMEOUT)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in silent {
+            self.mark_offline(client_id);
+        }
+    }
+
+    fn mark_offline(&mut self, client_id: NodeId) {
+        self.last_seen.remove(&client_id);
+        self.send_failures.remove(&client_id);
+        if !self.offline_clients.insert(client_id) {
+            return;
+        }
+
+        debug!("Server: {:?} client {:?} is offline", self.id, client_id);
+        self.store_new_message(
+            DEFAULT_ROOM.to_string(),
+            client_id,
+            format!("Client {} has left the chatroom", client_id),
+            None,
+        );
+        self.send_chatroom_to_sc();
+    }
+
+    fn send_client_not_registered(&mut self, client_id: NodeId) {
+        self.send_chat_response(client_id, ChatResponse::ClientNotRegistered);
+    }
+
+    fn send_chat_history(&mut self, client_id: NodeId, room: String, range: HistoryRange) {
+        if !self.registered_clients.contains(&client_id) {
+            self.send_client_not_registered(client_id);
+            return;
+        }
+        if !self.check_room_member(client_id, &room) {
+            return;
+        }
+
+        // Messages are stored in id order
+        let in_room = self.messages_stored.iter().filter(|m| m.room == room);
+        let history: Vec<ChatMessage> = match range {
+            HistoryRange::Last(count) => {
+                let mut last: Vec<ChatMessage> = in_room.rev().take(count).cloned().collect();
+                last.reverse();
+                last
+            }
+            HistoryRange::Since(message_id) => in_room
+                .filter(|m| m.id > message_id)
+                .cloned()
+                .collect(),
+        };
+
+        self.send_chat_response(client_id, ChatResponse::History(history));
+    }
+
+    fn store_chat_record(&mut self, record: ChatRecord) {
+        if let Err(_e) = self.chat_store.append(&record) {
+            debug!(
+                "ERROR: Server {:?} could not store {:?}: {}",
+                self.id, record, _e
+            );
+        }
+    }
+
     fn send_fragment_to_assembler(&mut self, packet: Packet) -> Result<(), String> {
         // send the packet to the assembler
         match self.assembler_send.send(packet) {
@@ -1,16 +1,58 @@
 #[cfg(feature = "debug")]
 use crate::debug;
 
+use crate::assembler::assembler::AssembledMessage;
 use crate::client_server::network_core::{
-    ClientServerCommand, ContentType, NetworkNode, ServerEvent, ServerType,
+    ClientServerCommand, ContentType, NetworkNode, NodeState, ProtocolFeature, SentMessages, ServerCapabilities, ServerSessionKeys, SessionKey,
+    ServerEvent, ServerType,
 };
 use crate::message::message::*;
+use crate::network_initializer::network_initializer::{
+    read_file_ids, MEDIA_FILES_DIR, TEXT_FILES_DIR, UPLOADS_DIR,
+};
 use crossbeam_channel::{select_biased, Receiver, Sender};
 use rand::random;
 use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{FloodRequest, NodeType, Packet, PacketType};
 
+const MAX_TEXT_UPLOAD_SIZE: usize = 64 * 1024;
+const MAX_MEDIA_UPLOAD_SIZE: usize = 512 * 1024;
+// Every server gives its uploads ids from its own block, so two servers never give the same id
+// to different files and the path of a file follows from its id
+const UPLOAD_ID_BLOCK: u64 = 1_000_000;
+
+// Where the file with this id is, the shipped files or the uploads of the server that owns its id
+pub fn content_file_path(content_type: &ContentType, file_id: u64) -> PathBuf {
+    let extension = match content_type {
+        ContentType::Text => "",
+        ContentType::Media => ".jpg",
+    };
+    let file_name = format!("{}{}", file_id, extension);
+    match (file_id / UPLOAD_ID_BLOCK).checked_sub(1) {
+        None => Path::new(content_dir(content_type)).join(file_name),
+        Some(server_id) => upload_dir(server_id, content_type).join(file_name),
+    }
+}
+
+fn content_dir(content_type: &ContentType) -> &'static str {
+    match content_type {
+        ContentType::Text => TEXT_FILES_DIR,
+        ContentType::Media => MEDIA_FILES_DIR,
+    }
+}
+
+// Not tracked by git, same layout as the shipped files
+fn upload_dir(server_id: u64, content_type: &ContentType) -> PathBuf {
+    let content_dir = Path::new(content_dir(content_type));
+    Path::new(UPLOADS_DIR)
+        .join(format!("server_{}", server_id))
+        .join(content_dir.file_name().unwrap_or_default())
+}
+
 pub struct ContentServer {
     id: NodeId,
     topology_map: HashSet<(NodeId, Vec<NodeId>)>,
@@ -20,9 +62,14 @@ pub struct ContentServer {
     packet_send: HashMap<NodeId, Sender<Packet>>,
     packet_recv: Receiver<Packet>,
     assembler_send: Sender<Packet>,
-    assembler_res_recv: Receiver<Vec<u8>>,
+    assembler_res_recv: Receiver<AssembledMessage>,
+    sent_messages: SentMessages, // kept to answer resend requests
+    session_keys: ServerSessionKeys, // agreed in the handshake
     content_type: ContentType,
     texts: Vec<u64>,
+    replicas: HashMap<u64, Vec<NodeId>>, // file id -> other servers holding a copy
+    client_features: HashMap<NodeId, HashSet<ProtocolFeature>>, // negotiated in the handshake
+    client_versions: HashMap<NodeId, u16>, // negotiated in the handshake
 }
 
 impl NetworkNode for ContentServer {
@@ -38,6 +85,15 @@ impl NetworkNode for ContentServer {
     fn topology_map_mut(&mut self) -> &mut HashSet<(NodeId, Vec<NodeId>)> {
         &mut self.topology_map
     }
+    fn sent_messages_mut(&mut self) -> &mut SentMessages {
+        &mut self.sent_messages
+    }
+    fn session_key(&self, node_id: NodeId) -> Option<&SessionKey> {
+        self.session_keys.get(node_id)
+    }
+    fn peer_version(&self, node_id: NodeId) -> Option<u16> {
+        self.client_versions.get(&node_id).copied()
+    }
 
     fn run(&mut self) {
         debug!(
@@ -47,6 +103,10 @@ impl NetworkNode for ContentServer {
         loop {
             select_biased! {
                 recv(self.controller_recv) -> command => {
+                    if let Ok(ClientServerCommand::Shutdown) = command {
+                        debug!("Content Server: {:?} shutting down", self.id);
+                        return;
+                    }
                     if let Ok(command) = command {
                         self.handle_command(command);
                     }
@@ -92,6 +152,16 @@ impl NetworkNode for ContentServer {
             })
             .expect("this is fine 🔥☕");
     }
+    fn send_integrity_failure_to_sc(&mut self, session_id: u64, route: Vec<NodeId>) {
+        self.controller_send
+            .send(ServerEvent::IntegrityCheckFailed { session_id, route })
+            .expect("this is fine 🔥☕");
+    }
+    fn use_compression(&self, target_node_id: NodeId) -> bool {
+        self.client_features
+            .get(&target_node_id)
+            .is_some_and(|features| features.contains(&ProtocolFeature::Compression))
+    }
 }
 
 impl ContentServer {
@@ -104,10 +174,19 @@ impl ContentServer {
         packet_recv: Receiver<Packet>,
         topology_map: HashSet<(NodeId, Vec<NodeId>)>,
         assembler_send: Sender<Packet>,
-        assembler_res_recv: Receiver<Vec<u8>>,
+        assembler_res_recv: Receiver<AssembledMessage>,
         content_type: ContentType,
         files: Vec<u64>,
+        replicas: HashMap<u64, Vec<NodeId>>,
     ) -> Self {
+        // Files uploaded to this server in earlier runs, only it has them
+        let uploads = read_file_ids(&upload_dir(id as u64, &content_type).to_string_lossy());
+        let (mut files, mut replicas) = (files, replicas);
+        for file_id in uploads {
+            replicas.entry(file_id).or_default();
+            files.push(file_id);
+        }
+
         Self {
             id,
             connected_drone_ids,
@@ -120,6 +199,11 @@ impl ContentServer {
             assembler_res_recv,
             content_type,
             texts: files,
+            replicas,
+            client_features: HashMap::new(),
+            client_versions: HashMap::new(),
+            sent_messages: SentMessages::new(),
+            session_keys: ServerSessionKeys::default(),
         }
     }
 
@@ -175,10 +259,25 @@ impl ContentServer {
                     \ntopology_map: {:?}\
                     \ncontent_type: {:?}\
                     \nfiles: {:?}\
+                    \nreplicas: {:?}\
                     \n",
-                    self.id, self.topology_map, self.content_type, self.texts
+                    self.id, self.topology_map, self.content_type, self.texts, self.replicas
                 );
             },
+            ClientServerCommand::QueryNodeState => {
+                let state = NodeState {
+                    node_id: self.id,
+                    routes: self.known_routes(),
+                    server_types: HashMap::new(),
+                    registered_servers: HashSet::new(),
+                    registered_clients: HashSet::new(),
+                    connected_drones: self.connected_drone_ids.clone(),
+                    assembler_backlog: self.assembler_send.len() + self.assembler_res_recv.len(),
+                };
+                self.controller_send
+                    .send(ServerEvent::NodeState(state))
+                    .expect("this is fine 🔥☕");
+            },
             _ => {}
         }
     }
@@ -246,80 +345,130 @@ impl ContentServer {
                     "Server: {:?} received a FloodResponse {:?}",
                     self.id, _flood_response
                 );
-                self.update_topology_with_flood_response(_flood_response, false);
+                self.update_topology_with_flood_response(_flood_response, &[NodeType::Client]);
             }
         }
     }
-    fn handle_assembler_data(&mut self, data: Vec<u8>) {
-        if let Ok(str_data_raw) = String::from_utf8(data.clone()) {
-            debug!(
-                "Server {:?} received assembled message: {:?}",
-                self.id, str_data_raw
-            );
-
-            if let Some(str_data) = str_data_raw.split("\0").nth(0) {
-                // Try to parse as ServerTypeRequest
-                if let Ok(message) = serde_json::from_str::<Message<ServerTypeRequest>>(&str_data) {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
-                    }
+    fn handle_assembler_data(&mut self, assembled: AssembledMessage) {
+        if !Envelope::verify_checksum(&assembled.data) {
+            self.handle_corrupted_message(assembled);
+            return;
+        }
+        let envelope = match Envelope::decode(&assembled.data)
+            .and_then(|envelope| self.open_envelope(envelope))
+        {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                self.reply_to_protocol_error(e);
+                return;
+            }
+        };
+        debug!(
+            "Server {:?} received assembled message: {:?}",
+            self.id, envelope
+        );
 
-                    match message.content {
-                        ServerTypeRequest::GetServerType => {
-                            debug!(
-                                "Server: {:?} received ServerTypeRequest from {:?}",
-                                self.id, message.source_id
-                            );
-                            self.send_server_type_response(message.source_id, message.session_id);
-                        }
-                    }
-                }
-                // Try to parse as TextRequest
-                else if let Ok(message) = serde_json::from_str::<Message<TextRequest>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
-                    }
+        // Send to SC
+        self.send_message_received_to_sc(MessageContent::from_protocol(&envelope.payload));
 
-                    match message.content {
-                        TextRequest::TextList => {
-                            debug!(
-                                "Server: {:?} received TextRequest::TextList from {:?}",
-                                self.id, message.source_id
-                            );
-                            self.send_text_response_text_list(message.source_id);
-                        }
-                        TextRequest::Text(file_id) => {
-                            debug!(
-                                "Server: {:?} received TextRequest::Text from {:?} file id: {:?}",
-                                self.id, message.source_id, file_id
-                            );
-                            self.send_text_response_text(message.source_id, file_id);
-                        }
-                    }
-                } else if let Ok(message) = serde_json::from_str::<Message<MediaRequest>>(&str_data)
-                {
-                    // Send to SC
-                    if let Some(content) = MessageContent::from_content(message.content.clone()) {
-                        self.send_message_received_to_sc(content);
+        let source_id = envelope.source_id;
+        let session_id = envelope.session_id;
+        match envelope.payload {
+            ProtocolMessage::Request(RequestMessage::ServerType(server_type_request)) => {
+                match server_type_request {
+                    ServerTypeRequest::GetServerType => {
+                        debug!(
+                            "Server: {:?} received ServerTypeRequest from {:?}",
+                            self.id, source_id
+                        );
+                        self.send_server_type_response(source_id, session_id);
                     }
-
-                    match message.content {
-                        MediaRequest::MediaList => {
-                            self.handle_media_list_request(message.source_id);
-                        }
-                        MediaRequest::Media(file_nr) => {
-                            self.handle_media_request(message.source_id, file_nr);
-                        }
+                    ServerTypeRequest::Handshake {
+                        protocol_version,
+                        features,
+                        public_key,
+                    } => {
+                        debug!(
+                            "Server: {:?} received Handshake from {:?}, version: {:?}, features: {:?}",
+                            self.id, source_id, protocol_version, features
+                        );
+                        self.send_handshake_response(
+                            source_id,
+                            session_id,
+                            protocol_version,
+                            &features,
+                            public_key,
+                        );
                     }
-                } else {
+                }
+            }
+            ProtocolMessage::Request(RequestMessage::Text(text_request)) => match text_request {
+                TextRequest::TextList => {
+                    debug!(
+                        "Server: {:?} received TextRequest::TextList from {:?}",
+                        self.id, source_id
+                    );
+                    self.send_text_response_text_list(source_id);
+                }
+                TextRequest::Text(file_id) => {
                     debug!(
-                        "Server: {:?} received unknown message format: {:?}",
-                        self.id, str_data
+                        "Server: {:?} received TextRequest::Text from {:?} file id: {:?}",
+                        self.id, source_id, file_id
                     );
+                    self.send_text_response_text(source_id, file_id);
+                }
+                TextRequest::Upload(text) => {
+                    debug!(
+                        "Server: {:?} received TextRequest::Upload from {:?}, {} bytes",
+                        self.id,
+                        source_id,
+                        text.len()
+                    );
+                    self.handle_text_upload(source_id, text);
+                }
+                TextRequest::Replicas => {
+                    let session_id = random::<u64>();
+                    let message = Message {
+                        source_id: self.id,
+                        session_id,
+                        content: TextResponse::Replicas(self.replicas.clone()),
+                    };
+                    self.send_message_in_fragments(source_id, session_id, message);
                 }
+            },
+            ProtocolMessage::Request(RequestMessage::Media(media_request)) => match media_request {
+                MediaRequest::MediaList => {
+                    self.handle_media_list_request(source_id);
+                }
+                MediaRequest::Media(file_nr) => {
+                    self.handle_media_request(source_id, file_nr);
+                }
+                MediaRequest::Upload(data) => {
+                    self.handle_media_upload(source_id, data);
+                }
+                MediaRequest::Replicas => {
+                    let session_id = random::<u64>();
+                    let message = Message {
+                        source_id: self.id,
+                        session_id,
+                        content: MediaResponse::Replicas(self.replicas.clone()),
+                    };
+                    self.send_message_in_fragments(source_id, session_id, message);
+                }
+            },
+            ProtocolMessage::Request(RequestMessage::Resend(resend_request)) => {
+                debug!(
+                    "Server: {:?} received resend request for session {:?} from {:?}",
+                    self.id, resend_request.session_id, source_id
+                );
+                self.resend_message(resend_request.session_id);
+            }
+            payload => {
+                debug!(
+                    "Server: {:?} received unexpected message: {:?}",
+                    self.id, payload
+                );
+                self.reply_unexpected_message(source_id, session_id, &payload);
             }
         }
     }
@@ -339,6 +488,68 @@ impl ContentServer {
         );
         self.send_message_in_fragments(client_id, session_id, message);
     }
+
+    fn send_handshake_response(
+        &mut self,
+        client_id: NodeId,
+        session_id: u64,
+        client_version: u16,
+        client_features: &HashSet<ProtocolFeature>,
+        client_public_key: Option<[u8; 32]>,
+    ) {
+        let capabilities = ServerCapabilities::negotiate(
+            ServerType::ContentServer(self.content_type.clone()),
+            client_version,
+            client_features,
+            &self.supported_features(),
+        );
+        self.client_features
+            .insert(client_id, capabilities.features.clone());
+        self.client_versions
+            .insert(client_id, capabilities.protocol_version);
+
+        // A client too old to talk to only learns the server type, in the legacy answer it can read
+        if !capabilities.is_compatible() {
+            debug!(
+                "Server: {:?} can't talk to {:?}, it only speaks protocol v{}",
+                self.id, client_id, capabilities.protocol_version
+            );
+            self.client_features.remove(&client_id);
+            self.session_keys.exchange(client_id, None);
+            let message = Message {
+                source_id: self.id,
+                session_id,
+                content: ServerTypeResponse::ServerType(capabilities.server_type),
+            };
+            self.send_message_in_fragments(client_id, session_id, message);
+            return;
+        }
+
+        // A new client key pair replaces the old key, a retry of the same handshake keeps it
+        let public_key = self.session_keys.exchange(
+            client_id,
+            client_public_key.filter(|_| capabilities.supports(ProtocolFeature::Encryption)),
+        );
+
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content: ServerTypeResponse::Handshake {
+                capabilities,
+                public_key,
+                client_public_key,
+            },
+        };
+        debug!(
+            "Server: {:?} sending msg to client {:?}, msg: {:?}",
+            self.id, client_id, message
+        );
+        self.send_message_in_fragments(client_id, session_id, message);
+    }
+
+    fn supported_features(&self) -> HashSet<ProtocolFeature> {
+        HashSet::from([ProtocolFeature::Compression, ProtocolFeature::Encryption])
+    }
     fn send_text_response_text_list(&mut self, client_id: NodeId) {
         let session_id = random::<u64>();
         let message = Message {
@@ -355,7 +566,7 @@ impl ContentServer {
     fn send_text_response_text(&mut self, client_id: NodeId, file_id: u64) {
         if self.texts.contains(&file_id) {
             // Try to read file content
-            let file_path = format!("server_content/text_files/{}", file_id);
+            let file_path = content_file_path(&ContentType::Text, file_id);
             match std::fs::read_to_string(&file_path) {
                 Ok(content) => {
                     let session_id = random::<u64>();
@@ -379,7 +590,7 @@ impl ContentServer {
                     let message = Message {
                         source_id: self.id,
                         session_id,
-                        content: TextResponse::NotFound,
+                        content: TextResponse::NotFound(file_id),
                     };
                     debug!(
                         "Server: {:?} sending msg to client {:?}, msg: {:?}",
@@ -394,7 +605,7 @@ impl ContentServer {
             let message = Message {
                 source_id: self.id,
                 session_id,
-                content: TextResponse::NotFound,
+                content: TextResponse::NotFound(file_id),
             };
             debug!(
                 "Server: {:?} sending msg to client {:?}, msg: {:?}",
@@ -447,21 +658,22 @@ impl ContentServer {
         );
         // Handle Media request
         if self.texts.contains(&file_nr) {
+            // A file that can't be read is reported missing, so the client tries a replica
+            let content = match std::fs::read(content_file_path(&ContentType::Media, file_nr)) {
+                Ok(media) => MediaResponse::Media(file_nr, media),
+                Err(_e) => {
+                    debug!(
+                        "Server: {:?} failed to read media file {:?}: {}",
+                        self.id, file_nr, _e
+                    );
+                    MediaResponse::NotFound(file_nr)
+                }
+            };
             let session_id = random::<u64>();
             let message = Message {
                 source_id: self.id,
                 session_id,
-                content: MediaResponse::Media(
-                    file_nr,
-                    std::fs::read(format!("server_content/media_files/{}.jpg", file_nr))
-                        .unwrap_or_else(|_e| {
-                            debug!(
-                                "Server: {:?} failed to read media file {:?}: {}",
-                                self.id, file_nr, _e
-                            );
-                            vec![]
-                        }),
-                ),
+                content,
             };
             debug!(
                 "Server: {:?} sending Media response to client {:?}, msg: {:?}",
@@ -477,7 +689,7 @@ impl ContentServer {
             let message = Message {
                 source_id: self.id,
                 session_id,
-                content: MediaResponse::NotFound,
+                content: MediaResponse::NotFound(file_nr),
             };
             debug!(
                 "Server: {:?} sending NotFound response to client {:?}, msg: {:?}",
@@ -486,4 +698,122 @@ impl ContentServer {
             self.send_message_in_fragments(message_id, session_id, message);
         }
     }
+
+    fn handle_text_upload(&mut self, client_id: NodeId, text: String) {
+        let result = if self.content_type != ContentType::Text {
+            Err("this server only stores media files".to_string())
+        } else if text.trim().is_empty() {
+            Err("the text is empty".to_string())
+        } else if text.len() > MAX_TEXT_UPLOAD_SIZE {
+            Err(format!(
+                "text is {} bytes, the limit is {}",
+                text.len(),
+                MAX_TEXT_UPLOAD_SIZE
+            ))
+        } else {
+            self.store_file(text.as_bytes())
+        };
+
+        let content = match result {
+            Ok(file_id) => TextResponse::Uploaded(file_id),
+            Err(reason) => {
+                debug!(
+                    "Server: {:?} rejected text upload from {:?}: {}",
+                    self.id, client_id, reason
+                );
+                TextResponse::UploadRejected(reason)
+            }
+        };
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content,
+        };
+        self.send_message_in_fragments(client_id, session_id, message);
+    }
+
+    fn handle_media_upload(&mut self, client_id: NodeId, data: AttachmentData) {
+        debug!(
+            "Server: {:?} received MediaRequest::Upload from {:?}, {} bytes",
+            self.id,
+            client_id,
+            data.0.len()
+        );
+        let result = if self.content_type != ContentType::Media {
+            Err("this server only stores text files".to_string())
+        } else if data.0.len() > MAX_MEDIA_UPLOAD_SIZE {
+            Err(format!(
+                "image is {} bytes, the limit is {}",
+                data.0.len(),
+                MAX_MEDIA_UPLOAD_SIZE
+            ))
+        } else {
+            Self::as_jpeg(&data.0).and_then(|jpeg| self.store_file(&jpeg))
+        };
+
+        let content = match result {
+            Ok(file_id) => MediaResponse::Uploaded(file_id),
+            Err(reason) => {
+                debug!(
+                    "Server: {:?} rejected media upload from {:?}: {}",
+                    self.id, client_id, reason
+                );
+                MediaResponse::UploadRejected(reason)
+            }
+        };
+        let session_id = random::<u64>();
+        let message = Message {
+            source_id: self.id,
+            session_id,
+            content,
+        };
+        self.send_message_in_fragments(client_id, session_id, message);
+    }
+
+    // Media files are always served as .jpg, so other formats are converted
+    fn as_jpeg(data: &[u8]) -> Result<Vec<u8>, String> {
+        if let Ok(image::ImageFormat::Jpeg) = image::guess_format(data) {
+            image::load_from_memory(data).map_err(|e| format!("not a readable image: {}", e))?;
+            return Ok(data.to_vec());
+        }
+        let image =
+            image::load_from_memory(data).map_err(|e| format!("not a readable image: {}", e))?;
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image
+            .to_rgb8()
+            .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))
+            .map_err(|e| format!("failed to convert the image: {}", e))?;
+        Ok(jpeg.into_inner())
+    }
+
+    // Uploads go to the directory of this server, ids continue from its last upload
+    fn store_file(&mut self, data: &[u8]) -> Result<u64, String> {
+        let dir = upload_dir(self.id as u64, &self.content_type);
+        fs::create_dir_all(&dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+        let first_id = (self.id as u64 + 1) * UPLOAD_ID_BLOCK;
+        let mut file_id = read_file_ids(&dir.to_string_lossy())
+            .into_iter()
+            .max()
+            .map_or(first_id, |id| id + 1);
+
+        loop {
+            let path = content_file_path(&self.content_type, file_id);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    if let Err(e) = file.write_all(data) {
+                        let _ = fs::remove_file(&path);
+                        return Err(format!("failed to write {:?}: {}", path, e));
+                    }
+                    // Only this server has it, the replicas answer lists it with no other holder
+                    self.texts.push(file_id);
+                    self.replicas.insert(file_id, Vec::new());
+                    debug!("Server: {:?} stored uploaded file {:?}", self.id, path);
+                    return Ok(file_id);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => file_id += 1,
+                Err(e) => return Err(format!("failed to create {:?}: {}", path, e)),
+            }
+        }
+    }
 }
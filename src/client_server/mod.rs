@@ -1,4 +1,5 @@
 pub mod client;
 pub mod network_core;
 pub mod communication_server;
-pub mod content_server;
\ No newline at end of file
+pub mod content_server;
+pub mod chat_store;
\ No newline at end of file
@@ -1,11 +1,17 @@
+use crate::assembler::assembler::{AssembledMessage, MAX_FRAGMENTS};
 use crate::message::message::{
-    DroneSend, MediaResponse, MediaResponseForMessageContent, Message, MessageContent,
+    known_features, DroneSend, Envelope, ErrorResponse, Message, MessageContent, ProtocolError,
+    ProtocolMessage, ResendRequest, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
 };
 use crossbeam_channel::Sender;
+use rand::random;
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
 use wg_2024::network::{NodeId, SourceRoutingHeader};
 use wg_2024::packet::{FloodResponse, Fragment, NodeType, Packet};
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
 pub enum ClientServerCommand {
     // shared
@@ -13,30 +19,151 @@ pub enum ClientServerCommand {
     RemoveDrone(NodeId),
     AddDrone(NodeId, Sender<Packet>),
     PrintAllNodeData,
+    QueryNodeState, // node answers with a NodeState event
+    Shutdown, // node stops, the batch runner uses it between runs
     
     // client only
     RequestServerType, // client will auto call it to itself after few seconds after a StartFloodRequest
-    SendChatMessage(NodeId, String), // client send a chat message to a specific node
+    SendChatMessage(NodeId, String, String), // client send a chat message to a room of a specific node
+    EditChatMessage(NodeId, u64, String), // client replace the content of one of its messages
+    DeleteChatMessage(NodeId, u64), // client delete one of its messages
+    RoomListRequest(NodeId), // client ask the server for its rooms
+    CreateRoom(NodeId, String), // client create a room and join it
+    JoinRoom(NodeId, String), // client join an existing room
+    LeaveRoom(NodeId, String), // client leave a room
+    SendChatAttachment(NodeId, String, String, Vec<u8>), // client upload an image and send it to a room with a caption
+    RequestAttachment(NodeId, Attachment), // client fetch the image of a chat message
+    UploadText(NodeId, String), // client publish a text file on a text content server
+    UploadMedia(NodeId, Vec<u8>), // client publish an image on a media content server
     ClientListRequest(NodeId), // client request the list of client connected to the chatroom
     RegistrationRequest(NodeId), // client request to register itself to the server
+    UnregistrationRequest(NodeId), // client request to leave the server
     RequestTextList(NodeId), // client ask the server for its list of files
     RequestText(NodeId, u64), // client ask the server for a specific file
     RequestImageList(NodeId), // client ask the server for its list of images
     RequestImage(NodeId, u64), // client ask the server for a specific image
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ServerType {
     ContentServer(ContentType),
     CommunicationServer,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ContentType {
     Text,
     Media,
 }
 
+// Optional protocol extensions, exchanged in the server type handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ProtocolFeature {
+    Compression,
+    Encryption,
+}
+
+// What a client knows about a server after the handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerCapabilities {
+    pub server_type: ServerType,
+    pub protocol_version: u16,
+    #[serde(deserialize_with = "known_features")]
+    pub features: HashSet<ProtocolFeature>,
+}
+
+impl ServerCapabilities {
+    // The agreed version is the highest both sides speak, features are the ones both sides have
+    pub fn negotiate(
+        server_type: ServerType,
+        client_version: u16,
+        client_features: &HashSet<ProtocolFeature>,
+        server_features: &HashSet<ProtocolFeature>,
+    ) -> Self {
+        Self {
+            server_type,
+            protocol_version: client_version.min(PROTOCOL_VERSION),
+            features: client_features
+                .intersection(server_features)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: ProtocolFeature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    // False if the server is too old for anything but the handshake
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version >= MIN_PROTOCOL_VERSION
+    }
+}
+
+// What a node believes about the network, sent to the controller when asked
+#[derive(Debug, Clone)]
+pub struct NodeState {
+    pub node_id: NodeId,
+    pub routes: Vec<(NodeId, Vec<NodeId>)>, // destination -> source route found by the last flood
+    pub server_types: HashMap<NodeId, Option<ServerType>>, // client only, None until the server answers the handshake
+    pub registered_servers: HashSet<NodeId>, // client only
+    pub registered_clients: HashSet<NodeId>, // communication server only
+    pub connected_drones: HashSet<NodeId>,
+    pub assembler_backlog: usize, // packets waiting for the assembler plus messages waiting to be handled
+}
+
+// ChaCha20-Poly1305 key shared by a client-server pair
+pub type SessionKey = [u8; 32];
+
+// X25519 key exchange, every handshake uses a fresh key pair and its retries reuse it
+pub fn new_key_exchange() -> (EphemeralSecret, [u8; 32]) {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret).to_bytes();
+    (secret, public_key)
+}
+
+pub fn finish_key_exchange(secret: EphemeralSecret, other_public_key: [u8; 32]) -> SessionKey {
+    *secret
+        .diffie_hellman(&PublicKey::from(other_public_key))
+        .as_bytes()
+}
+
+pub fn key_exchange_public_key(secret: &EphemeralSecret) -> [u8; 32] {
+    PublicKey::from(secret).to_bytes()
+}
+
+// Keys a server agreed with its clients, with the public keys of the exchange
+#[derive(Default)]
+pub struct ServerSessionKeys {
+    keys: HashMap<NodeId, (SessionKey, [u8; 32], [u8; 32])>, // (key, client public key, server public key)
+}
+
+impl ServerSessionKeys {
+    pub fn get(&self, client_id: NodeId) -> Option<&SessionKey> {
+        self.keys.get(&client_id).map(|(key, _, _)| key)
+    }
+
+    // Answers a handshake, returns the server public key to send back. A retry with the same
+    // client key gets the same answer, so whichever answer reaches the client gives it our key.
+    // Without a client key, or if Encryption wasn't agreed, the old key is dropped
+    pub fn exchange(&mut self, client_id: NodeId, client_public_key: Option<[u8; 32]>) -> Option<[u8; 32]> {
+        let Some(client_public_key) = client_public_key else {
+            self.keys.remove(&client_id);
+            return None;
+        };
+        if let Some((_, known_client_key, server_public_key)) = self.keys.get(&client_id) {
+            if *known_client_key == client_public_key {
+                return Some(*server_public_key);
+            }
+        }
+        let (secret, server_public_key) = new_key_exchange();
+        let key = finish_key_exchange(secret, client_public_key);
+        self.keys
+            .insert(client_id, (key, client_public_key, server_public_key));
+        Some(server_public_key)
+    }
+}
+
 pub enum ServerEvent {
     PacketSent(Packet),
     PacketReceived(Packet),
@@ -49,6 +176,15 @@ pub enum ServerEvent {
         receiver: NodeId,
         content: MessageContent,
     },
+    IntegrityCheckFailed {
+        session_id: u64,
+        route: Vec<NodeId>,
+    },
+    PendingDeliveries {
+        server_id: NodeId,
+        queued: HashMap<NodeId, usize>, // client id -> messages waiting for it
+    },
+    NodeState(NodeState),
 }
 
 pub enum ClientEvent {
@@ -64,12 +200,102 @@ pub enum ClientEvent {
         content: MessageContent,
     },
     BrokenDroneDetected(NodeId),
+    IntegrityCheckFailed {
+        session_id: u64,
+        route: Vec<NodeId>,
+    },
+    // Every [image_N] of a downloaded text has arrived or was given up
+    TextResolved {
+        client_id: NodeId,
+        server_id: NodeId,
+        text_id: u64,
+        images: Vec<u64>,
+        missing: Vec<u64>, // images no server could provide
+    },
+    NodeState(NodeState),
 }
 
-#[derive(Debug, Clone)]
+// Registered clients send a heartbeat this often, the server marks them offline after missing a few
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Every client joins it when registering, it's also the room of messages stored before rooms existed
+pub const DEFAULT_ROOM: &str = "general";
+
+pub fn default_room() -> String {
+    DEFAULT_ROOM.to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
+    #[serde(default)]
+    pub id: u64, // assigned by the server, increasing in the order messages were stored
+    #[serde(default = "default_room")]
+    pub room: String,
     pub sender_id: NodeId,
     pub content: String,
+    #[serde(default)]
+    pub sent_at: Option<i64>, // sender clock, ms since the epoch
+    #[serde(default)]
+    pub stored_at: i64, // server clock, ms since the epoch
+    #[serde(default)]
+    pub edited: bool,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub origin: Option<(NodeId, u64)>, // (server, id there) of a message relayed by a federated server
+    #[serde(default)]
+    pub attachment: Option<Attachment>,
+}
+
+// An image shown with a chat message, fetched by the recipients when they want to see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Attachment {
+    Upload(u64), // stored by the communication server
+    Media(u64),  // an [image_N] of the media content servers
+}
+
+const SENT_MESSAGES_CAPACITY: usize = 64;
+const MAX_RESENDS: u8 = 3;
+
+// Last messages sent by a node, kept so they can be sent again if they arrive corrupted
+pub struct SentMessages {
+    order: VecDeque<u64>,
+    messages: HashMap<u64, (NodeId, Vec<u8>, u8)>, // session id -> (target, encoded message, n. resends)
+}
+
+impl SentMessages {
+    pub fn new() -> Self {
+        Self {
+            order: VecDeque::new(),
+            messages: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, session_id: u64, target_node_id: NodeId, data: Vec<u8>) {
+        if self.messages.insert(session_id, (target_node_id, data, 0)).is_none() {
+            self.order.push_back(session_id);
+        }
+        while self.order.len() > SENT_MESSAGES_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    // None if the message is gone or was already resent too many times
+    pub fn take_for_resend(&mut self, session_id: u64) -> Option<(NodeId, Vec<u8>)> {
+        let (target_node_id, data, resends) = self.messages.get_mut(&session_id)?;
+        if *resends >= MAX_RESENDS {
+            return None;
+        }
+        *resends += 1;
+        Some((*target_node_id, data.clone()))
+    }
+
+    pub fn target(&self, session_id: u64) -> Option<NodeId> {
+        self.messages.get(&session_id).map(|(target_node_id, _, _)| *target_node_id)
+    }
 }
 
 pub trait NetworkNode {
@@ -78,6 +304,9 @@ pub trait NetworkNode {
     fn packet_send(&self) -> &HashMap<NodeId, Sender<Packet>>;
     fn topology_map(&self) -> &HashSet<(NodeId, Vec<NodeId>)>;
     fn topology_map_mut(&mut self) -> &mut HashSet<(NodeId, Vec<NodeId>)>;
+    fn sent_messages_mut(&mut self) -> &mut SentMessages;
+    fn session_key(&self, node_id: NodeId) -> Option<&SessionKey>;
+    fn peer_version(&self, node_id: NodeId) -> Option<u16>; // agreed in the handshake
     // fn assembler_send(&self) -> &Sender<Packet>;
 
     // common methods to implement
@@ -86,9 +315,11 @@ pub trait NetworkNode {
     fn send_packet_received_to_sc(&mut self, packet: Packet);
     fn send_message_sent_to_sc(&mut self, content: MessageContent, target: NodeId);
     fn send_message_received_to_sc(&mut self, content: MessageContent);
+    fn send_integrity_failure_to_sc(&mut self, session_id: u64, route: Vec<NodeId>);
+    fn use_compression(&self, target_node_id: NodeId) -> bool;
 
     // common methods with default implementations
-    fn update_topology_with_flood_response(&mut self, flood_response: &FloodResponse, is_client: bool) {
+    fn update_topology_with_flood_response(&mut self, flood_response: &FloodResponse, kept_node_types: &[NodeType]) {
         let _node_id = self.id();
         let topology_map = self.topology_map_mut();
 
@@ -105,18 +336,11 @@ pub trait NetworkNode {
             // Case 1: New node entry - add to topology map
             let &(_, node_type) = flood_response.path_trace.last().unwrap();
             
-            // Only add servers if it's the clients to topology map
-            // or clients if it's the servers to topology map
-            if is_client {
-                if node_type != NodeType::Drone && node_type != NodeType::Client {
-                    topology_map.insert((target_node_id, new_path));
-                    debug!("Client {:?}, updated topology_map: {:?}", _node_id, topology_map);
-                }
-            } else {
-                if node_type != NodeType::Drone && node_type != NodeType::Server {
-                    topology_map.insert((target_node_id, new_path));
-                    debug!("Server {:?}, updated topology_map: {:?}", _node_id, topology_map);
-                }
+            // Only add the kind of nodes this node talks to:
+            // servers for clients, clients for servers (and other servers for federated ones)
+            if kept_node_types.contains(&node_type) && target_node_id != _node_id {
+                topology_map.insert((target_node_id, new_path));
+                debug!("Node {:?}, updated topology_map: {:?}", _node_id, topology_map);
             }
         } else {
             // Case 2: Existing node - check if new path is better
@@ -164,6 +388,12 @@ pub trait NetworkNode {
             self.try_send_packet_with_target_id(&target_node_id, packet);
         }
     }
+    // Routes of the topology map, sorted by destination
+    fn known_routes(&self) -> Vec<(NodeId, Vec<NodeId>)> {
+        let mut routes: Vec<(NodeId, Vec<NodeId>)> = self.topology_map().iter().cloned().collect();
+        routes.sort();
+        routes
+    }
     fn compute_path_to_node(&self, target_node_id: NodeId) -> Result<Vec<NodeId>, String> {
         let topology_map = self.topology_map();
         let path = topology_map.iter().find(|(id, _)| *id == target_node_id);
@@ -178,16 +408,63 @@ pub trait NetworkNode {
         target_node_id: NodeId,
         session_id: u64,
         message: Message<M>,
-    ) {
+    ) -> bool {
         let _id = self.id();
         debug!("Node {:?} sending message to {:?}", _id, target_node_id);
 
-        // Serialize the message
-        let serialized = serde_json::to_string(&message).unwrap();
-        let serialized_bytes = serialized.into_bytes();
+        let mut envelope = message.into_envelope();
+        let content = MessageContent::from_protocol(&envelope.payload);
+
+        // Written in the version agreed with the target. Before the handshake that's unknown,
+        // the message goes in the oldest version that has it, and so does the handshake itself
+        // so that a peer agreed on an older version can still upgrade
+        let payload_version = envelope.payload.since_version();
+        let peer_version = match envelope.payload.is_handshake() {
+            true => None,
+            false => self.peer_version(target_node_id),
+        };
+        envelope.version = match peer_version {
+            Some(version) if version < payload_version => {
+                debug!(
+                    "Node {:?} not sending {:?} to {:?}, it only speaks protocol v{}",
+                    _id, content, target_node_id, version
+                );
+                return false;
+            }
+            Some(version) => version.min(PROTOCOL_VERSION),
+            None => payload_version,
+        };
+
+        // Serialize the message, encrypted if a key was agreed with the target
+        let compress = self.use_compression(target_node_id);
+        let envelope = match self.session_key(target_node_id) {
+            Some(key) => envelope.seal(key, compress),
+            None => envelope,
+        };
+        let serialized_bytes = envelope.encode(compress);
 
+        if self.send_bytes_in_fragments(target_node_id, session_id, &serialized_bytes) {
+            self.sent_messages_mut()
+                .insert(session_id, target_node_id, serialized_bytes);
+
+            // Send message sent notification
+            self.send_message_sent_to_sc(content, target_node_id);
+            return true;
+        }
+        false
+    }
+    fn send_bytes_in_fragments(&mut self, target_node_id: NodeId, session_id: u64, serialized_bytes: &[u8]) -> bool {
         // Calculate fragments needed
         let total_fragments = (serialized_bytes.len() + 127) / 128;
+        if total_fragments as u64 > MAX_FRAGMENTS {
+            debug!(
+                "Node {:?} not sending session {:?}, {} fragments are more than the receiver accepts",
+                self.id(),
+                session_id,
+                total_fragments
+            );
+            return false;
+        }
 
         // Compute path to target
         match self.compute_path_to_node(target_node_id) {
@@ -216,28 +493,91 @@ pub trait NetworkNode {
 
                     self.try_send_packet_with_target_id(&path[1], &packet);
                 }
-                
-                // Send message sent notification
-                if let Ok(content) = serde_json::to_value(&message.content)
-                    .and_then(|v| serde_json::from_value::<MediaResponse>(v))
-                {
-                    let message_content =
-                        MessageContent::MediaResponse(MediaResponseForMessageContent::new(content));
-                    self.send_message_sent_to_sc(message_content, target_node_id);
-                } else {
-                    if let Some(content) = MessageContent::from_content(message.content) {
-                        self.send_message_sent_to_sc(content, target_node_id);
-                    }
-                }
+                true
             }
             Err(_e) => {
                 debug!(
                     "ERROR: Could not compute path to node {:?}: {}",
                     target_node_id, _e
                 );
+                false
+            }
+        }
+    }
+    fn handle_corrupted_message(&mut self, assembled: AssembledMessage) {
+        debug!(
+            "Node {:?} received a corrupted message, session: {:?}, route: {:?}",
+            self.id(),
+            assembled.session_id,
+            assembled.route
+        );
+        self.send_integrity_failure_to_sc(assembled.session_id, assembled.route.clone());
+
+        // The header can't be trusted, the route tells who sent it
+        if let Some(&source_id) = assembled.route.first() {
+            let session_id = random::<u64>();
+            let message = Message {
+                source_id: self.id(),
+                session_id,
+                content: ResendRequest {
+                    session_id: assembled.session_id,
+                },
+            };
+            self.send_message_in_fragments(source_id, session_id, message);
+        }
+    }
+    fn resend_message(&mut self, session_id: u64) {
+        match self.sent_messages_mut().take_for_resend(session_id) {
+            Some((target_node_id, serialized_bytes)) => {
+                debug!(
+                    "Node {:?} resending session {:?} to {:?}",
+                    self.id(),
+                    session_id,
+                    target_node_id
+                );
+                self.send_bytes_in_fragments(target_node_id, session_id, &serialized_bytes);
+            }
+            None => {
+                debug!(
+                    "Node {:?} can't resend session {:?}, giving up",
+                    self.id(),
+                    session_id
+                );
             }
         }
     }
+    fn open_envelope(&self, envelope: Envelope) -> Result<Envelope, ProtocolError> {
+        let key = self.session_key(envelope.source_id);
+        envelope.open(key)
+    }
+    fn reply_to_protocol_error(&mut self, error: ProtocolError) {
+        debug!(
+            "Node {:?} could not decode assembled message: {:?}",
+            self.id(),
+            error
+        );
+
+        // Without a readable header there is no one to answer to
+        if let Some((source_id, session_id)) = error.sender {
+            let message = Message {
+                source_id: self.id(),
+                session_id,
+                content: error.error,
+            };
+            self.send_message_in_fragments(source_id, session_id, message);
+        }
+    }
+    fn reply_unexpected_message(&mut self, target_node_id: NodeId, session_id: u64, payload: &ProtocolMessage) {
+        // Only requests get an answer, replying to a response could bounce forever
+        if let ProtocolMessage::Request(_) = payload {
+            let message = Message {
+                source_id: self.id(),
+                session_id,
+                content: ErrorResponse::UnexpectedMessage(format!("{:?}", payload)),
+            };
+            self.send_message_in_fragments(target_node_id, session_id, message);
+        }
+    }
 
     // fn send_fragment_to_assembler(&mut self, packet: Packet) -> Result<(), String> {
     //     // send the packet to the assembler
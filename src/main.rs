@@ -1,4 +1,5 @@
 use std::env;
+use std::path::PathBuf;
 
 #[cfg(feature = "debug")]
 #[macro_export]
@@ -21,11 +22,35 @@ mod client_server;
 fn main() {
     debug!("Running in Debug mode");
 
+    // `cargo run -- batch path/to/batch.toml` runs the experiments of the file without the GUI
+    if env::args().nth(1).as_deref() == Some("batch") {
+        let batch_path = env::args().nth(2).unwrap_or_else(|| "src/batch.toml".to_string());
+        if let Err(e) = simulation_controller::batch::main(&batch_path) {
+            eprintln!("Batch failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Get config file path from command line arguments or use default
     let config_path = env::args()
         .nth(1)
         .unwrap_or_else(|| "src/config.toml".to_string());
 
+    // Directory where communication servers keep their chat history
+    let chat_history_dir = env::args()
+        .nth(2)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("chat_history"));
+
+    // Directory where every run writes its JSON-lines event log
+    let event_log_dir = env::args()
+        .nth(3)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("event_logs"));
+
     debug!("Using configuration file: {}", config_path);
-    network_initializer::network_initializer::main(config_path);
+    debug!("Using chat history directory: {:?}", chat_history_dir);
+    debug!("Using event log directory: {:?}", event_log_dir);
+    network_initializer::network_initializer::main(config_path, chat_history_dir, event_log_dir);
 }
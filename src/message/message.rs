@@ -1,7 +1,18 @@
-use crate::client_server::network_core::{ChatMessage, ServerType};
+use crate::client_server::network_core::{
+    default_room, Attachment, ChatMessage, ProtocolFeature, ServerCapabilities, ServerType,
+    SessionKey,
+};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use rand::random;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
 use wg_2024::network::NodeId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +27,7 @@ pub struct Message<M: DroneSend> {
 pub trait DroneSend: Serialize + DeserializeOwned + std::fmt::Debug {
     fn stringify(&self) -> String;
     fn from_string(raw: String) -> Result<Self, String>;
+    fn into_protocol(self) -> ProtocolMessage;
 }
 
 pub trait Request: DroneSend {
@@ -37,60 +49,440 @@ pub enum MessageContent {
     WholeChatVecResponse(Chatroom),
     ChatRequest(ChatRequest),
     ChatResponse(ChatResponse),
+    ResendRequest(ResendRequest),
     MediaRequest(MediaRequest),
     MediaResponse(MediaResponseForMessageContent),
     MediaListWithServer(NodeId, Vec<u64>),
     MediaIdWithServer(NodeId, u64),
+    ReplicasWithServer(NodeId, HashMap<u64, Vec<NodeId>>),
+    CapabilitiesWithServer(NodeId, ServerCapabilities),
+    ChatHistoryWithServer(NodeId, Vec<ChatMessage>),
+    RoomListWithServer(NodeId, Vec<RoomInfo>),
+    AttachmentWithServer(NodeId, u64, AttachmentData),
+    ErrorResponse(ErrorResponse),
+    Encrypted(usize), // length of a body that couldn't be read
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MediaResponseForMessageContent {
     MediaList(Vec<u64>),
     Media(u64),
-    NotFound,
+    NotFound(u64),
+    Uploaded(u64),
+    UploadRejected(String),
+    Replicas(HashMap<u64, Vec<NodeId>>),
 }
 
 impl MediaResponseForMessageContent {
-    pub fn new(media_response: MediaResponse) -> Self {
-        match &media_response {
+    pub fn new(media_response: &MediaResponse) -> Self {
+        match media_response {
             MediaResponse::MediaList(_m) => Self::MediaList(_m.clone()),
-            MediaResponse::Media(_m, _) => Self::Media(_m.clone()),
-            MediaResponse::NotFound => Self::NotFound,
+            MediaResponse::Media(_m, _) => Self::Media(*_m),
+            MediaResponse::NotFound(id) => Self::NotFound(*id),
+            MediaResponse::Uploaded(id) => Self::Uploaded(*id),
+            MediaResponse::UploadRejected(reason) => Self::UploadRejected(reason.clone()),
+            MediaResponse::Replicas(replicas) => Self::Replicas(replicas.clone()),
         }
     }
 }
 
 impl MessageContent {
-    // Converts a message content into a MessageContent enum variant
-    pub fn from_content<T: DroneSend>(content: T) -> Option<Self> {
-        // Try type conversions for each supported message type
-        if let Ok(content) = serde_json::to_value(&content)
-            .and_then(|v| serde_json::from_value::<ServerTypeRequest>(v))
-        {
-            Some(MessageContent::ServerTypeRequest(content))
-        } else if let Ok(content) = serde_json::to_value(&content)
-            .and_then(|v| serde_json::from_value::<ServerTypeResponse>(v))
-        {
-            Some(MessageContent::ServerTypeResponse(content))
-        } else if let Ok(content) =
-            serde_json::to_value(&content).and_then(|v| serde_json::from_value::<TextRequest>(v))
-        {
-            Some(MessageContent::TextRequest(content))
-        } else if let Ok(content) =
-            serde_json::to_value(&content).and_then(|v| serde_json::from_value::<TextResponse>(v))
-        {
-            Some(MessageContent::TextResponse(content))
-        } else if let Ok(content) =
-            serde_json::to_value(&content).and_then(|v| serde_json::from_value::<MediaRequest>(v))
-        {
-            Some(MessageContent::MediaRequest(content))
-        } else if let Ok(content) = serde_json::to_value(&content)
-            .and_then(|v| serde_json::from_value::<MediaResponseForMessageContent>(v))
-        {
-            Some(MessageContent::MediaResponse(content))
+    // Converts a decoded protocol message into a MessageContent enum variant
+    pub fn from_protocol(payload: &ProtocolMessage) -> Self {
+        match payload {
+            ProtocolMessage::Request(request) => match request {
+                RequestMessage::ServerType(r) => MessageContent::ServerTypeRequest(r.clone()),
+                RequestMessage::Text(r) => MessageContent::TextRequest(r.clone()),
+                RequestMessage::Media(r) => MessageContent::MediaRequest(r.clone()),
+                RequestMessage::Chat(r) => MessageContent::ChatRequest(r.clone()),
+                RequestMessage::Resend(r) => MessageContent::ResendRequest(r.clone()),
+            },
+            ProtocolMessage::Response(response) => match response {
+                ResponseMessage::ServerType(r) => MessageContent::ServerTypeResponse(r.clone()),
+                ResponseMessage::Text(r) => MessageContent::TextResponse(r.clone()),
+                ResponseMessage::Media(r) => {
+                    MessageContent::MediaResponse(MediaResponseForMessageContent::new(r))
+                }
+                ResponseMessage::Chat(r) => MessageContent::ChatResponse(r.clone()),
+                ResponseMessage::Error(r) => MessageContent::ErrorResponse(r.clone()),
+            },
+            ProtocolMessage::Encrypted(encrypted) => {
+                MessageContent::Encrypted(encrypted.ciphertext.len())
+            }
+        }
+    }
+}
+
+// Bumped with every change to the messages:
+//   1: envelope, GetServerType handshake
+//   2: Handshake with version and features, UnsupportedVersion errors
+//   3: checksums and resends, encryption, chat ids, edits, rooms, presence, relays,
+//      attachments, uploads, replicas, NotFound with the file id
+pub const PROTOCOL_VERSION: u16 = 3;
+// Oldest version this build talks to. Older peers still get the handshake and errors,
+// anything else they send is answered with UnsupportedVersion and nothing else is sent to them
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+// Oldest version whose handshake is still understood
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+// Messages smaller than this are sent as they are, compressing them isn't worth it
+pub const COMPRESSION_THRESHOLD: usize = 256;
+// A message starts with a flags byte, an old plain message starts directly with '{'
+const FLAG_COMPRESSED: u8 = 0x01;
+// The flags byte is followed by the CRC32 of the rest of the message
+const FLAG_CHECKSUM: u8 = 0x02;
+const CHECKSUM_LEN: usize = 4;
+
+// What actually travels inside the fragments, every node decodes it in one step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u16,
+    pub source_id: NodeId,
+    pub session_id: u64,
+    pub payload: ProtocolMessage,
+}
+
+// Used to still answer the sender when the payload itself can't be decoded
+#[derive(Debug, Deserialize)]
+struct EnvelopeHeader {
+    version: u16,
+    source_id: NodeId,
+    session_id: u64,
+    #[serde(default)]
+    payload: Option<PayloadFamily>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PayloadFamily {
+    family: String,
+    #[serde(default)]
+    body: Option<PayloadKind>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PayloadKind {
+    #[serde(default)]
+    kind: Option<String>,
+}
+
+impl EnvelopeHeader {
+    // Responses are never answered, otherwise two nodes could bounce errors forever
+    fn expects_reply(&self) -> bool {
+        !matches!(&self.payload, Some(p) if p.family == "Response")
+    }
+
+    // The handshake and errors are the only messages still read from peers too old to talk to
+    fn is_handshake_or_error(&self) -> bool {
+        let kind = self
+            .payload
+            .as_ref()
+            .and_then(|p| p.body.as_ref())
+            .and_then(|body| body.kind.as_deref());
+        matches!(kind, Some("ServerType") | Some("Error"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "family", content = "body")]
+pub enum ProtocolMessage {
+    Request(RequestMessage),
+    Response(ResponseMessage),
+    Encrypted(EncryptedPayload), // a Request or Response sealed with the session key
+}
+
+impl ProtocolMessage {
+    // Oldest version that has this message, it's never sent to a peer that agreed on an older one
+    pub fn since_version(&self) -> u16 {
+        match self {
+            ProtocolMessage::Request(RequestMessage::ServerType(ServerTypeRequest::GetServerType))
+            | ProtocolMessage::Response(ResponseMessage::ServerType(ServerTypeResponse::ServerType(_))) => {
+                LEGACY_PROTOCOL_VERSION
+            }
+            ProtocolMessage::Request(RequestMessage::ServerType(ServerTypeRequest::Handshake { .. }))
+            | ProtocolMessage::Response(ResponseMessage::Error(_)) => 2,
+            _ => PROTOCOL_VERSION,
+        }
+    }
+
+    // The handshake carries the keys, so it can never be encrypted itself
+    pub fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            ProtocolMessage::Request(RequestMessage::ServerType(_))
+                | ProtocolMessage::Response(ResponseMessage::ServerType(_))
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    #[serde(with = "hex_bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub ciphertext: Vec<u8>,
+    #[serde(default)]
+    pub compressed: bool, // the plaintext was deflated before encrypting
+}
+
+// None if compression is off, the data is too small or it doesn't actually save fragments
+fn deflate(data: &[u8], compress: bool) -> Option<Vec<u8>> {
+    if !compress || data.len() <= COMPRESSION_THRESHOLD {
+        return None;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .ok()
+        .filter(|compressed| compressed.len() < data.len())
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(data)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| e.to_string())?;
+    Ok(decompressed)
+}
+
+// Uploaded file bytes, only their size shows up in the logs
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AttachmentData(#[serde(with = "hex_bytes")] pub Vec<u8>);
+
+impl std::fmt::Debug for AttachmentData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AttachmentData({} bytes)", self.0.len())
+    }
+}
+
+// Bytes are sent as hex strings, a JSON array of numbers would take twice the fragments
+mod hex_bytes {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(|| D::Error::custom("invalid hex digit"))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "content")]
+pub enum RequestMessage {
+    ServerType(ServerTypeRequest),
+    Text(TextRequest),
+    Media(MediaRequest),
+    Chat(ChatRequest),
+    Resend(ResendRequest),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "content")]
+pub enum ResponseMessage {
+    ServerType(ServerTypeResponse),
+    Text(TextResponse),
+    Media(MediaResponse),
+    Chat(ChatResponse),
+    Error(ErrorResponse),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub sender: Option<(NodeId, u64)>, // (source_id, session_id) if the header could be read
+    pub error: ErrorResponse,
+}
+
+impl<M: DroneSend> Message<M> {
+    // In the latest version, the sender lowers it to the one agreed with the receiver
+    pub fn into_envelope(self) -> Envelope {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            source_id: self.source_id,
+            session_id: self.session_id,
+            payload: self.content.into_protocol(),
+        }
+    }
+}
+
+impl Envelope {
+    pub fn encode(&self, compress: bool) -> Vec<u8> {
+        let serialized = serde_json::to_vec(self).unwrap();
+        // Older peers read the bare JSON, without flags and checksum
+        if self.version < MIN_PROTOCOL_VERSION {
+            return serialized;
+        }
+        let (flags, body) = match deflate(&serialized, compress) {
+            Some(compressed) => (FLAG_COMPRESSED, compressed),
+            None => (0, serialized),
+        };
+
+        let mut frame = Vec::with_capacity(1 + CHECKSUM_LEN + body.len());
+        frame.push(flags | FLAG_CHECKSUM);
+        frame.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        frame.extend_from_slice(&body);
+        frame
+    }
+
+    // Encrypts the payload, the header stays readable for the receiver to pick the key. The
+    // payload is compressed first, ciphertext doesn't compress
+    pub fn seal(self, key: &SessionKey, compress: bool) -> Self {
+        if self.payload.is_handshake() {
+            return self;
+        }
+        let serialized = serde_json::to_vec(&self.payload).unwrap();
+        let compressed = deflate(&serialized, compress);
+        let is_compressed = compressed.is_some();
+        let plaintext = compressed.unwrap_or(serialized);
+        let nonce: [u8; 12] = random();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let sealed = cipher.encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad: &self.associated_data(),
+            },
+        );
+        match sealed {
+            Ok(ciphertext) => Envelope {
+                version: self.version,
+                source_id: self.source_id,
+                session_id: self.session_id,
+                payload: ProtocolMessage::Encrypted(EncryptedPayload {
+                    nonce: nonce.to_vec(),
+                    ciphertext,
+                    compressed: is_compressed,
+                }),
+            },
+            Err(_) => self,
+        }
+    }
+
+    // Plain payloads are returned as they are
+    pub fn open(self, key: Option<&SessionKey>) -> Result<Self, ProtocolError> {
+        let ProtocolMessage::Encrypted(encrypted) = &self.payload else {
+            return Ok(self);
+        };
+        // The sender can't be trusted to be who the header says, so no reply
+        let error = |reason: String| ProtocolError {
+            sender: None,
+            error: ErrorResponse::MalformedMessage(reason),
+        };
+
+        let key = key.ok_or_else(|| error("No session key for the sender".to_string()))?;
+        if encrypted.nonce.len() != 12 {
+            return Err(error("Invalid nonce".to_string()));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(&encrypted.nonce),
+                Payload {
+                    msg: &encrypted.ciphertext,
+                    aad: &self.associated_data(),
+                },
+            )
+            .map_err(|_| error("Could not decrypt the payload".to_string()))?;
+        let plaintext = if encrypted.compressed {
+            inflate(&plaintext).map_err(error)?
+        } else {
+            plaintext
+        };
+        let payload = serde_json::from_slice(&plaintext).map_err(|e| error(e.to_string()))?;
+
+        Ok(Envelope { payload, ..self })
+    }
+
+    // Binds the ciphertext to its header, so it can't be replayed under another sender or session
+    fn associated_data(&self) -> Vec<u8> {
+        let mut aad = vec![self.source_id];
+        aad.extend_from_slice(&self.session_id.to_be_bytes());
+        aad
+    }
+
+    // Splits a message in (flags, checksum, body), None if it's too short to even have a header
+    fn split_frame(data: &[u8]) -> Option<(u8, Option<u32>, &[u8])> {
+        match data.first()? {
+            b'{' => Some((0, None, data)),
+            &flags if flags & FLAG_CHECKSUM != 0 => {
+                let checksum = data.get(1..1 + CHECKSUM_LEN)?;
+                let checksum = u32::from_be_bytes(checksum.try_into().ok()?);
+                Some((flags, Some(checksum), &data[1 + CHECKSUM_LEN..]))
+            }
+            &flags => Some((flags, None, &data[1..])),
+        }
+    }
+
+    // False if the message was corrupted or truncated on its way. Only the bare JSON of legacy
+    // peers goes without a checksum, a flipped flag can't turn the check off
+    pub fn verify_checksum(data: &[u8]) -> bool {
+        match Self::split_frame(data) {
+            Some((_, Some(checksum), body)) => crc32fast::hash(body) == checksum,
+            Some((_, None, body)) if data.first() == Some(&b'{') => Self::is_legacy(body),
+            _ => false,
+        }
+    }
+
+    fn is_legacy(body: &[u8]) -> bool {
+        let end = body.iter().position(|&b| b == 0).unwrap_or(body.len());
+        serde_json::from_slice::<EnvelopeHeader>(&body[..end])
+            .is_ok_and(|header| header.version < MIN_PROTOCOL_VERSION)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (flags, _, body) = Self::split_frame(data).ok_or_else(|| ProtocolError {
+            sender: None,
+            error: ErrorResponse::MalformedMessage("Empty message".to_string()),
+        })?;
+        let data: Cow<[u8]> = if flags & FLAG_COMPRESSED != 0 {
+            Cow::Owned(inflate(body).map_err(|e| ProtocolError {
+                sender: None,
+                error: ErrorResponse::MalformedMessage(e),
+            })?)
         } else {
-            None
+            Cow::Borrowed(body)
+        };
+
+        // The last fragment may be padded with zeros, the message ends at the first one
+        let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        let raw = std::str::from_utf8(&data[..end]).map_err(|e| ProtocolError {
+            sender: None,
+            error: ErrorResponse::MalformedMessage(e.to_string()),
+        })?;
+
+        let header = serde_json::from_str::<EnvelopeHeader>(raw).ok();
+        let sender = header
+            .as_ref()
+            .filter(|h| h.expects_reply())
+            .map(|h| (h.source_id, h.session_id));
+        if let Some(header) = &header {
+            let too_old = header.version < MIN_PROTOCOL_VERSION && !header.is_handshake_or_error();
+            if too_old || !(LEGACY_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&header.version) {
+                return Err(ProtocolError {
+                    sender,
+                    error: ErrorResponse::UnsupportedVersion(header.version),
+                });
+            }
         }
+
+        serde_json::from_str::<Envelope>(raw).map_err(|e| ProtocolError {
+            sender,
+            error: ErrorResponse::MalformedMessage(e.to_string()),
+        })
     }
 }
 
@@ -99,6 +491,8 @@ impl MessageContent {
 pub enum TextRequest {
     TextList,
     Text(u64),
+    Upload(String), // publish a new text file
+    Replicas, // which other servers hold the files of this one
 }
 
 impl DroneSend for TextRequest {
@@ -108,6 +502,9 @@ impl DroneSend for TextRequest {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Request(RequestMessage::Text(self))
+    }
 }
 
 impl Request for TextRequest {
@@ -115,6 +512,8 @@ impl Request for TextRequest {
         match self {
             TextRequest::TextList => "TextList".to_string(),
             TextRequest::Text(_) => "Text".to_string(),
+            TextRequest::Upload(_) => "Upload".to_string(),
+            TextRequest::Replicas => "Replicas".to_string(),
         }
     }
 }
@@ -123,6 +522,8 @@ impl Request for TextRequest {
 pub enum MediaRequest {
     MediaList,
     Media(u64),
+    Upload(AttachmentData), // publish a new image
+    Replicas, // which other servers hold the files of this one
 }
 
 impl DroneSend for MediaRequest {
@@ -132,6 +533,9 @@ impl DroneSend for MediaRequest {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Request(RequestMessage::Media(self))
+    }
 }
 
 impl Request for MediaRequest {
@@ -139,6 +543,8 @@ impl Request for MediaRequest {
         match self {
             MediaRequest::MediaList => "MediaList".to_string(),
             MediaRequest::Media(_) => "Media".to_string(),
+            MediaRequest::Upload(_) => "Upload".to_string(),
+            MediaRequest::Replicas => "Replicas".to_string(),
         }
     }
 }
@@ -147,7 +553,47 @@ impl Request for MediaRequest {
 pub enum ChatRequest {
     ClientList,
     Register(NodeId),
-    SendMessage { from: NodeId, message: String },
+    Unregister, // always the client sending it
+    Heartbeat, // keeps the client online, any other request does too
+    SendMessage {
+        from: NodeId,
+        message: String,
+        #[serde(default)]
+        sent_at: Option<i64>, // sender clock, ms since the epoch
+        #[serde(default = "default_room")]
+        room: String,
+        #[serde(default)]
+        attachment: Option<Attachment>,
+    },
+    History {
+        #[serde(default = "default_room")]
+        room: String,
+        range: HistoryRange,
+    },
+    Edit { id: u64, message: String }, // only the sender of the message can change it
+    Delete(u64),
+    ListRooms,
+    Relay {
+        message: ChatMessage, // its origin is set
+        via: Vec<NodeId>,     // servers that already stored it
+        #[serde(default)]
+        attachment: Option<AttachmentData>, // the uploaded image, peers can't fetch it from the origin
+    },
+    UploadAttachment {
+        upload_id: u64, // chosen by the client to match the answer
+        data: AttachmentData,
+    },
+    GetAttachment(u64),
+    CreateRoom(String), // also joins it
+    JoinRoom(String),
+    LeaveRoom(String),
+}
+
+// Which part of the stored chat a client wants
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HistoryRange {
+    Last(usize),
+    Since(u64), // messages after this id
 }
 
 impl DroneSend for ChatRequest {
@@ -157,13 +603,28 @@ impl DroneSend for ChatRequest {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Request(RequestMessage::Chat(self))
+    }
 }
 impl Request for ChatRequest {
     fn request_type(&self) -> String {
         match self {
             ChatRequest::ClientList => "ClientList".to_string(),
             ChatRequest::Register(_) => "Register".to_string(),
+            ChatRequest::Unregister => "Unregister".to_string(),
+            ChatRequest::Heartbeat => "Heartbeat".to_string(),
             ChatRequest::SendMessage { .. } => "SendMessage".to_string(),
+            ChatRequest::History { .. } => "History".to_string(),
+            ChatRequest::Edit { .. } => "Edit".to_string(),
+            ChatRequest::Delete(_) => "Delete".to_string(),
+            ChatRequest::ListRooms => "ListRooms".to_string(),
+            ChatRequest::Relay { .. } => "Relay".to_string(),
+            ChatRequest::UploadAttachment { .. } => "UploadAttachment".to_string(),
+            ChatRequest::GetAttachment(_) => "GetAttachment".to_string(),
+            ChatRequest::CreateRoom(_) => "CreateRoom".to_string(),
+            ChatRequest::JoinRoom(_) => "JoinRoom".to_string(),
+            ChatRequest::LeaveRoom(_) => "LeaveRoom".to_string(),
         }
     }
 }
@@ -172,7 +633,10 @@ impl Request for ChatRequest {
 pub enum TextResponse {
     TextList(Vec<u64>),
     Text(TextAndId),
-    NotFound,
+    NotFound(u64), // id of the missing file
+    Uploaded(u64), // id given to the new file
+    UploadRejected(String),
+    Replicas(HashMap<u64, Vec<NodeId>>), // file id -> other servers holding it
 }
 
 type TextAndId = (u64, String);
@@ -184,6 +648,9 @@ impl DroneSend for TextResponse {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Response(ResponseMessage::Text(self))
+    }
 }
 
 impl Response for TextResponse {
@@ -191,7 +658,10 @@ impl Response for TextResponse {
         match self {
             TextResponse::TextList(_) => "TextList".to_string(),
             TextResponse::Text(_) => "Text".to_string(),
-            TextResponse::NotFound => "NotFound".to_string(),
+            TextResponse::NotFound(_) => "NotFound".to_string(),
+            TextResponse::Uploaded(_) => "Uploaded".to_string(),
+            TextResponse::UploadRejected(_) => "UploadRejected".to_string(),
+            TextResponse::Replicas(_) => "Replicas".to_string(),
         }
     }
 }
@@ -200,7 +670,10 @@ impl Response for TextResponse {
 pub enum MediaResponse {
     MediaList(Vec<u64>),
     Media(u64, Vec<u8>),
-    NotFound,
+    NotFound(u64), // id of the missing file
+    Uploaded(u64), // id given to the new file
+    UploadRejected(String),
+    Replicas(HashMap<u64, Vec<NodeId>>), // file id -> other servers holding it
 }
 
 impl DroneSend for MediaResponse {
@@ -210,24 +683,65 @@ impl DroneSend for MediaResponse {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Response(ResponseMessage::Media(self))
+    }
 }
 impl Response for MediaResponse {
     fn response_type(&self) -> String {
         match self {
             MediaResponse::MediaList(_) => "MediaList".to_string(),
             MediaResponse::Media(_, _) => "Media".to_string(),
-            MediaResponse::NotFound => "NotFound".to_string(),
+            MediaResponse::NotFound(_) => "NotFound".to_string(),
+            MediaResponse::Uploaded(_) => "Uploaded".to_string(),
+            MediaResponse::UploadRejected(_) => "UploadRejected".to_string(),
+            MediaResponse::Replicas(_) => "Replicas".to_string(),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChatResponse {
-    ClientList(HashSet<NodeId>),
-    MessageFrom { from: NodeId, message: Vec<u8> },
-    MessageSent,
+    ClientList(HashMap<NodeId, Presence>),
+    MessageFrom {
+        from: NodeId,
+        message: Vec<u8>,
+        #[serde(default = "default_room")]
+        room: String,
+        #[serde(default)]
+        id: u64,
+        #[serde(default)]
+        attachment: Option<Attachment>,
+    },
+    MessageSent(u64), // id the server gave to the message
     ClientNotRegistered,
     ClientRegistered(NodeId),
+    ClientUnregistered(NodeId),
+    History(Vec<ChatMessage>),
+    MessageEdited(ChatMessage),
+    MessageDeleted(u64),
+    EditRejected(u64), // no such message, or the client isn't its sender
+    RoomList(Vec<RoomInfo>),
+    RoomJoined(String),
+    RoomLeft(String),
+    RoomNotFound(String),
+    NotInRoom(String),
+    AttachmentUploaded { upload_id: u64, id: u64 },
+    AttachmentRejected { upload_id: u64, reason: String },
+    Attachment(u64, AttachmentData),
+    AttachmentNotFound(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+    Online,
+    Offline, // registered, but unreachable or silent for too long
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: HashSet<NodeId>,
 }
 
 #[derive(Clone, Debug)]
@@ -243,6 +757,9 @@ impl DroneSend for ChatResponse {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Response(ResponseMessage::Chat(self))
+    }
 }
 
 impl Response for ChatResponse {
@@ -250,16 +767,46 @@ impl Response for ChatResponse {
         match self {
             ChatResponse::ClientList(_) => "ClientList".to_string(),
             ChatResponse::MessageFrom { .. } => "MessageFrom".to_string(),
-            ChatResponse::MessageSent => "MessageSent".to_string(),
+            ChatResponse::MessageSent(_) => "MessageSent".to_string(),
             ChatResponse::ClientNotRegistered => "ClientNotRegistered".to_string(),
             ChatResponse::ClientRegistered(_) => "ClientRegistered".to_string(),
+            ChatResponse::ClientUnregistered(_) => "ClientUnregistered".to_string(),
+            ChatResponse::History(_) => "History".to_string(),
+            ChatResponse::MessageEdited(_) => "MessageEdited".to_string(),
+            ChatResponse::MessageDeleted(_) => "MessageDeleted".to_string(),
+            ChatResponse::EditRejected(_) => "EditRejected".to_string(),
+            ChatResponse::RoomList(_) => "RoomList".to_string(),
+            ChatResponse::RoomJoined(_) => "RoomJoined".to_string(),
+            ChatResponse::RoomLeft(_) => "RoomLeft".to_string(),
+            ChatResponse::RoomNotFound(_) => "RoomNotFound".to_string(),
+            ChatResponse::NotInRoom(_) => "NotInRoom".to_string(),
+            ChatResponse::AttachmentUploaded { .. } => "AttachmentUploaded".to_string(),
+            ChatResponse::AttachmentRejected { .. } => "AttachmentRejected".to_string(),
+            ChatResponse::Attachment(_, _) => "Attachment".to_string(),
+            ChatResponse::AttachmentNotFound(_) => "AttachmentNotFound".to_string(),
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerTypeRequest {
-    GetServerType,
+    GetServerType, // legacy handshake, answered with ServerTypeResponse::ServerType
+    Handshake {
+        protocol_version: u16,
+        #[serde(deserialize_with = "known_features")]
+        features: HashSet<ProtocolFeature>,
+        #[serde(default)]
+        public_key: Option<[u8; 32]>, // X25519, sent when asking for Encryption
+    },
+}
+
+// Features this build doesn't know are left out instead of failing the whole handshake
+pub(crate) fn known_features<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<HashSet<ProtocolFeature>, D::Error> {
+    let features = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(features
+        .into_iter()
+        .filter_map(|feature| serde_json::from_value(feature).ok())
+        .collect())
 }
 
 impl DroneSend for ServerTypeRequest {
@@ -269,17 +816,54 @@ impl DroneSend for ServerTypeRequest {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Request(RequestMessage::ServerType(self))
+    }
 }
 
 impl Request for ServerTypeRequest {
     fn request_type(&self) -> String {
-        "GetServerType".to_string()
+        match self {
+            ServerTypeRequest::GetServerType => "GetServerType".to_string(),
+            ServerTypeRequest::Handshake { .. } => "Handshake".to_string(),
+        }
+    }
+}
+
+// Sent back to the sender of a message that failed the integrity check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResendRequest {
+    pub session_id: u64,
+}
+
+impl DroneSend for ResendRequest {
+    fn stringify(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    fn from_string(raw: String) -> Result<Self, String> {
+        serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
+    }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Request(RequestMessage::Resend(self))
+    }
+}
+
+impl Request for ResendRequest {
+    fn request_type(&self) -> String {
+        "Resend".to_string()
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerTypeResponse {
     ServerType(ServerType),
+    Handshake {
+        capabilities: ServerCapabilities,
+        #[serde(default)]
+        public_key: Option<[u8; 32]>, // X25519, only if Encryption was agreed
+        #[serde(default)]
+        client_public_key: Option<[u8; 32]>, // echoed, tells the client which of its handshakes this answers
+    },
 }
 
 impl DroneSend for ServerTypeResponse {
@@ -289,10 +873,203 @@ impl DroneSend for ServerTypeResponse {
     fn from_string(raw: String) -> Result<Self, String> {
         serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
     }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Response(ResponseMessage::ServerType(self))
+    }
+}
+
+impl ServerTypeResponse {
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        match self {
+            ServerTypeResponse::ServerType(_) => None,
+            ServerTypeResponse::Handshake { public_key, .. } => *public_key,
+        }
+    }
+
+    pub fn client_public_key(&self) -> Option<[u8; 32]> {
+        match self {
+            ServerTypeResponse::ServerType(_) => None,
+            ServerTypeResponse::Handshake { client_public_key, .. } => *client_public_key,
+        }
+    }
+
+    pub fn into_capabilities(self) -> ServerCapabilities {
+        match self {
+            // Legacy server: oldest protocol and no optional features
+            ServerTypeResponse::ServerType(server_type) => ServerCapabilities {
+                server_type,
+                protocol_version: LEGACY_PROTOCOL_VERSION,
+                features: HashSet::new(),
+            },
+            ServerTypeResponse::Handshake { capabilities, .. } => capabilities,
+        }
+    }
 }
 
 impl Response for ServerTypeResponse {
     fn response_type(&self) -> String {
-        "GetServerType".to_string()
+        match self {
+            ServerTypeResponse::ServerType(_) => "GetServerType".to_string(),
+            ServerTypeResponse::Handshake { .. } => "Handshake".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    MalformedMessage(String),
+    UnsupportedVersion(u16),
+    UnexpectedMessage(String),
+}
+
+impl DroneSend for ErrorResponse {
+    fn stringify(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    fn from_string(raw: String) -> Result<Self, String> {
+        serde_json::from_str(raw.as_str()).map_err(|e| e.to_string())
+    }
+    fn into_protocol(self) -> ProtocolMessage {
+        ProtocolMessage::Response(ResponseMessage::Error(self))
+    }
+}
+
+impl Response for ErrorResponse {
+    fn response_type(&self) -> String {
+        match self {
+            ErrorResponse::MalformedMessage(_) => "MalformedMessage".to_string(),
+            ErrorResponse::UnsupportedVersion(_) => "UnsupportedVersion".to_string(),
+            ErrorResponse::UnexpectedMessage(_) => "UnexpectedMessage".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(request: TextRequest) -> Envelope {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            source_id: 7,
+            session_id: 42,
+            payload: request.into_protocol(),
+        }
+    }
+
+    fn text_request(envelope: &Envelope) -> Option<&TextRequest> {
+        match &envelope.payload {
+            ProtocolMessage::Request(RequestMessage::Text(request)) => Some(request),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for compress in [false, true] {
+            let data = envelope(TextRequest::Text(3)).encode(compress);
+            assert!(Envelope::verify_checksum(&data));
+            let decoded = Envelope::decode(&data).unwrap();
+            assert_eq!((decoded.source_id, decoded.session_id), (7, 42));
+            assert!(matches!(text_request(&decoded), Some(TextRequest::Text(3))));
+        }
+    }
+
+    #[test]
+    fn large_messages_are_compressed() {
+        let text = "a".repeat(4 * COMPRESSION_THRESHOLD);
+        let data = envelope(TextRequest::Upload(text.clone())).encode(true);
+        assert_ne!(data[0] & FLAG_COMPRESSED, 0);
+        assert!(data.len() < text.len());
+        let decoded = Envelope::decode(&data).unwrap();
+        assert!(matches!(text_request(&decoded), Some(TextRequest::Upload(t)) if *t == text));
+    }
+
+    #[test]
+    fn zero_padding_of_the_last_fragment_is_ignored() {
+        let mut data = envelope(TextRequest::Text(3)).encode(false);
+        let checked = data.len();
+        data.resize(checked + 40, 0);
+        assert!(Envelope::decode(&data).is_ok());
+        // The checksum covers the message only, as it was before the padding
+        assert!(Envelope::verify_checksum(&data[..checked]));
+    }
+
+    #[test]
+    fn corrupted_frames_fail_the_checksum() {
+        let data = envelope(TextRequest::TextList).encode(false);
+        let mut corrupted = data.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0x01;
+        assert!(!Envelope::verify_checksum(&corrupted));
+        assert!(!Envelope::verify_checksum(&data[..data.len() - 1]));
+        assert!(!Envelope::verify_checksum(&data[..2]));
+        assert!(!Envelope::verify_checksum(&[]));
+    }
+
+    #[test]
+    fn only_legacy_frames_go_without_checksum() {
+        let mut data = envelope(TextRequest::TextList).encode(false);
+        data[0] &= !FLAG_CHECKSUM;
+        assert!(!Envelope::verify_checksum(&data));
+
+        // Bare JSON claiming a version that has the checksum
+        let current = serde_json::to_vec(&envelope(TextRequest::TextList)).unwrap();
+        assert!(!Envelope::verify_checksum(&current));
+
+        let mut legacy = envelope(TextRequest::TextList);
+        legacy.version = LEGACY_PROTOCOL_VERSION;
+        assert!(Envelope::verify_checksum(&legacy.encode(false)));
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let key: SessionKey = [1; 32];
+        for text in ["hello".to_string(), "a".repeat(4 * COMPRESSION_THRESHOLD)] {
+            let sealed = envelope(TextRequest::Upload(text.clone())).seal(&key, true);
+            let ProtocolMessage::Encrypted(encrypted) = &sealed.payload else {
+                panic!("payload was not encrypted");
+            };
+            // Only the large payload is worth compressing
+            assert_eq!(encrypted.compressed, text.len() > COMPRESSION_THRESHOLD);
+
+            // The sealed envelope still goes through the wire format
+            let received = Envelope::decode(&sealed.encode(true)).unwrap();
+            let opened = received.open(Some(&key)).unwrap();
+            assert!(matches!(text_request(&opened), Some(TextRequest::Upload(t)) if *t == text));
+        }
+    }
+
+    #[test]
+    fn open_rejects_the_wrong_key_and_header() {
+        let sealed = envelope(TextRequest::Text(3)).seal(&[1; 32], false);
+        assert!(sealed.clone().open(Some(&[2; 32])).is_err());
+        assert!(sealed.clone().open(None).is_err());
+
+        // The header is bound to the ciphertext
+        let replayed = Envelope { source_id: 8, ..sealed };
+        assert!(replayed.open(Some(&[1; 32])).is_err());
+    }
+
+    #[test]
+    fn handshakes_are_never_sealed() {
+        let handshake = Envelope {
+            payload: ServerTypeRequest::GetServerType.into_protocol(),
+            ..envelope(TextRequest::TextList)
+        };
+        let sealed = handshake.seal(&[1; 32], true);
+        assert!(sealed.payload.is_handshake());
+        assert!(sealed.open(None).is_ok());
+    }
+
+    #[test]
+    fn legacy_peers_only_get_the_handshake() {
+        let mut legacy = envelope(TextRequest::TextList);
+        legacy.version = LEGACY_PROTOCOL_VERSION;
+        let data = legacy.encode(true);
+        assert_eq!(data[0], b'{');
+        let error = Envelope::decode(&data).unwrap_err();
+        assert!(matches!(error.error, ErrorResponse::UnsupportedVersion(LEGACY_PROTOCOL_VERSION)));
+        assert_eq!(error.sender, Some((7, 42)));
     }
 }
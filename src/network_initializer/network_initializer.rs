@@ -2,12 +2,14 @@ use crate::assembler::assembler::Assembler;
 #[cfg(feature = "debug")]
 use crate::debug;
 
+use crate::client_server::chat_store::{ChatStore, JsonLinesChatStore};
 use crate::client_server::client::Client;
 use crate::client_server::communication_server::CommunicationServer;
 use crate::client_server::content_server::ContentServer;
 use crate::client_server::network_core::{
     ClientEvent, ClientServerCommand, ContentType, NetworkNode, ServerEvent, ServerType,
 };
+use crate::simulation_controller::fault_injection::FaultInjector;
 use crate::simulation_controller::simulation_controller::{
     simulation_controller_main, SimulationController,
 };
@@ -15,8 +17,10 @@ use crossbeam_channel::{unbounded, Receiver, Sender};
 use rand::seq::SliceRandom;
 use rand::{thread_rng};
 use rustaceans_wit_attitudes::RustaceansWitAttitudesDrone;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::{fs, thread};
 use wg_2024::config::Config;
 use wg_2024::controller::{DroneCommand, DroneEvent};
@@ -41,15 +45,60 @@ use {
 
 
 const NUM_CONTENT_SERVERS: usize = 4;
+const DEFAULT_REPLICATION_FACTOR: usize = 1;
+pub const TEXT_FILES_DIR: &str = "server_content/text_files";
+pub const MEDIA_FILES_DIR: &str = "server_content/media_files";
+pub const UPLOADS_DIR: &str = "uploads"; // files uploaded while running, one directory per server
+pub const DRONE_IMPLEMENTATIONS: usize = 10; // drones create_drone can pick from, with the all_drones feature
+const LAYOUTS_DIR: &str = "layouts"; // node positions saved from the topology view
+const SCENARIOS_DIR: &str = "fault_scenarios"; // fault scenarios loaded with the matching config
+
+// Settings of this simulation that are not part of the wg_2024 config
+#[derive(Deserialize)]
+struct ContentConfig {
+    #[serde(default = "default_replication_factor")]
+    replication_factor: usize, // how many content servers hold each file
+}
+
+// "src/config.toml" -> "config", so every config keeps its own runtime files
+fn config_stem(config_path: &str) -> String {
+    Path::new(config_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "config".to_string())
+}
+
+fn default_replication_factor() -> usize {
+    DEFAULT_REPLICATION_FACTOR
+}
 
-pub fn main(config_path: String){
+pub fn main(config_path: String, chat_history_dir: PathBuf, event_log_dir: PathBuf){
     // let current_path = env::current_dir().expect("Unable to get current directory");
     // println!("Current path: {:?}", current_path);
     let config = parse_config(&config_path);
+    let replication_factor = parse_replication_factor(&config_path);
 
     // check for errors in the toml
-    check_toml_validity(&config);
+    check_toml_validity(&config, replication_factor);
 
+    let sc = start_network(config, replication_factor, &chat_history_dir, &[]);
+
+    // Node positions of the topology view are saved out of the source tree, one file per config
+    let layout_path = PathBuf::from(LAYOUTS_DIR).join(config_stem(&config_path)).with_extension("json");
+    // A fault scenario with the name of the config is loaded on start up, when there is one
+    let scenario_path = PathBuf::from(SCENARIOS_DIR).join(config_stem(&config_path)).with_extension("toml");
+    simulation_controller_main(sc, event_log_dir, layout_path, scenario_path).expect("GUI panicked!");
+}
+
+// Spawns every node of a checked config and returns the controller of the running network.
+// With all_drones, drone_mix gives the create_drone number of each drone in turn, the first
+// ten drones get a different one and the others a random one if it's empty
+pub fn start_network(
+    config: Config,
+    replication_factor: usize,
+    chat_history_dir: &Path,
+    drone_mix: &[usize],
+) -> SimulationController {
     // hashmap with all packet_channels
     let mut packet_channels: HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)> = HashMap::new();
     for drone in config.drone.iter() {
@@ -61,6 +110,8 @@ pub fn main(config_path: String){
     for server in config.server.iter() {
         packet_channels.insert(server.id, unbounded());
     }
+    // every node sends to its neighbors through a proxy, where the SC can inject faults
+    let faults = FaultInjector::new();
 
     // INITIALIZE DRONES
     let (node_event_send_drone, node_event_recv_drone): (Sender<DroneEvent>, Receiver<DroneEvent>) =
@@ -87,11 +138,12 @@ pub fn main(config_path: String){
         let packet_send: HashMap<NodeId, Sender<Packet>> = drone
             .connected_node_ids
             .into_iter()
-            .map(|id| (id, packet_channels[&id].0.clone()))
+            .map(|id| (id, faults.proxy(drone.id, id, packet_channels[&id].0.clone())))
             .collect();
 
         #[cfg(not(feature = "all_drones"))]
         {
+            let _ = drone_mix; // there's only our drone to pick
             // spawn
             thread::spawn(move || {
                 let mut drone = RustaceansWitAttitudesDrone::new(
@@ -110,16 +162,16 @@ pub fn main(config_path: String){
         #[cfg(feature = "all_drones")]
         {
             // spawn
+            let drone_i = if !drone_mix.is_empty() {
+                drone_mix[i % drone_mix.len()]
+            } else if i < 10 {
+                i
+            } else {
+                thread_rng().gen_range(0..10)
+            };
             thread::spawn(move || {
-                if i < 10 {
-                    let mut drone = create_drone(drone.id, node_event_send_drone, controller_drone_recv, packet_recv, packet_send, drone.pdr, i);
-                    drone.run();
-                } else {
-                    let mut rng = thread_rng();
-                    let random_i = rng.gen_range(0..10);
-                    let mut drone = create_drone(drone.id, node_event_send_drone, controller_drone_recv, packet_recv, packet_send, drone.pdr, random_i);
-                    drone.run();
-                }
+                let mut drone = create_drone(drone.id, node_event_send_drone, controller_drone_recv, packet_recv, packet_send, drone.pdr, drone_i);
+                drone.run();
             });
         }
     }
@@ -151,7 +203,7 @@ pub fn main(config_path: String){
             .connected_drone_ids
             .clone()
             .into_iter()
-            .map(|id| (id, packet_channels[&id].0.clone()))
+            .map(|id| (id, faults.proxy(client.id, id, packet_channels[&id].0.clone())))
             .collect();
 
         // spawn assembler for client
@@ -206,26 +258,8 @@ pub fn main(config_path: String){
         );
     }
     // Load file IDs from directories
-    let text_files: Vec<u64> = match fs::read_dir("server_content/text_files") {
-        Ok(entries) => entries
-            .filter_map(|entry| {
-                let path = entry.ok()?.path();
-                let file_name = path.file_stem()?.to_str()?;
-                file_name.parse::<u64>().ok()
-            })
-            .collect(),
-        Err(_) => vec![],
-    };
-    let media_files: Vec<u64> = match fs::read_dir("server_content/media_files") {
-        Ok(entries) => entries
-            .filter_map(|entry| {
-                let path = entry.ok()?.path();
-                let file_name = path.file_stem()?.to_str()?;
-                file_name.parse::<u64>().ok()
-            })
-            .collect(),
-        Err(_) => vec![],
-    };
+    let text_files = read_file_ids(TEXT_FILES_DIR);
+    let media_files = read_file_ids(MEDIA_FILES_DIR);
     debug!(
         "Found {} text files and {} media files",
         text_files.len(),
@@ -245,18 +279,12 @@ pub fn main(config_path: String){
         let media_server_count = NUM_CONTENT_SERVERS - text_server_count;
 
         // Text content servers
-        for i in 0..text_server_count {
-            let start_idx = i * available_text_files.len() / text_server_count;
-            let end_idx = (i + 1) * available_text_files.len() / text_server_count;
-            let server_files = available_text_files[start_idx..end_idx].to_vec();
+        for server_files in split_files(&available_text_files, text_server_count, replication_factor) {
             types.push((ServerType::ContentServer(ContentType::Text), server_files));
         }
 
         // Media content servers
-        for i in 0..media_server_count {
-            let start_idx = i * available_media_files.len() / media_server_count;
-            let end_idx = (i + 1) * available_media_files.len() / media_server_count;
-            let server_files = available_media_files[start_idx..end_idx].to_vec();
+        for server_files in split_files(&available_media_files, media_server_count, replication_factor) {
             types.push((ServerType::ContentServer(ContentType::Media), server_files));
         }
 
@@ -268,6 +296,18 @@ pub fn main(config_path: String){
         types.shuffle(&mut thread_rng());
         types
     };
+    // Servers holding each file, so every content server can advertise its replicas
+    let mut file_holders: HashMap<(ContentType, u64), Vec<NodeId>> = HashMap::new();
+    for (server, (server_type, files)) in config.server.iter().zip(server_types_with_content.iter()) {
+        if let ServerType::ContentServer(content_type) = server_type {
+            for file_id in files {
+                file_holders
+                    .entry((content_type.clone(), *file_id))
+                    .or_default()
+                    .push(server.id);
+            }
+        }
+    }
     let (node_event_send_server, node_event_recv_server): (
         Sender<ServerEvent>,
         Receiver<ServerEvent>,
@@ -299,7 +339,7 @@ pub fn main(config_path: String){
             .connected_drone_ids
             .clone()
             .into_iter()
-            .map(|id| (id, packet_channels[&id].0.clone()))
+            .map(|id| (id, faults.proxy(server.id, id, packet_channels[&id].0.clone())))
             .collect();
 
         // spawn assembler for server
@@ -336,6 +376,17 @@ pub fn main(config_path: String){
 
                 let content_type_clone = content_type.clone();
                 let files_clone = files.clone();
+                let replicas: HashMap<u64, Vec<NodeId>> = files
+                    .iter()
+                    .map(|file_id| {
+                        let holders = file_holders[&(content_type.clone(), *file_id)]
+                            .iter()
+                            .filter(|&&holder| holder != server.id)
+                            .cloned()
+                            .collect();
+                        (*file_id, holders)
+                    })
+                    .collect();
 
                 thread::spawn(move || {
                     let mut server = ContentServer::new(
@@ -350,12 +401,18 @@ pub fn main(config_path: String){
                         assembler_recv_res,
                         content_type_clone,
                         files_clone,
+                        replicas,
                     );
                     server.run();
                 });
             }
             ServerType::CommunicationServer => {
+                let chat_history_dir = chat_history_dir.to_path_buf();
                 thread::spawn(move || {
+                    // Reload what the server stored in its previous runs
+                    let mut chat_store = JsonLinesChatStore::new(&chat_history_dir, server.id);
+                    let chat_state = chat_store.load();
+
                     let mut server = CommunicationServer::new(
                         server.id,
                         HashSet::from_iter(server.connected_drone_ids.iter().cloned()),
@@ -366,8 +423,8 @@ pub fn main(config_path: String){
                         HashSet::new(),
                         assembler_send,
                         assembler_recv_res,
-                        HashSet::new(),
-                        Vec::new(),
+                        chat_state,
+                        Box::new(chat_store),
                     );
                     server.run();
                 });
@@ -375,9 +432,9 @@ pub fn main(config_path: String){
         }
     }
 
-    // INITIALIZE SIMULATION CONTROLLER AND GUI
-    // THE SC WILL ALSO START THE FIRST FLOOD REQUEST
-    let sc = SimulationController::new(
+    // INITIALIZE SIMULATION CONTROLLER
+    // THE GUI OR THE BATCH RUNNER WILL START THE FIRST FLOOD REQUEST
+    SimulationController::new(
         controller_drones,
         controller_clients,
         controller_servers,
@@ -385,9 +442,8 @@ pub fn main(config_path: String){
         node_event_recv_client,
         node_event_recv_server,
         packet_channels,
-    );
-
-    simulation_controller_main(sc).expect("GUI panicked!");
+        faults,
+    )
 }
 
 #[cfg(feature = "all_drones")]
@@ -400,7 +456,7 @@ fn create_drone(
     pdr: f32,
     i: usize
 ) -> Box<dyn Drone> {
-    match i % 10 {
+    match i % DRONE_IMPLEMENTATIONS {
         0 => Box::new(RustaceansWitAttitudesDrone::new(
             id,
             node_event_send_drone,
@@ -486,12 +542,47 @@ fn create_drone(
 }
 
 
-fn parse_config(file: &str) -> Config {
+// Ids of the files the content servers share, from the names of the files in `dir`
+pub fn read_file_ids(dir: &str) -> Vec<u64> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let file_name = path.file_stem()?.to_str()?;
+                file_name.parse::<u64>().ok()
+            })
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+pub fn parse_config(file: &str) -> Config {
     let file_str = fs::read_to_string(file).unwrap();
     toml::from_str(&file_str).unwrap()
 }
 
-fn check_toml_validity(config: &Config) {
+pub fn parse_replication_factor(file: &str) -> usize {
+    let file_str = fs::read_to_string(file).unwrap();
+    let content_config: ContentConfig = toml::from_str(&file_str).unwrap();
+    content_config.replication_factor
+}
+
+// Gives each file to `replication_factor` different servers, one after the other
+fn split_files(files: &[u64], server_count: usize, replication_factor: usize) -> Vec<Vec<u64>> {
+    let mut server_files = vec![Vec::new(); server_count];
+    if server_count == 0 {
+        return server_files;
+    }
+    let copies = replication_factor.min(server_count);
+    for (i, file_id) in files.iter().enumerate() {
+        for copy in 0..copies {
+            server_files[(i + copy) % server_count].push(*file_id);
+        }
+    }
+    server_files
+}
+
+pub fn check_toml_validity(config: &Config, replication_factor: usize) {
     let mut all_ids = HashSet::new();
     let mut drone_ids = HashSet::new();
     let mut client_ids = HashSet::new();
@@ -658,4 +749,46 @@ fn check_toml_validity(config: &Config) {
         }
     }
     // </editor-fold>
+
+    // <editor-fold desc="Content replication">
+    if replication_factor == 0 {
+        panic!("replication_factor must be at least 1");
+    }
+    // </editor-fold>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers_holding(server_files: &[Vec<u64>], file_id: u64) -> Vec<usize> {
+        (0..server_files.len())
+            .filter(|&server| server_files[server].contains(&file_id))
+            .collect()
+    }
+
+    #[test]
+    fn split_files_without_replication() {
+        let server_files = split_files(&[1, 2, 3, 4, 5], 2, 1);
+        assert_eq!(server_files, vec![vec![1, 3, 5], vec![2, 4]]);
+    }
+
+    #[test]
+    fn split_files_spreads_replicas_on_distinct_servers() {
+        let files: Vec<u64> = (0..8).collect();
+        let server_files = split_files(&files, 4, 2);
+        for &file_id in &files {
+            assert_eq!(servers_holding(&server_files, file_id).len(), 2);
+        }
+        // Every server gets its share of the replicas
+        let sizes: Vec<usize> = server_files.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![4, 4, 4, 4]);
+    }
+
+    #[test]
+    fn split_files_caps_replicas_at_the_server_count() {
+        let server_files = split_files(&[1, 2], 2, 5);
+        assert_eq!(server_files, vec![vec![1, 2], vec![1, 2]]);
+        assert!(split_files(&[1, 2], 0, 2).is_empty());
+    }
 }
@@ -0,0 +1,327 @@
+#[cfg(feature = "debug")]
+use crate::debug;
+
+use crate::client_server::network_core::{ClientEvent, ContentType, ServerEvent, ServerType};
+use crate::message::message::MessageContent;
+use crate::network_initializer::network_initializer::{
+    check_toml_validity, parse_config, parse_replication_factor, read_file_ids, start_network,
+    DRONE_IMPLEMENTATIONS, TEXT_FILES_DIR,
+};
+use crate::simulation_controller::simulation_controller::SimulationController;
+use crate::simulation_controller::stats::NetworkStats;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use wg_2024::packet::PacketType;
+
+const DEFAULT_REPETITIONS: usize = 3;
+const DEFAULT_RUN_TIMEOUT_SECS: u64 = 60;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500); // events sent while the nodes stop are still drained
+
+// Experiments to run, from a TOML file:
+//   config = "src/config.toml"
+//   pdrs = [0.0, 0.1, 0.2, 0.3]
+//   repetitions = 5
+//   run_timeout_secs = 60
+//   drone_mixes = [[0], [0, 1, 2]]   # create_drone numbers, needs all_drones
+//   output = "batch_results.csv"
+#[derive(Deserialize)]
+struct BatchConfig {
+    config: String, // every drone of it gets the PDR of the run
+    pdrs: Vec<f32>,
+    #[serde(default = "default_repetitions")]
+    repetitions: usize,
+    #[serde(default = "default_run_timeout_secs")]
+    run_timeout_secs: u64, // a run stops here even if some texts never arrived
+    #[serde(default)]
+    drone_mixes: Vec<Vec<usize>>, // empty for the usual drones
+    #[serde(default = "default_output")]
+    output: PathBuf,
+}
+
+fn default_repetitions() -> usize {
+    DEFAULT_REPETITIONS
+}
+
+fn default_run_timeout_secs() -> u64 {
+    DEFAULT_RUN_TIMEOUT_SECS
+}
+
+fn default_output() -> PathBuf {
+    PathBuf::from("batch_results.csv")
+}
+
+// Totals of one run
+#[derive(Default)]
+struct RunResult {
+    downloads_expected: u64,
+    downloads_succeeded: u64,
+    total_latency_millis: u64, // of the succeeded downloads
+    fragments_sent: u64,       // by clients and servers, retransmissions included
+    retransmissions: u64,
+    fragments_dropped: u64, // by the drones
+}
+
+// The same for every run: flood, learn the server types, ask every text server for its list
+// and download every text with its images. A download succeeds when the client resolved it
+// with all of its images
+struct Workload {
+    text_servers: HashSet<NodeId>,
+    asked_lists: HashSet<(NodeId, NodeId)>,     // (client, text server)
+    received_lists: HashSet<(NodeId, NodeId)>,  // (client, text server)
+    texts: HashSet<u64>, // the shipped texts and any upload a list showed, every client downloads them all
+    requested: HashMap<(NodeId, u64), Instant>, // (client, text)
+    resolved: HashMap<(NodeId, u64), Duration>, // time from the request to the last image
+    incomplete: HashSet<(NodeId, u64)>,         // resolved after giving up some images
+}
+
+impl Workload {
+    fn new(sc: &SimulationController) -> Self {
+        Self {
+            text_servers: sc
+                .get_servers()
+                .iter()
+                .filter(|(_, (_, _, server_type))| *server_type == ServerType::ContentServer(ContentType::Text))
+                .map(|(id, _)| *id)
+                .collect(),
+            asked_lists: HashSet::new(),
+            received_lists: HashSet::new(),
+            texts: read_file_ids(TEXT_FILES_DIR).into_iter().collect(),
+            requested: HashMap::new(),
+            resolved: HashMap::new(),
+            incomplete: HashSet::new(),
+        }
+    }
+
+    // Uploads of earlier runs are listed too, so the total is only known once the lists are in
+    fn downloads_expected(&self, clients: u64) -> u64 {
+        clients * self.texts.len() as u64
+    }
+
+    fn all_lists_received(&self, clients: u64) -> bool {
+        self.received_lists.len() as u64 >= clients * self.text_servers.len() as u64
+    }
+
+    fn finished(&self) -> u64 {
+        self.resolved.len() as u64 + self.incomplete.iter().filter(|key| !self.resolved.contains_key(key)).count() as u64
+    }
+
+    fn client_event(&mut self, sc: &mut SimulationController, event: &ClientEvent) {
+        match event {
+            // Text lists are asked once the handshake tells the client it's a text server
+            ClientEvent::MessageReceived {
+                receiver,
+                content: MessageContent::CapabilitiesWithServer(server_id, _),
+            } => {
+                if self.text_servers.contains(server_id) && self.asked_lists.insert((*receiver, *server_id)) {
+                    sc.handle_text_list_request(*receiver, *server_id);
+                }
+            }
+            // Replicas are listed by several servers, each text is asked once
+            ClientEvent::MessageReceived {
+                receiver,
+                content: MessageContent::TextListWithServer(server_id, text_ids),
+            } => {
+                self.received_lists.insert((*receiver, *server_id));
+                self.texts.extend(text_ids);
+                for text_id in text_ids {
+                    if !self.requested.contains_key(&(*receiver, *text_id)) {
+                        self.requested.insert((*receiver, *text_id), Instant::now());
+                        sc.handle_text_request(*receiver, *server_id, *text_id);
+                    }
+                }
+            }
+            ClientEvent::TextResolved { client_id, text_id, missing, .. } => {
+                let key = (*client_id, *text_id);
+                match self.requested.get(&key) {
+                    Some(_) if !missing.is_empty() => {
+                        self.incomplete.insert(key);
+                    }
+                    Some(requested_at) => {
+                        self.resolved.entry(key).or_insert_with(|| requested_at.elapsed());
+                    }
+                    None => {}
+                }
+            }
+            ClientEvent::BrokenDroneDetected(drone_id) => {
+                sc.handle_broken_drone(*drone_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Sweeps the PDRs and drone mixes of the batch file and writes one CSV row per configuration
+pub fn main(batch_path: &str) -> Result<(), String> {
+    let batch_str = fs::read_to_string(batch_path).map_err(|e| format!("Can't read {}: {}", batch_path, e))?;
+    let batch: BatchConfig = toml::from_str(&batch_str).map_err(|e| format!("Invalid batch file {}: {}", batch_path, e))?;
+    if batch.pdrs.iter().any(|pdr| !(0.0..=1.0).contains(pdr)) {
+        return Err("Every PDR must be between 0 and 1".to_string());
+    }
+    if batch.repetitions == 0 {
+        return Err("repetitions must be at least 1".to_string());
+    }
+    if !batch.drone_mixes.is_empty() && !cfg!(feature = "all_drones") {
+        return Err("drone_mixes needs the all_drones feature".to_string());
+    }
+    if batch.drone_mixes.iter().any(|mix| mix.is_empty()) {
+        return Err("A drone mix can't be empty".to_string());
+    }
+    if batch.drone_mixes.iter().flatten().any(|&drone| drone >= DRONE_IMPLEMENTATIONS) {
+        return Err(format!("Drone mixes can only use drones 0 to {}", DRONE_IMPLEMENTATIONS - 1));
+    }
+
+    // Checked once, every run starts from the same file
+    let replication_factor = parse_replication_factor(&batch.config);
+    check_toml_validity(&parse_config(&batch.config), replication_factor);
+    let drone_mixes = if batch.drone_mixes.is_empty() {
+        vec![Vec::new()]
+    } else {
+        batch.drone_mixes.clone()
+    };
+
+    if let Some(dir) = batch.output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format!("Can't create {:?}: {}", dir, e))?;
+    }
+    let mut csv = File::create(&batch.output).map_err(|e| format!("Can't create {:?}: {}", batch.output, e))?;
+    writeln!(
+        csv,
+        "pdr,drone_mix,runs,downloads_expected,downloads_succeeded,success_rate,avg_latency_ms,avg_fragments_sent,avg_retransmissions,avg_fragments_dropped"
+    )
+    .map_err(|e| e.to_string())?;
+
+    for drone_mix in &drone_mixes {
+        for &pdr in &batch.pdrs {
+            let mut results = Vec::with_capacity(batch.repetitions);
+            for repetition in 0..batch.repetitions {
+                let mut config = parse_config(&batch.config);
+                for drone in config.drone.iter_mut() {
+                    drone.pdr = pdr;
+                }
+                let clients = config.client.len() as u64;
+                // Every run starts without the chat history of the previous ones
+                let chat_history_dir = std::env::temp_dir()
+                    .join(format!("batch_chat_history_{}_{}", std::process::id(), rand::random::<u64>()));
+                let mut sc = start_network(config, replication_factor, &chat_history_dir, drone_mix);
+                let result = run(&mut sc, clients, Duration::from_secs(batch.run_timeout_secs));
+                println!(
+                    "pdr {:.2}, drones {}, run {}/{}: {}/{} downloads",
+                    pdr,
+                    mix_label(drone_mix),
+                    repetition + 1,
+                    batch.repetitions,
+                    result.downloads_succeeded,
+                    result.downloads_expected
+                );
+                fs::remove_dir_all(&chat_history_dir).ok();
+                results.push(result);
+            }
+            writeln!(csv, "{}", csv_row(pdr, drone_mix, &results)).map_err(|e| e.to_string())?;
+            csv.flush().map_err(|e| e.to_string())?;
+        }
+    }
+
+    println!("Results saved to {:?}", Path::new(&batch.output));
+    Ok(())
+}
+
+// Runs the workload until every download succeeded or the timeout, then stops the network
+fn run(sc: &mut SimulationController, clients: u64, timeout: Duration) -> RunResult {
+    let mut stats = NetworkStats::new();
+    let mut workload = Workload::new(sc);
+    let mut fragments_sent = 0;
+
+    sc.start_flood_request_for_all();
+    let deadline = Instant::now() + timeout;
+    let mut stop_at = None;
+    loop {
+        while let Ok(event) = sc.get_drone_event_recv().try_recv() {
+            stats.record_drone_event(&event);
+        }
+        while let Ok(event) = sc.get_client_event_recv().try_recv() {
+            if let ClientEvent::PacketSent(packet) = &event {
+                if let PacketType::MsgFragment(_) = packet.pack_type {
+                    fragments_sent += 1;
+                }
+            }
+            if stop_at.is_none() {
+                workload.client_event(sc, &event);
+            }
+            stats.record_client_event(&event);
+        }
+        while let Ok(event) = sc.get_server_event_recv().try_recv() {
+            if let ServerEvent::PacketSent(packet) = &event {
+                if let PacketType::MsgFragment(_) = packet.pack_type {
+                    fragments_sent += 1;
+                }
+            }
+            stats.record_server_event(&event);
+        }
+
+        let now = Instant::now();
+        match stop_at {
+            // A text given up on won't get its images later, the run doesn't wait for it
+            None if now >= deadline
+                || (workload.all_lists_received(clients)
+                    && workload.finished() >= workload.downloads_expected(clients)) =>
+            {
+                debug!(
+                    "Run over, {} of {} downloads",
+                    workload.resolved.len(),
+                    workload.downloads_expected(clients)
+                );
+                sc.shutdown();
+                stop_at = Some(now + SHUTDOWN_GRACE);
+            }
+            Some(stop_at) if now >= stop_at => break,
+            _ => {}
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    RunResult {
+        downloads_expected: workload.downloads_expected(clients),
+        downloads_succeeded: workload.resolved.len() as u64,
+        total_latency_millis: workload.resolved.values().map(|latency| latency.as_millis() as u64).sum(),
+        fragments_sent,
+        retransmissions: stats.retransmissions,
+        fragments_dropped: stats.drones.values().map(|drone| drone.dropped).sum(),
+    }
+}
+
+fn mix_label(drone_mix: &[usize]) -> String {
+    if drone_mix.is_empty() {
+        return "default".to_string();
+    }
+    drone_mix.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn csv_row(pdr: f32, drone_mix: &[usize], results: &[RunResult]) -> String {
+    let runs = results.len() as f64;
+    let expected: u64 = results.iter().map(|result| result.downloads_expected).sum();
+    let succeeded: u64 = results.iter().map(|result| result.downloads_succeeded).sum();
+    let total_latency: u64 = results.iter().map(|result| result.total_latency_millis).sum();
+    let average = |value: fn(&RunResult) -> u64| results.iter().map(value).sum::<u64>() as f64 / runs;
+
+    let success_rate = if expected > 0 { format!("{:.3}", succeeded as f64 / expected as f64) } else { String::new() };
+    let latency = if succeeded > 0 { format!("{:.1}", total_latency as f64 / succeeded as f64) } else { String::new() };
+    format!(
+        "{:.3},{},{},{},{},{},{},{:.1},{:.1},{:.1}",
+        pdr,
+        mix_label(drone_mix),
+        results.len(),
+        expected,
+        succeeded,
+        success_rate,
+        latency,
+        average(|result| result.fragments_sent),
+        average(|result| result.retransmissions),
+        average(|result| result.fragments_dropped),
+    )
+}
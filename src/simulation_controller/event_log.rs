@@ -0,0 +1,96 @@
+#[cfg(feature = "debug")]
+use crate::debug;
+
+use crate::simulation_controller::gui_structs::LogEntry;
+use chrono::Utc;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// Appends every log entry of a run as one JSON object per line
+pub struct EventLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl EventLog {
+    // A new file per run, named after the time the simulation started
+    pub fn create(dir: &Path) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+        let path = dir.join(format!("events_{}.jsonl", Utc::now().format("%Y%m%d_%H%M%S")));
+        let file = File::create(&path).map_err(|e| format!("failed to create {:?}: {}", path, e))?;
+        debug!("Writing the event log to {:?}", path);
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&mut self, entry: &LogEntry) {
+        let line = serde_json::to_string(entry).expect("log entries always serialize");
+        if let Err(_e) = writeln!(self.writer, "{}", line) {
+            debug!("Failed to write the event log {:?}: {}", self.path, _e);
+        }
+    }
+
+    // Called once per frame, so a crash loses at most the last frame
+    pub fn flush(&mut self) {
+        if let Err(_e) = self.writer.flush() {
+            debug!("Failed to flush the event log {:?}: {}", self.path, _e);
+        }
+    }
+}
+
+// Reads a file written by EventLog, for the replay viewer
+pub fn load(path: &Path) -> Result<Vec<LogEntry>, String> {
+    let file = File::open(path).map_err(|e| format!("Can't open {:?}: {}", path, e))?;
+    let mut entries = Vec::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Can't read {:?}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: LogEntry = serde_json::from_str(&line)
+            .map_err(|e| format!("Line {} of {:?} is not a log entry: {}", line_number + 1, path, e))?;
+        entries.push(entry);
+    }
+    entries.sort_by_key(|entry| entry.elapsed_millis);
+    Ok(entries)
+}
+
+// A log file loaded in the Logs page, shown up to `position_millis`
+pub struct Replay {
+    pub path: PathBuf,
+    pub entries: Vec<LogEntry>,
+    pub position_millis: u64,
+    pub playing: bool,
+}
+
+impl Replay {
+    // Opens with the whole run visible
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let entries = load(path)?;
+        let position_millis = entries.last().map_or(0, |entry| entry.elapsed_millis);
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+            position_millis,
+            playing: false,
+        })
+    }
+
+    pub fn duration_millis(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.elapsed_millis)
+    }
+
+    pub fn advance(&mut self, millis: u64) {
+        self.position_millis = (self.position_millis + millis).min(self.duration_millis());
+        if self.position_millis == self.duration_millis() {
+            self.playing = false;
+        }
+    }
+}
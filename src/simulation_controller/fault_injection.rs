@@ -0,0 +1,324 @@
+#[cfg(feature = "debug")]
+use crate::debug;
+
+use crossbeam_channel::{after, never, select, unbounded, Receiver, Sender};
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+use wg_2024::packet::Packet;
+
+// How long a reordered packet is held back, the next ones on the link overtake it
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+// Faults of a link, in both directions. All zero is a plain channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LinkFaults {
+    pub latency_ms: u64,
+    pub jitter_ms: u64, // random extra latency, up to this
+    pub reorder: f32,   // probability of a packet being held back
+    pub flap_up_ms: u64, // with flap_down_ms, the link goes down for flap_down_ms after every flap_up_ms
+    pub flap_down_ms: u64,
+}
+
+impl LinkFaults {
+    fn is_flapped_down(&self, since_start: Duration) -> bool {
+        if self.flap_down_ms == 0 {
+            return false;
+        }
+        let period = self.flap_up_ms + self.flap_down_ms;
+        since_start.as_millis() as u64 % period >= self.flap_up_ms
+    }
+
+    fn delay(&self) -> (Duration, bool) {
+        let mut rng = rand::thread_rng();
+        let jitter = if self.jitter_ms > 0 { rng.gen_range(0..=self.jitter_ms) } else { 0 };
+        let delay = Duration::from_millis(self.latency_ms + jitter);
+        if self.reorder > 0.0 && rng.gen::<f32>() < self.reorder {
+            (delay + REORDER_DELAY, true)
+        } else {
+            (delay, false)
+        }
+    }
+}
+
+// Nodes cut off from the rest of the network until heals_at
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub nodes: HashSet<NodeId>,
+    pub starts_at: Instant,
+    pub heals_at: Instant,
+}
+
+impl Partition {
+    pub fn is_active(&self, now: Instant) -> bool {
+        self.starts_at <= now && now < self.heals_at
+    }
+
+    fn cuts(&self, node1_id: NodeId, node2_id: NodeId, now: Instant) -> bool {
+        self.is_active(now) && self.nodes.contains(&node1_id) != self.nodes.contains(&node2_id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxyCounters {
+    pub forwarded: u64,
+    pub delayed: u64,
+    pub reordered: u64,
+    pub dropped: u64, // sent while the link was down
+}
+
+// Steps of a scenario file, times are counted from when it's loaded:
+//   [[link]]                      [[partition]]
+//   nodes = [1, 11]               nodes = [3, 4]
+//   at_ms = 0                     at_ms = 5000
+//   latency_ms = 80               duration_ms = 3000
+//   jitter_ms = 20
+//   reorder = 0.1
+//   flap_up_ms = 2000
+//   flap_down_ms = 500
+#[derive(Deserialize)]
+struct Scenario {
+    #[serde(default)]
+    link: Vec<LinkStep>,
+    #[serde(default)]
+    partition: Vec<PartitionStep>,
+}
+
+#[derive(Deserialize)]
+struct LinkStep {
+    nodes: (NodeId, NodeId),
+    #[serde(default)]
+    at_ms: u64,
+    #[serde(flatten)]
+    faults: LinkFaults,
+}
+
+#[derive(Deserialize)]
+struct PartitionStep {
+    nodes: HashSet<NodeId>,
+    #[serde(default)]
+    at_ms: u64,
+    duration_ms: u64,
+}
+
+struct FaultState {
+    started: Instant, // flapping links go down together, counted from here
+    links: HashMap<(NodeId, NodeId), LinkFaults>,
+    scheduled: Vec<(Instant, (NodeId, NodeId), LinkFaults)>, // link steps of the scenario not reached yet
+    partitions: Vec<Partition>,
+    counters: HashMap<(NodeId, NodeId), ProxyCounters>,
+}
+
+impl FaultState {
+    fn apply_due(&mut self, now: Instant) {
+        let (due, scheduled): (Vec<_>, Vec<_>) = self.scheduled.drain(..).partition(|(at, _, _)| *at <= now);
+        self.scheduled = scheduled;
+        for (_, link, faults) in due {
+            self.links.insert(link, faults);
+        }
+        self.partitions.retain(|partition| now < partition.heals_at);
+    }
+
+    fn is_down(&self, node1_id: NodeId, node2_id: NodeId, now: Instant) -> bool {
+        let flapped_down = self
+            .links
+            .get(&link_key(node1_id, node2_id))
+            .is_some_and(|faults| faults.is_flapped_down(now - self.started));
+        flapped_down || self.partitions.iter().any(|partition| partition.cuts(node1_id, node2_id, now))
+    }
+}
+
+// Links are stored with the smaller id first, the faults are the same both ways
+fn link_key(node1_id: NodeId, node2_id: NodeId) -> (NodeId, NodeId) {
+    (node1_id.min(node2_id), node1_id.max(node2_id))
+}
+
+// Every packet a node sends to a neighbor goes through a proxy thread of that link, so the
+// faults work whatever drone implementation is on either side
+#[derive(Clone)]
+pub struct FaultInjector {
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        FaultInjector {
+            state: Arc::new(Mutex::new(FaultState {
+                started: Instant::now(),
+                links: HashMap::new(),
+                scheduled: Vec::new(),
+                partitions: Vec::new(),
+                counters: HashMap::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        // A proxy panicking while holding the lock doesn't make the faults unusable
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Sender to give `from` in place of `target`, the packets reach `to` through the proxy
+    pub fn proxy(&self, from: NodeId, to: NodeId, target: Sender<Packet>) -> Sender<Packet> {
+        let (proxy_send, proxy_recv) = unbounded();
+        let injector = self.clone();
+        thread::spawn(move || injector.run_proxy(from, to, proxy_recv, target));
+        proxy_send
+    }
+
+    fn run_proxy(&self, from: NodeId, to: NodeId, packet_recv: Receiver<Packet>, target: Sender<Packet>) {
+        let mut held: VecDeque<(Instant, Packet)> = VecDeque::new(); // by release time
+        let closed = never();
+        let mut open = true;
+
+        // Runs until `from` drops the sender and the held packets are delivered
+        while open || !held.is_empty() {
+            let input = if open { &packet_recv } else { &closed };
+            let release = match held.front() {
+                Some((release_at, _)) => after(release_at.saturating_duration_since(Instant::now())),
+                None => never(),
+            };
+            select! {
+                recv(input) -> packet => match packet {
+                    Ok(packet) => {
+                        if let Some(delay) = self.route(from, to) {
+                            let release_at = Instant::now() + delay;
+                            let index = held.partition_point(|(at, _)| *at <= release_at);
+                            held.insert(index, (release_at, packet));
+                        }
+                    }
+                    Err(_) => open = false,
+                },
+                recv(release) -> _ => {}
+            }
+
+            let now = Instant::now();
+            while held.front().is_some_and(|(release_at, _)| *release_at <= now) {
+                if let Some((_, packet)) = held.pop_front() {
+                    target.send(packet).ok();
+                }
+            }
+        }
+        debug!("Proxy {} -> {} closed", from, to);
+    }
+
+    // How long a packet waits on the link, None if the link is down and it's lost
+    fn route(&self, from: NodeId, to: NodeId) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = self.state();
+        state.apply_due(now);
+        let link = link_key(from, to);
+        let faults = state.links.get(&link).copied().unwrap_or_default();
+        let is_down = state.is_down(from, to, now);
+
+        let counters = state.counters.entry(link).or_default();
+        if is_down {
+            counters.dropped += 1;
+            return None;
+        }
+        let (delay, reordered) = faults.delay();
+        counters.forwarded += 1;
+        if !delay.is_zero() {
+            counters.delayed += 1;
+        }
+        if reordered {
+            counters.reordered += 1;
+        }
+        Some(delay)
+    }
+
+    pub fn link_faults(&self, node1_id: NodeId, node2_id: NodeId) -> LinkFaults {
+        let mut state = self.state();
+        state.apply_due(Instant::now());
+        state.links.get(&link_key(node1_id, node2_id)).copied().unwrap_or_default()
+    }
+
+    pub fn set_link_faults(&self, node1_id: NodeId, node2_id: NodeId, faults: LinkFaults) {
+        let mut state = self.state();
+        if faults == LinkFaults::default() {
+            state.links.remove(&link_key(node1_id, node2_id));
+        } else {
+            state.links.insert(link_key(node1_id, node2_id), faults);
+        }
+    }
+
+    pub fn counters(&self, node1_id: NodeId, node2_id: NodeId) -> ProxyCounters {
+        self.state().counters.get(&link_key(node1_id, node2_id)).copied().unwrap_or_default()
+    }
+
+    // Whether packets between the two nodes are currently lost
+    pub fn is_link_down(&self, node1_id: NodeId, node2_id: NodeId) -> bool {
+        let now = Instant::now();
+        let mut state = self.state();
+        state.apply_due(now);
+        state.is_down(node1_id, node2_id, now)
+    }
+
+    // Cuts `nodes` off from the rest of the network, it heals by itself after `duration`
+    pub fn partition(&self, nodes: HashSet<NodeId>, duration: Duration) {
+        let now = Instant::now();
+        self.state().partitions.push(Partition {
+            nodes,
+            starts_at: now,
+            heals_at: now + duration,
+        });
+    }
+
+    // Active partitions and the ones a scenario starts later
+    pub fn partitions(&self) -> Vec<Partition> {
+        let mut state = self.state();
+        state.apply_due(Instant::now());
+        state.partitions.clone()
+    }
+
+    pub fn heal_partitions(&self) {
+        self.state().partitions.clear();
+    }
+
+    // Removes every fault, also the scenario steps not reached yet
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.links.clear();
+        state.scheduled.clear();
+        state.partitions.clear();
+    }
+
+    // Replaces the current faults with the ones of a TOML scenario, returns how many steps it has
+    pub fn load_scenario(&self, path: &Path) -> Result<usize, String> {
+        let file_str = fs::read_to_string(path).map_err(|e| format!("Can't read {:?}: {}", path, e))?;
+        let scenario: Scenario = toml::from_str(&file_str).map_err(|e| format!("Invalid scenario {:?}: {}", path, e))?;
+        for step in &scenario.link {
+            if step.faults.reorder < 0.0 || step.faults.reorder > 1.0 {
+                return Err(format!("Link {:?}: reorder must be between 0 and 1", step.nodes));
+            }
+            if step.faults.flap_down_ms > 0 && step.faults.flap_up_ms == 0 {
+                return Err(format!("Link {:?}: flap_up_ms must be set with flap_down_ms", step.nodes));
+            }
+        }
+
+        self.clear();
+        let now = Instant::now();
+        let mut state = self.state();
+        for step in &scenario.link {
+            let link = link_key(step.nodes.0, step.nodes.1);
+            state.scheduled.push((now + Duration::from_millis(step.at_ms), link, step.faults));
+        }
+        for step in &scenario.partition {
+            let starts_at = now + Duration::from_millis(step.at_ms);
+            state.partitions.push(Partition {
+                nodes: step.nodes.clone(),
+                starts_at,
+                heals_at: starts_at + Duration::from_millis(step.duration_ms),
+            });
+        }
+        state.apply_due(now);
+        Ok(scenario.link.len() + scenario.partition.len())
+    }
+}
@@ -1,25 +1,66 @@
 use wg_2024::controller::{DroneCommand, DroneEvent};
 use wg_2024::network::NodeId;
 
+use crate::simulation_controller::event_log::{EventLog, Replay};
 use crate::simulation_controller::gui_structs::*;
 use crate::simulation_controller::logs_handler;
+use crate::simulation_controller::packet_trace::{self, TraceKey};
 use crate::simulation_controller::popup_handler;
-use crate::simulation_controller::simulation_controller::SimulationController;
+use crate::simulation_controller::stats::{NetworkStats, Sample, MAX_SAMPLES, SAMPLE_INTERVAL};
+use crate::simulation_controller::simulation_controller::{SimulationController, TopologyError};
 
 use crate::client_server::network_core::{
-    ChatMessage, ClientEvent, ClientServerCommand, ServerEvent, ServerType,
+    default_room, ChatMessage, ClientEvent, ClientServerCommand, NodeState, ServerCapabilities,
+    ServerEvent, ServerType,
+};
+use crate::message::message::{
+    ChatResponse, MediaResponseForMessageContent, MessageContent, Presence, RoomInfo, TextResponse,
 };
-use crate::message::message::{ChatResponse, MessageContent};
 use crossbeam_channel::Sender;
 use eframe::egui;
 
-use std::collections::{HashMap, HashSet};
-use wg_2024::packet::PacketType;
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use wg_2024::packet::{Packet, PacketType};
+
+const MAX_SNOOPED_FRAGMENTS: usize = 100;
+// Packet animation on the topology graph, times at normal speed
+const PACKET_TRAVEL_SECS: f32 = 0.6;
+const DROP_FLASH_SECS: f32 = 0.8;
+const THROUGHPUT_WINDOW_SECS: f32 = 2.0; // edge thickness follows the packets of about the last window
+const MAX_PACKET_DOTS: usize = 500;
+// Force-directed layout of the topology graph
+const LAYOUT_CENTER: (f32, f32) = (400.0, 300.0);
+const LAYOUT_SIZE: (f32, f32) = (700.0, 500.0);
+const LAYOUT_ITERATIONS: usize = 300;
+const LAYOUT_ATTEMPTS: usize = 5; // random starts, the one with the fewest crossing edges is kept
+const DEFAULT_PARTITION_SECS: u64 = 10;
+const MIN_ZOOM: f32 = 0.2;
+const MAX_ZOOM: f32 = 4.0;
 
 pub struct MyApp {
     pub(crate) simulation_controller: SimulationController,
     current_screen: Screen,             //Network diagram or Logs Page screen.
     pub(crate) logs_vec: Vec<LogEntry>, //Vector of logs shown in the Logs page
+    pub(crate) started_at: Instant, // log entries are timed from here
+    pub(crate) event_log: Result<EventLog, String>, // JSON-lines copy of the logs, or why it couldn't be created
+    replay: Option<Replay>, // log file shown in the Logs page instead of the live logs
+    replay_path_input: String,
+    replay_error: Option<String>,
+    trace_filter: String, // origin or session id to look for in the Packet Traces page
+    selected_trace: Option<TraceKey>,
+    stats: NetworkStats, // counters shown in the Stats page
+    stats_dir: PathBuf,  // where the Stats page exports its CSV files
+    stats_export_status: Option<Result<String, String>>,
+    layout_path: PathBuf, // node positions of the topology view, one file per config
+    layout_status: Option<Result<String, String>>,
+    scenario_path_input: String, // fault scenario to load, fault_scenarios/<config>.toml by default
+    scenario_status: Option<Result<String, String>>,
+    partition_nodes: HashSet<NodeId>, // nodes picked for the next partition
+    partition_secs: u64,
     show_confirmation_dialog: bool, //Confirmation dialog box when clicking "X" button of the window.
     allowed_to_close: bool,         //Confirm closing the program window.
     pub(crate) open_popups: HashMap<String, bool>, //Hashmap of popup windows for clients and drones.
@@ -39,12 +80,47 @@ pub struct MyApp {
     pub(crate) registered_servers: HashMap<NodeId, Vec<NodeId>>, // Maps client ID to list of servers they're registered with
     pub client_data_id_inputs: HashMap<NodeId, u64>, // Maps client ID to input for requesting data
     pub clients_downloaded_data: ClientsDownloadedData,
-    pub(crate) registered_clients: HashSet<NodeId>,
+    pub(crate) registered_clients: HashMap<NodeId, Presence>,
+    pub(crate) pending_deliveries: HashMap<NodeId, HashMap<NodeId, usize>>, // Maps server ID to the messages queued for each client
+    pub(crate) server_capabilities: HashMap<NodeId, HashMap<NodeId, ServerCapabilities>>, // Maps client ID to what each server answered in the handshake
+    pub(crate) snooped_fragments: HashMap<NodeId, VecDeque<String>>, // Fragments forwarded by the drones being snooped on
+    pub(crate) chat_editing: HashMap<NodeId, u64>, // Maps client ID to the id of the message being edited
+    pub(crate) chat_rooms: HashMap<NodeId, HashMap<NodeId, Vec<RoomInfo>>>, // Maps client ID to the rooms of each server
+    pub selected_room: HashMap<NodeId, String>, // Maps client ID to the room shown in the chat
+    pub new_room_inputs: HashMap<NodeId, String>,
+    pub attachment_path_inputs: HashMap<NodeId, String>,
+    pub(crate) attachment_errors: HashMap<NodeId, String>, // Maps client ID to why its last image couldn't be sent or shown
+    pub(crate) chat_attachments: HashMap<(NodeId, u64), egui::TextureHandle>, // Images uploaded to a server, by (server ID, attachment ID)
+    pub(crate) fetched_attachments: HashMap<NodeId, HashSet<(NodeId, u64)>>, // Maps client ID to the images it fetched
+    pub upload_text_inputs: HashMap<NodeId, String>,
+    pub upload_path_inputs: HashMap<NodeId, String>,
+    pub(crate) upload_status: HashMap<NodeId, Result<u64, String>>, // Maps client ID to the id given to its last upload, or why it failed
+    pub(crate) resolved_texts: HashMap<NodeId, HashMap<u64, Vec<u64>>>, // Maps client ID to its resolved texts, with the images that never arrived
+    pub(crate) drone_command_errors: HashMap<NodeId, String>, // Maps drone ID to why its last command was rejected
+    unsafe_edit: Option<(TopologyEdit, Vec<String>)>, // Change waiting to be forced or cancelled, with what it would break
+    pub(crate) node_states: HashMap<NodeId, NodeState>, // Last state each client or server answered with
 }
 
 pub struct NetworkTopology {
     pub nodes: Vec<Node>, //Vector of nodes (clients, servers and drones) in the network topology graph.
     pub connections: Vec<(usize, usize)>, //Connections (lines) between nodes.
+    pub highlighted_path: Vec<NodeId>, // Path of the traced fragment.
+    pub highlighted_stops: Vec<NodeId>, // Nodes that dropped or refused the traced fragment.
+    pub packet_dots: VecDeque<PacketDot>, // Packets travelling along the connections.
+    pub drop_flashes: HashMap<NodeId, f32>, // Seconds left of the red flash of drones that dropped a packet.
+    pub flood_senders: HashMap<(NodeId, u64, NodeId), Instant>, // (initiator, flood id, sender) of the flood requests already animated.
+    pub edge_traffic: HashMap<(NodeId, NodeId), f32>, // Decaying count of the packets sent along each connection.
+    pub animation_speed: f32, // 0 pauses the animation, 1 is real time.
+    pub zoom: f32,
+    pub pan: egui::Vec2, // Offset of the graph in the canvas, in screen points.
+    pub saved_positions: HashMap<NodeId, (f32, f32)>, // Positions loaded from the layout file.
+    pub link_drag_from: Option<NodeId>, // Node a new link is being dragged from.
+    pub selected_edge: Option<(NodeId, NodeId)>, // Connection the context menu is open for.
+    pub pdr_inputs: HashMap<NodeId, f32>, // PDR sliders of the drone context menus.
+    pub editor_error: Option<String>, // Why the last edit was rejected.
+    pub routing_overlay: Option<NodeId>, // Client or server whose believed paths are drawn.
+    pub believed_paths: Vec<Vec<NodeId>>, // Routes of that node, from the last state it sent.
+    pub state_query: Option<NodeId>, // Node whose state was asked from its menu, sent by the app.
 }
 
 fn load_image(path: &str) -> Result<egui::ColorImage, image::ImageError> {
@@ -60,12 +136,107 @@ fn load_image(path: &str) -> Result<egui::ColorImage, image::ImageError> {
     ))
 }
 
+// Colour of the dots of a packet type on the topology graph
+fn packet_color(packet_type: &str) -> egui::Color32 {
+    match packet_type {
+        "MsgFragment" => egui::Color32::LIGHT_BLUE,
+        "Ack" => egui::Color32::GREEN,
+        "Nack" => egui::Color32::RED,
+        "FloodRequest" => egui::Color32::YELLOW,
+        _ => egui::Color32::from_rgb(200, 120, 255),
+    }
+}
+
+// Colour of an event in the trace timeline
+fn sorted(ids: &HashSet<NodeId>) -> Vec<NodeId> {
+    let mut ids: Vec<NodeId> = ids.iter().copied().collect();
+    ids.sort();
+    ids
+}
+
+fn trace_event_color(event: &LogEntry) -> egui::Color32 {
+    match (event.packet_type.as_deref(), event.kind.as_str()) {
+        (_, "PacketDropped") | (Some("Nack"), _) => egui::Color32::RED,
+        (Some("Ack"), _) => egui::Color32::GREEN,
+        (_, "PacketReceived") => egui::Color32::LIGHT_BLUE,
+        _ => egui::Color32::YELLOW,
+    }
+}
+
+// Line chart of the last samples, every series scaled to the largest value
+fn draw_plot(ui: &mut egui::Ui, title: &str, samples: &VecDeque<Sample>, series: &[(&str, egui::Color32, fn(&Sample) -> u64)]) {
+    ui.label(egui::RichText::new(title).strong());
+    ui.horizontal(|ui| {
+        for (name, color, _) in series {
+            ui.colored_label(*color, format!("━ {}", name));
+        }
+    });
+
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), 120.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_stroke(rect, 0.0, egui::Stroke::new(1.0, egui::Color32::GRAY), egui::StrokeKind::Inside);
+
+    let max = series
+        .iter()
+        .flat_map(|(_, _, value)| samples.iter().map(value))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let step = rect.width() / (MAX_SAMPLES - 1) as f32;
+    for (_, color, value) in series {
+        let points: Vec<egui::Pos2> = samples
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let x = rect.right() - step * (samples.len() - 1 - i) as f32;
+                let y = rect.bottom() - rect.height() * value(sample) as f32 / max as f32;
+                egui::pos2(x, y)
+            })
+            .collect();
+        painter.add(egui::Shape::line(points, egui::Stroke::new(2.0, *color)));
+    }
+    painter.text(
+        rect.left_top() + egui::vec2(4.0, 2.0),
+        egui::Align2::LEFT_TOP,
+        max.to_string(),
+        egui::FontId::monospace(10.0),
+        egui::Color32::GRAY,
+    );
+}
+
 impl MyApp {
-    pub fn new(sc: SimulationController) -> MyApp {
+    pub fn new(sc: SimulationController, event_log_dir: &Path, layout_path: PathBuf, scenario_path: PathBuf) -> MyApp {
+        let mut topology = NetworkTopology::new();
+        let layout_status = layout_path.exists().then(|| {
+            topology
+                .load_positions(&layout_path)
+                .map(|count| format!("Loaded {} positions", count))
+        });
+        let scenario_status = scenario_path.exists().then(|| {
+            sc.get_fault_injector()
+                .load_scenario(&scenario_path)
+                .map(|steps| format!("Loaded {} fault steps", steps))
+        });
         Self {
             simulation_controller: sc,
             current_screen: Screen::NetworkScreen,
             logs_vec: Vec::new(),
+            started_at: Instant::now(),
+            event_log: EventLog::create(event_log_dir),
+            replay: None,
+            replay_path_input: String::new(),
+            replay_error: None,
+            trace_filter: String::new(),
+            selected_trace: None,
+            stats: NetworkStats::new(),
+            stats_dir: event_log_dir.to_path_buf(),
+            stats_export_status: None,
+            layout_path,
+            layout_status,
+            scenario_path_input: scenario_path.display().to_string(),
+            scenario_status,
+            partition_nodes: HashSet::new(),
+            partition_secs: DEFAULT_PARTITION_SECS,
             show_confirmation_dialog: false,
             allowed_to_close: false,
             open_popups: HashMap::new(),
@@ -76,7 +247,7 @@ impl MyApp {
             selected_server: HashMap::new(),
             client_popup_screens: HashMap::new(),
             client_list_popups: Default::default(),
-            topology: NetworkTopology::new(),
+            topology,
             client_texture: None,
             server_texture: None,
             drone_texture: None,
@@ -85,10 +256,519 @@ impl MyApp {
             registered_servers: Default::default(),
             client_data_id_inputs: HashMap::new(),
             clients_downloaded_data: ClientsDownloadedData::new(),
-            registered_clients: HashSet::new(),
+            registered_clients: HashMap::new(),
+            pending_deliveries: HashMap::new(),
+            server_capabilities: HashMap::new(),
+            snooped_fragments: HashMap::new(),
+            chat_editing: HashMap::new(),
+            chat_rooms: HashMap::new(),
+            selected_room: HashMap::new(),
+            new_room_inputs: HashMap::new(),
+            attachment_path_inputs: HashMap::new(),
+            attachment_errors: HashMap::new(),
+            chat_attachments: HashMap::new(),
+            fetched_attachments: HashMap::new(),
+            upload_text_inputs: HashMap::new(),
+            upload_path_inputs: HashMap::new(),
+            upload_status: HashMap::new(),
+            resolved_texts: HashMap::new(),
+            drone_command_errors: HashMap::new(),
+            unsafe_edit: None,
+            node_states: HashMap::new(),
+        }
+    }
+
+    // Keeps the answer of a state query, the overlay follows the node it's showing
+    fn store_node_state(&mut self, state: &NodeState) {
+        if self.topology.routing_overlay == Some(state.node_id) {
+            self.topology.believed_paths = state.routes.iter().map(|(_, path)| path.clone()).collect();
+        }
+        self.node_states.insert(state.node_id, state.clone());
+    }
+
+    // What the overlay node believes, next to the graph so stale routes can be compared
+    fn show_routing_state(&mut self, ctx: &egui::Context) {
+        let Some(node_id) = self.topology.routing_overlay else {
+            return;
+        };
+        let mut is_open = true;
+        let mut refresh = false;
+        egui::Window::new(format!("Routing state of {}", node_id))
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .resizable(true)
+            .default_width(260.0)
+            .open(&mut is_open)
+            .show(ctx, |ui| {
+                let Some(state) = self.node_states.get(&node_id) else {
+                    ui.label("Waiting for the node to answer...");
+                    return;
+                };
+                ui.label(format!("Connected drones: {:?}", sorted(&state.connected_drones)));
+                ui.label(format!("Assembler backlog: {}", state.assembler_backlog));
+                if !state.registered_servers.is_empty() {
+                    ui.label(format!("Registered to: {:?}", sorted(&state.registered_servers)));
+                }
+                if !state.registered_clients.is_empty() {
+                    ui.label(format!("Registered clients: {:?}", sorted(&state.registered_clients)));
+                }
+                if !state.server_types.is_empty() {
+                    ui.separator();
+                    ui.label("Server types:");
+                    let mut server_types: Vec<_> = state.server_types.iter().collect();
+                    server_types.sort_by_key(|(server_id, _)| **server_id);
+                    for (server_id, server_type) in server_types {
+                        match server_type {
+                            Some(server_type) => ui.label(format!("  {}: {:?}", server_id, server_type)),
+                            None => ui.colored_label(egui::Color32::YELLOW, format!("  {}: not answered", server_id)),
+                        };
+                    }
+                }
+                ui.separator();
+                ui.label(format!("Known routes ({}):", state.routes.len()));
+                egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                    for (destination, path) in &state.routes {
+                        let stale = path.windows(2).any(|hop| !self.topology.is_connected(hop[0], hop[1]));
+                        let text = format!("  {}: {:?}", destination, path);
+                        if stale {
+                            ui.colored_label(egui::Color32::RED, text).on_hover_text("Uses a link that no longer exists");
+                        } else {
+                            ui.label(text);
+                        }
+                    }
+                });
+                ui.separator();
+                if ui.button("Refresh").clicked() {
+                    refresh = true;
+                }
+            });
+        if refresh {
+            self.simulation_controller.handle_query_node_state(node_id);
+        }
+        if !is_open {
+            self.topology.routing_overlay = None;
+            self.topology.believed_paths.clear();
         }
     }
 
+    // Follows single fragments through the network, from the live logs or the replayed file
+    fn show_trace_screen(&mut self, ctx: &egui::Context) {
+        let entries: Vec<&LogEntry> = match &self.replay {
+            Some(replay) => replay
+                .entries
+                .iter()
+                .filter(|entry| entry.elapsed_millis <= replay.position_millis)
+                .collect(),
+            None => self.logs_vec.iter().collect(),
+        };
+        let traces = packet_trace::build_traces(&entries);
+
+        let filter = self.trace_filter.trim().to_string();
+        let rows: Vec<(TraceKey, String)> = traces
+            .iter()
+            .filter(|trace| {
+                let (origin, session_id, _) = trace.key;
+                filter.is_empty()
+                    || origin.to_string() == filter
+                    || session_id.to_string().contains(&filter)
+            })
+            .map(|trace| {
+                let (origin, session_id, fragment_index) = trace.key;
+                let summary = format!(
+                    "{} → {} | session {} | fragment {} | {}",
+                    origin,
+                    trace.route.last().copied().unwrap_or_default(),
+                    session_id,
+                    fragment_index,
+                    trace.status()
+                );
+                (trace.key, summary)
+            })
+            .collect();
+
+        let selected = self
+            .selected_trace
+            .and_then(|key| traces.iter().find(|trace| trace.key == key));
+        let details = selected.map(|trace| {
+            let start = trace.start_millis();
+            let events: Vec<(u64, String, egui::Color32)> = trace
+                .events
+                .iter()
+                .map(|event| {
+                    let node = event
+                        .node_id
+                        .map_or_else(|| "?".to_string(), |node_id| node_id.to_string());
+                    let mut description = format!(
+                        "{:?} {} {} {}",
+                        event.node_kind,
+                        node,
+                        event.kind,
+                        event.packet_type.clone().unwrap_or_default()
+                    );
+                    if let Some(nack_type) = &event.nack_type {
+                        description.push_str(&format!(" ({})", nack_type));
+                    }
+                    (event.elapsed_millis - start, description, trace_event_color(event))
+                })
+                .collect();
+            (
+                trace.route.clone(),
+                trace.path(),
+                trace.stopped_at(),
+                trace.status(),
+                trace.attempts(),
+                trace.nacks().len(),
+                trace.end_millis() - start,
+                events,
+            )
+        });
+        let trace_count = traces.len();
+        drop(traces);
+
+        egui::SidePanel::left("trace_list")
+            .min_width(380.0)
+            .show(ctx, |ui| {
+                ui.heading("Fragments");
+                ui.horizontal(|ui| {
+                    ui.label("Origin or session:");
+                    ui.text_edit_singleline(&mut self.trace_filter);
+                });
+                ui.small(format!("{} of {} fragments", rows.len(), trace_count));
+                ui.separator();
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (key, summary) in &rows {
+                        if ui
+                            .selectable_label(self.selected_trace == Some(*key), summary)
+                            .clicked()
+                        {
+                            self.selected_trace = Some(*key);
+                        }
+                    }
+                });
+            });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let Some((route, path, stopped_at, status, attempts, nacks, duration, events)) = details else {
+                ui.label("Select a fragment to see where it went.");
+                return;
+            };
+
+            ui.heading(status);
+            ui.label(format!("Source route: {:?}", route));
+            ui.label(format!("Reached: {:?}", path));
+            if !stopped_at.is_empty() {
+                ui.colored_label(egui::Color32::RED, format!("Stopped at: {:?}", stopped_at));
+            }
+            ui.label(format!("Sent {} time(s), {} Nack(s)", attempts, nacks));
+            if ui.button("Show on topology").clicked() {
+                self.topology.highlighted_path = path.clone();
+                self.topology.highlighted_stops = stopped_at.clone();
+                self.current_screen = Screen::NetworkScreen;
+            }
+            ui.separator();
+
+            // Timeline, one mark per event
+            let width = ui.available_width();
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(width, 40.0), egui::Sense::hover());
+            let painter = ui.painter_at(rect);
+            painter.line_segment(
+                [rect.left_center(), rect.right_center()],
+                egui::Stroke::new(1.0, egui::Color32::GRAY),
+            );
+            for (offset, _, color) in &events {
+                let x = rect.left() + 8.0 + (width - 16.0) * (*offset as f32 / duration.max(1) as f32);
+                painter.circle_filled(egui::pos2(x, rect.center().y), 6.0, *color);
+            }
+            ui.small(format!("0 ms … {} ms", duration));
+            ui.separator();
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("trace_events").striped(true).show(ui, |ui| {
+                    for (offset, description, color) in &events {
+                        ui.label(format!("+{} ms", offset));
+                        ui.colored_label(*color, description);
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+    }
+
+    // Counters of the live network, with the last two minutes plotted
+    // Every link of the network once, smaller id first
+    fn links(&self) -> Vec<(NodeId, NodeId)> {
+        let drones = self.simulation_controller.get_drones();
+        let neighbor_lists = drones
+            .iter()
+            .map(|(id, (_, neighbors, _))| (*id, neighbors))
+            .chain(self.simulation_controller.get_clients().iter().map(|(id, (_, neighbors))| (*id, neighbors)))
+            .chain(self.simulation_controller.get_servers().iter().map(|(id, (_, neighbors, _))| (*id, neighbors)));
+        let mut links: Vec<(NodeId, NodeId)> = neighbor_lists
+            .flat_map(|(node_id, neighbors)| neighbors.iter().map(move |&neighbor_id| (node_id.min(neighbor_id), node_id.max(neighbor_id))))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        links.sort();
+        links
+    }
+
+    // Latency, flapping, reordering and partitions added by the link proxies
+    fn show_faults_screen(&mut self, ctx: &egui::Context) {
+        ctx.request_repaint_after(SAMPLE_INTERVAL);
+        let faults = self.simulation_controller.get_fault_injector().clone();
+        let mut node_ids: Vec<NodeId> = self
+            .simulation_controller
+            .get_clients()
+            .keys()
+            .chain(self.simulation_controller.get_drones().keys())
+            .chain(self.simulation_controller.get_servers().keys())
+            .copied()
+            .collect();
+        node_ids.sort();
+
+        egui::SidePanel::left("fault_scenario")
+            .min_width(200.0)
+            .max_width(200.0)
+            .show(ctx, |ui| {
+                ui.heading("Scenario");
+                ui.text_edit_singleline(&mut self.scenario_path_input);
+                ui.horizontal(|ui| {
+                    if ui.button("Load").clicked() && !self.scenario_path_input.trim().is_empty() {
+                        self.scenario_status = Some(
+                            faults
+                                .load_scenario(&PathBuf::from(self.scenario_path_input.trim()))
+                                .map(|steps| format!("Loaded {} fault steps", steps)),
+                        );
+                    }
+                    if ui.button("Clear all faults").clicked() {
+                        faults.clear();
+                        self.scenario_status = None;
+                    }
+                });
+                match &self.scenario_status {
+                    Some(Ok(status)) => {
+                        ui.small(status);
+                    }
+                    Some(Err(e)) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                    None => {}
+                }
+                ui.separator();
+
+                ui.heading("Partition");
+                ui.small("The picked nodes can't reach the others until it heals");
+                egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                    for node_id in &node_ids {
+                        let mut picked = self.partition_nodes.contains(node_id);
+                        if ui.checkbox(&mut picked, format!("{:?} {}", self.node_kind(*node_id), node_id)).changed() {
+                            if picked {
+                                self.partition_nodes.insert(*node_id);
+                            } else {
+                                self.partition_nodes.remove(node_id);
+                            }
+                        }
+                    }
+                });
+                ui.add(egui::Slider::new(&mut self.partition_secs, 1..=120).text("seconds"));
+                if ui
+                    .add_enabled(!self.partition_nodes.is_empty(), egui::Button::new("Partition"))
+                    .clicked()
+                {
+                    let message = format!("Partition of {:?} for {}s", sorted(&self.partition_nodes), self.partition_secs);
+                    faults.partition(self.partition_nodes.clone(), Duration::from_secs(self.partition_secs));
+                    self.partition_nodes.clear();
+                    logs_handler::push_log(self, LogEntry::new(LogCategory::Command, "Partition", NodeKind::Drone, message));
+                }
+                ui.separator();
+
+                let now = Instant::now();
+                for partition in faults.partitions() {
+                    let status = if partition.is_active(now) {
+                        format!("heals in {}s", partition.heals_at.duration_since(now).as_secs())
+                    } else {
+                        format!("starts in {}s", partition.starts_at.duration_since(now).as_secs())
+                    };
+                    ui.label(format!("{:?} {}", sorted(&partition.nodes), status));
+                }
+                if !faults.partitions().is_empty() && ui.button("Heal all").clicked() {
+                    faults.heal_partitions();
+                }
+            });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Links");
+            ui.small("Changes apply to the packets sent from now on, in both directions");
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("link_faults").striped(true).show(ui, |ui| {
+                    ui.strong("Link");
+                    ui.strong("Latency ms");
+                    ui.strong("Jitter ms");
+                    ui.strong("Reorder");
+                    ui.strong("Flap up ms");
+                    ui.strong("Flap down ms");
+                    ui.strong("State");
+                    ui.strong("Forwarded");
+                    ui.strong("Delayed");
+                    ui.strong("Reordered");
+                    ui.strong("Lost");
+                    ui.end_row();
+                    for (node1_id, node2_id) in self.links() {
+                        let mut link_faults = faults.link_faults(node1_id, node2_id);
+                        let before = link_faults;
+                        ui.label(format!("{} – {}", node1_id, node2_id));
+                        ui.add(egui::DragValue::new(&mut link_faults.latency_ms).range(0..=5000));
+                        ui.add(egui::DragValue::new(&mut link_faults.jitter_ms).range(0..=5000));
+                        ui.add(egui::Slider::new(&mut link_faults.reorder, 0.0..=1.0));
+                        ui.add(egui::DragValue::new(&mut link_faults.flap_up_ms).range(0..=60000));
+                        ui.add(egui::DragValue::new(&mut link_faults.flap_down_ms).range(0..=60000));
+                        if link_faults != before {
+                            faults.set_link_faults(node1_id, node2_id, link_faults);
+                        }
+                        if faults.is_link_down(node1_id, node2_id) {
+                            ui.colored_label(egui::Color32::RED, "down");
+                        } else {
+                            ui.label("up");
+                        }
+                        let counters = faults.counters(node1_id, node2_id);
+                        ui.label(counters.forwarded.to_string());
+                        ui.label(counters.delayed.to_string());
+                        ui.label(counters.reordered.to_string());
+                        ui.label(counters.dropped.to_string());
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+    }
+
+    fn show_stats_screen(&mut self, ctx: &egui::Context) {
+        ctx.request_repaint_after(SAMPLE_INTERVAL);
+        let pdrs: HashMap<NodeId, f32> = self
+            .simulation_controller
+            .get_drones()
+            .iter()
+            .map(|(id, (_, _, pdr))| (*id, *pdr))
+            .collect();
+
+        egui::SidePanel::left("stats_export")
+            .min_width(180.0)
+            .max_width(180.0)
+            .show(ctx, |ui| {
+                ui.heading("Network");
+                ui.label(format!("Floods started: {}", self.stats.floods_started));
+                ui.label(format!("Flood packets: {}", self.stats.flood_packets));
+                ui.label(format!("Retransmitted fragments: {}", self.stats.retransmissions));
+                ui.separator();
+
+                if ui.button("Export CSV").clicked() {
+                    self.stats_export_status = Some(
+                        self.stats
+                            .export_csv(&self.stats_dir, &pdrs)
+                            .map(|(totals, series)| format!("Saved {:?} and {:?}", totals, series)),
+                    );
+                }
+                match &self.stats_export_status {
+                    Some(Ok(saved)) => {
+                        ui.small(saved);
+                    }
+                    Some(Err(e)) => {
+                        ui.colored_label(egui::Color32::RED, e);
+                    }
+                    None => {}
+                }
+            });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                draw_plot(ui, "Fragments per second", &self.stats.samples, &[
+                    ("forwarded", egui::Color32::LIGHT_BLUE, |sample| sample.forwarded),
+                    ("dropped", egui::Color32::RED, |sample| sample.dropped),
+                    ("retransmitted", egui::Color32::YELLOW, |sample| sample.retransmissions),
+                    ("flood packets", egui::Color32::LIGHT_GRAY, |sample| sample.flood_packets),
+                ]);
+                draw_plot(ui, "Requests answered per second", &self.stats.samples, &[
+                    ("succeeded", egui::Color32::GREEN, |sample| sample.requests_succeeded),
+                    ("failed", egui::Color32::RED, |sample| sample.requests_failed),
+                ]);
+                draw_plot(ui, "Bytes served per second", &self.stats.samples, &[
+                    ("bytes", egui::Color32::ORANGE, |sample| sample.bytes_served),
+                ]);
+                ui.separator();
+
+                ui.heading("Drones");
+                let mut drone_ids: Vec<&NodeId> = pdrs.keys().collect();
+                drone_ids.sort();
+                egui::Grid::new("drone_stats").striped(true).show(ui, |ui| {
+                    ui.strong("Drone");
+                    ui.strong("Forwarded");
+                    ui.strong("Dropped");
+                    ui.strong("Observed drop rate");
+                    ui.strong("Configured PDR");
+                    ui.end_row();
+                    for drone_id in drone_ids {
+                        let stats = self.stats.drones.get(drone_id);
+                        ui.label(drone_id.to_string());
+                        ui.label(stats.map_or(0, |stats| stats.forwarded).to_string());
+                        ui.label(stats.map_or(0, |stats| stats.dropped).to_string());
+                        ui.label(
+                            stats
+                                .and_then(|stats| stats.drop_rate())
+                                .map_or_else(|| "-".to_string(), |rate| format!("{:.2}", rate)),
+                        );
+                        ui.label(format!("{:.2}", pdrs[drone_id]));
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+
+                ui.heading("Client requests");
+                let mut requests: Vec<_> = self.stats.requests.iter().collect();
+                requests.sort_by(|a, b| a.0.cmp(b.0));
+                egui::Grid::new("request_stats").striped(true).show(ui, |ui| {
+                    ui.strong("Client");
+                    ui.strong("Request");
+                    ui.strong("Sent");
+                    ui.strong("Success rate");
+                    ui.strong("Average latency");
+                    ui.end_row();
+                    for ((client_id, request_type), stats) in requests {
+                        ui.label(client_id.to_string());
+                        ui.label(request_type);
+                        ui.label(stats.sent.to_string());
+                        ui.label(
+                            stats
+                                .success_rate()
+                                .map_or_else(|| "-".to_string(), |rate| format!("{:.0}%", rate * 100.0)),
+                        );
+                        ui.label(
+                            stats
+                                .average_latency_millis()
+                                .map_or_else(|| "-".to_string(), |latency| format!("{:.0} ms", latency)),
+                        );
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+
+                ui.heading("Servers");
+                let mut servers: Vec<_> = self.stats.servers.iter().collect();
+                servers.sort_by_key(|(server_id, _)| **server_id);
+                egui::Grid::new("server_stats").striped(true).show(ui, |ui| {
+                    ui.strong("Server");
+                    ui.strong("Bytes served");
+                    ui.strong("Fragments sent");
+                    ui.end_row();
+                    for (server_id, stats) in servers {
+                        ui.label(server_id.to_string());
+                        ui.label(stats.bytes_served.to_string());
+                        ui.label(stats.fragments_sent.to_string());
+                        ui.end_row();
+                    }
+                });
+            });
+        });
+    }
+
     fn show_popup(&mut self, ctx: &egui::Context, name: &str) {
         popup_handler::show_popup(self, ctx, name);
     }
@@ -96,6 +776,123 @@ impl MyApp {
     fn logs(&mut self, event: Event) {
         logs_handler::logs(self, event);
     }
+
+    fn node_kind(&self, node_id: NodeId) -> NodeKind {
+        if self.simulation_controller.get_clients().contains_key(&node_id) {
+            NodeKind::Client
+        } else if self.simulation_controller.get_servers().contains_key(&node_id) {
+            NodeKind::Server
+        } else {
+            NodeKind::Drone
+        }
+    }
+
+    // Applies a change from the drone popups or the topology editor. Unsafe ones wait for
+    // the user to force or cancel them
+    pub(crate) fn apply_edit(&mut self, edit: TopologyEdit, force: bool) -> Result<(), String> {
+        let result = match edit {
+            TopologyEdit::AddLink(node1_id, node2_id) => self.link_nodes(node1_id, node2_id, force),
+            TopologyEdit::RemoveLink(node1_id, node2_id) => self.unlink_nodes(node1_id, node2_id, force),
+            TopologyEdit::Crash(drone_id) => self.crash_drone(drone_id, force),
+            TopologyEdit::SetPdr(drone_id, pdr) => {
+                self.set_drone_pdr(drone_id, pdr);
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(TopologyError::Invalid(reason)) => Err(reason),
+            Err(TopologyError::Unsafe(problems)) => {
+                self.unsafe_edit = Some((edit, problems));
+                Err(format!("{}: waiting for confirmation", edit.describe()))
+            }
+        }
+    }
+
+    // Topology changes are logged once applied
+    fn link_nodes(&mut self, node1_id: NodeId, node2_id: NodeId, force: bool) -> Result<(), TopologyError> {
+        self.simulation_controller.handle_add_sender(node1_id, node2_id, force)?;
+        self.topology_needs_update = true;
+        let message = format!("Added sender {} to {}", node2_id, node1_id);
+        logs_handler::push_log(
+            self,
+            LogEntry::new(LogCategory::Command, "AddSender", self.node_kind(node1_id), message)
+                .node(node1_id)
+                .peer(node2_id),
+        );
+        Ok(())
+    }
+
+    fn unlink_nodes(&mut self, node1_id: NodeId, node2_id: NodeId, force: bool) -> Result<(), TopologyError> {
+        self.simulation_controller.handle_remove_sender(node1_id, node2_id, force)?;
+        self.topology_needs_update = true;
+        let message = format!("Removed sender {} from {}", node2_id, node1_id);
+        logs_handler::push_log(
+            self,
+            LogEntry::new(LogCategory::Command, "RemoveSender", self.node_kind(node1_id), message)
+                .node(node1_id)
+                .peer(node2_id),
+        );
+        Ok(())
+    }
+
+    fn crash_drone(&mut self, drone_id: NodeId, force: bool) -> Result<(), TopologyError> {
+        self.simulation_controller.handle_crash(drone_id, force)?;
+        self.topology_needs_update = true;
+        let message = format!("Crashing Drone {}", drone_id);
+        logs_handler::push_log(
+            self,
+            LogEntry::new(LogCategory::Command, "Crash", NodeKind::Drone, message).node(drone_id),
+        );
+
+        // Close its popup and clean up any related state
+        self.open_popups.remove(&format!("Drone {}", drone_id));
+        self.slider_temp_pdrs.remove(&drone_id);
+        self.drone_text_inputs.remove(&drone_id);
+        self.snooped_fragments.remove(&drone_id);
+        self.topology.pdr_inputs.remove(&drone_id);
+        self.drone_command_errors.remove(&drone_id);
+        Ok(())
+    }
+
+    fn set_drone_pdr(&mut self, drone_id: NodeId, pdr: f32) {
+        self.simulation_controller.handle_set_packet_drop_rate(drone_id, pdr);
+        self.slider_temp_pdrs.insert(drone_id, pdr);
+        self.topology.pdr_inputs.insert(drone_id, pdr);
+        let message = format!("Updated PDR of Drone {} to {:.2}%", drone_id, pdr * 100.0);
+        logs_handler::push_log(
+            self,
+            LogEntry::new(LogCategory::Command, "SetPacketDropRate", NodeKind::Drone, message).node(drone_id),
+        );
+    }
+
+    // Keeps what a drone forwarding the fragment is able to read
+    fn snoop_packet(&mut self, packet: &Packet) {
+        if let PacketType::MsgFragment(fragment) = &packet.pack_type {
+            // A length past the data is ignored, like the assembler does
+            let Some(data) = fragment.data.get(..fragment.length as usize) else {
+                return;
+            };
+            let routing_header = &packet.routing_header;
+            let forwarded_by = routing_header
+                .hop_index
+                .checked_sub(1)
+                .and_then(|i| routing_header.hops.get(i));
+
+            if let Some(snooped) = forwarded_by.and_then(|id| self.snooped_fragments.get_mut(id)) {
+                snooped.push_back(format!(
+                    "Session {} [{}/{}]: {}",
+                    packet.session_id,
+                    fragment.fragment_index + 1,
+                    fragment.total_n_fragments,
+                    String::from_utf8_lossy(data)
+                ));
+                if snooped.len() > MAX_SNOOPED_FRAGMENTS {
+                    snooped.pop_front();
+                }
+            }
+        }
+    }
 }
 
 impl eframe::App for MyApp {
@@ -103,10 +900,16 @@ impl eframe::App for MyApp {
         //Poll for new events and log them.
         while let Ok(event) = self.simulation_controller.get_drone_event_recv().try_recv() {
             match event {
-                DroneEvent::PacketSent(_) => {}
-                DroneEvent::PacketDropped(_) => {}
+                DroneEvent::PacketSent(ref packet) => {
+                    self.snoop_packet(packet);
+                    self.topology.packet_sent(packet);
+                }
+                DroneEvent::PacketDropped(ref packet) => {
+                    self.topology.packet_dropped(packet);
+                }
                 DroneEvent::ControllerShortcut(_) => {}
             }
+            self.stats.record_drone_event(&event);
             self.logs(Event::Drone(event));
         }
 
@@ -116,7 +919,9 @@ impl eframe::App for MyApp {
             .try_recv()
         {
             match &event {
-                ClientEvent::PacketSent(_) => {}
+                ClientEvent::PacketSent(packet) => {
+                    self.topology.packet_sent(packet);
+                }
                 ClientEvent::PacketReceived(p) => {
                     match &p.pack_type {
                         PacketType::MsgFragment(_) => {}
@@ -138,21 +943,57 @@ impl eframe::App for MyApp {
                         MessageContent::ServerTypeRequest(_) => {}
                         MessageContent::ServerTypeResponse(_) => {}
                         MessageContent::TextRequest(_) => {}
-                        MessageContent::TextResponse(_) => {}
+                        MessageContent::TextResponse(response_context) => {
+                            match response_context {
+                                TextResponse::Uploaded(file_id) => {
+                                    self.upload_status.insert(*receiver, Ok(*file_id));
+                                }
+                                TextResponse::UploadRejected(reason) => {
+                                    self.upload_status.insert(*receiver, Err(reason.clone()));
+                                }
+                                _ => {}
+                            }
+                        }
                         MessageContent::WholeChatVecResponse(_) => { /*not used by client*/ }
                         MessageContent::ChatRequest(_) => {}
+                        MessageContent::ResendRequest(_) => {}
                         MessageContent::ChatResponse(response_context) => {
                             match response_context {
                                 ChatResponse::ClientList(c) => {
-                                    self.registered_clients.clear();
-
-                                    for clients in c {
-                                        self.registered_clients.insert(clients.clone());
-                                    }
+                                    self.registered_clients = c.clone();
                                 }
                                 ChatResponse::MessageFrom { .. } => {}
-                                ChatResponse::MessageSent => {}
+                                ChatResponse::MessageSent(_) => {}
                                 ChatResponse::ClientNotRegistered => {}
+                                ChatResponse::History(_) => {}
+                                ChatResponse::MessageEdited(_) => {}
+                                ChatResponse::MessageDeleted(_) => {}
+                                ChatResponse::EditRejected(_) => {}
+                                ChatResponse::ClientUnregistered(server_id) => {
+                                    if let Some(servers) = self.registered_servers.get_mut(receiver) {
+                                        servers.retain(|s| s != server_id);
+                                    }
+                                }
+                                ChatResponse::RoomList(_) => {}
+                                ChatResponse::RoomJoined(room) => {
+                                    // Show the room just joined
+                                    self.selected_room.insert(*receiver, room.clone());
+                                }
+                                ChatResponse::RoomLeft(_) => {
+                                    self.selected_room.insert(*receiver, default_room());
+                                }
+                                ChatResponse::RoomNotFound(_) => {}
+                                ChatResponse::NotInRoom(_) => {}
+                                ChatResponse::AttachmentUploaded { .. } => {
+                                    self.attachment_errors.remove(receiver);
+                                }
+                                ChatResponse::AttachmentRejected { reason, .. } => {
+                                    self.attachment_errors.insert(*receiver, format!("Image rejected: {}", reason));
+                                }
+                                ChatResponse::Attachment(_, _) => {}
+                                ChatResponse::AttachmentNotFound(attachment_id) => {
+                                    self.attachment_errors.insert(*receiver, format!("Image {} not found on the server", attachment_id));
+                                }
                                 ChatResponse::ClientRegistered(server_id) => {
                                     // Insert the client in the registered_servers
                                     self.registered_servers
@@ -163,7 +1004,17 @@ impl eframe::App for MyApp {
                             }
                         }
                         MessageContent::MediaRequest(_) => {}
-                        MessageContent::MediaResponse(_) => {}
+                        MessageContent::MediaResponse(response_context) => {
+                            match response_context {
+                                MediaResponseForMessageContent::Uploaded(media_id) => {
+                                    self.upload_status.insert(*receiver, Ok(*media_id));
+                                }
+                                MediaResponseForMessageContent::UploadRejected(reason) => {
+                                    self.upload_status.insert(*receiver, Err(reason.clone()));
+                                }
+                                _ => {}
+                            }
+                        }
                         MessageContent::TextListWithServer(_server_id, content) => {
                             self.clients_downloaded_data.add_text_list(*receiver, *_server_id, content.iter().cloned().collect::<HashSet<u64>>());
                         }
@@ -176,12 +1027,73 @@ impl eframe::App for MyApp {
                         MessageContent::MediaIdWithServer(_server_id, content) => {
                             self.clients_downloaded_data.add_media(*receiver, *_server_id, *content);
                         }
+                        MessageContent::ReplicasWithServer(server_id, replicas) => {
+                            self.clients_downloaded_data.add_replicas(*receiver, *server_id, replicas.clone());
+                        }
+                        MessageContent::ChatHistoryWithServer(server_id, history) => {
+                            // Merged by id, so messages already shown are not duplicated
+                            let messages = self.chatrooms_messages.entry(*server_id).or_default();
+                            for chat_message in history {
+                                match messages.iter_mut().find(|m| m.id == chat_message.id) {
+                                    Some(known) => *known = chat_message.clone(),
+                                    None => messages.push(chat_message.clone()),
+                                }
+                            }
+                            messages.sort_by_key(|m| m.id);
+                        }
+                        MessageContent::RoomListWithServer(server_id, room_list) => {
+                            self.chat_rooms
+                                .entry(*receiver)
+                                .or_insert_with(HashMap::new)
+                                .insert(*server_id, room_list.clone());
+                        }
+                        MessageContent::AttachmentWithServer(server_id, attachment_id, data) => {
+                            // Decoded once, the chat view only shows the texture
+                            match image::load_from_memory(&data.0) {
+                                Ok(image) => {
+                                    let image_buffer = image.to_rgba8();
+                                    let (width, height) = image_buffer.dimensions();
+                                    let texture = ctx.load_texture(
+                                        format!("chat_attachment_{}_{}", server_id, attachment_id),
+                                        egui::ColorImage::from_rgba_unmultiplied(
+                                            [width as usize, height as usize],
+                                            &image_buffer.into_raw(),
+                                        ),
+                                        egui::TextureOptions::default(),
+                                    );
+                                    self.chat_attachments.insert((*server_id, *attachment_id), texture);
+                                    self.fetched_attachments
+                                        .entry(*receiver)
+                                        .or_default()
+                                        .insert((*server_id, *attachment_id));
+                                }
+                                Err(e) => {
+                                    self.attachment_errors.insert(*receiver, format!("Image {} can't be shown: {}", attachment_id, e));
+                                }
+                            }
+                        }
+                        MessageContent::CapabilitiesWithServer(server_id, capabilities) => {
+                            self.server_capabilities
+                                .entry(*receiver)
+                                .or_insert_with(HashMap::new)
+                                .insert(*server_id, capabilities.clone());
+                        }
+                        MessageContent::ErrorResponse(_) => {}
+                        MessageContent::Encrypted(_) => {}
                     }
                 }
                 ClientEvent::BrokenDroneDetected(node_id) => {
                     self.simulation_controller.handle_broken_drone(*node_id);
                 }
+                ClientEvent::IntegrityCheckFailed { .. } => {}
+                ClientEvent::TextResolved { client_id, text_id, missing, .. } => {
+                    self.resolved_texts.entry(*client_id).or_default().insert(*text_id, missing.clone());
+                }
+                ClientEvent::NodeState(state) => {
+                    self.store_node_state(state);
+                }
             }
+            self.stats.record_client_event(&event);
             self.logs(Event::Client(event));
         }
 
@@ -191,7 +1103,9 @@ impl eframe::App for MyApp {
             .try_recv()
         {
             match &event {
-                ServerEvent::PacketSent(_) => {}
+                ServerEvent::PacketSent(packet) => {
+                    self.topology.packet_sent(packet);
+                }
                 ServerEvent::PacketReceived(p) => {
                     match &p.pack_type {
                         PacketType::MsgFragment(_) => {}
@@ -222,17 +1136,37 @@ impl eframe::App for MyApp {
                         }
                         MessageContent::ChatRequest(_) => { /*not used by server*/ }
                         MessageContent::ChatResponse(_) => { /*not used by server*/ }
+                        MessageContent::ResendRequest(_) => {}
                         MessageContent::MediaRequest(_) => {}
                         MessageContent::MediaResponse(_) => {}
                         MessageContent::TextListWithServer(_, _) => {}
                         MessageContent::TextIdWithServer(_, _) => {}
                         MessageContent::MediaListWithServer(_, _) => {}
                         MessageContent::MediaIdWithServer(_, _) => {}
+                        MessageContent::ReplicasWithServer(_, _) => {}
+                        MessageContent::ChatHistoryWithServer(_, _) => {}
+                        MessageContent::RoomListWithServer(_, _) => {}
+                        MessageContent::AttachmentWithServer(_, _, _) => {}
+                        MessageContent::CapabilitiesWithServer(_, _) => {}
+                        MessageContent::ErrorResponse(_) => {}
+                        MessageContent::Encrypted(_) => {}
                     }
                 }
+                ServerEvent::IntegrityCheckFailed { .. } => {}
+                ServerEvent::PendingDeliveries { server_id, queued } => {
+                    self.pending_deliveries.insert(*server_id, queued.clone());
+                }
+                ServerEvent::NodeState(state) => {
+                    self.store_node_state(state);
+                }
             }
+            self.stats.record_server_event(&event);
             self.logs(Event::Server(event));
         }
+        if let Ok(event_log) = self.event_log.as_mut() {
+            event_log.flush();
+        }
+        self.stats.tick();
 
         //Load icon textures for nodes in graph.
         if self.client_texture.is_none() {
@@ -320,6 +1254,18 @@ impl eframe::App for MyApp {
                     if ui.button("Logs Page").clicked() {
                         self.current_screen = Screen::LogsScreen;
                     }
+
+                    if ui.button("Packet Traces").clicked() {
+                        self.current_screen = Screen::TraceScreen;
+                    }
+
+                    if ui.button("Stats").clicked() {
+                        self.current_screen = Screen::StatsScreen;
+                    }
+
+                    if ui.button("Faults").clicked() {
+                        self.current_screen = Screen::FaultsScreen;
+                    }
                 });
 
                 ui.add_space(2.0)
@@ -397,14 +1343,38 @@ impl eframe::App for MyApp {
                                 }
                             });
 
-                        egui::CentralPanel::default().show(ctx, |ui| {
-                            self.topology.draw(
-                                ui,
-                                self.client_texture.as_ref(),
-                                self.server_texture.as_ref(),
-                                self.drone_texture.as_ref(),
-                            );
-                        });
+                        let drone_pdrs: HashMap<NodeId, f32> = self
+                            .simulation_controller
+                            .get_drones()
+                            .iter()
+                            .map(|(id, (_, _, pdr))| (*id, *pdr))
+                            .collect();
+                        let faults = self.simulation_controller.get_fault_injector();
+                        let down_links: HashSet<(NodeId, NodeId)> = self
+                            .links()
+                            .into_iter()
+                            .filter(|&(node1_id, node2_id)| faults.is_link_down(node1_id, node2_id))
+                            .collect();
+                        let edit = egui::CentralPanel::default()
+                            .show(ctx, |ui| {
+                                self.topology.draw(
+                                    ui,
+                                    self.client_texture.as_ref(),
+                                    self.server_texture.as_ref(),
+                                    self.drone_texture.as_ref(),
+                                    &drone_pdrs,
+                                    &down_links,
+                                )
+                            })
+                            .inner;
+                        if let Some(edit) = edit {
+                            self.topology.editor_error = self.apply_edit(edit, false).err();
+                        }
+                        if let Some(node_id) = self.topology.state_query.take() {
+                            self.topology.routing_overlay = Some(node_id);
+                            self.topology.believed_paths.clear();
+                            self.simulation_controller.handle_query_node_state(node_id);
+                        }
                     }
 
                     Screen::LogsScreen => {
@@ -428,48 +1398,115 @@ impl eframe::App for MyApp {
 
                                 ui.label("Search:");
                                 ui.text_edit_singleline(&mut self.log_filters.search_text);
+
+                                ui.separator();
+                                match &self.event_log {
+                                    Ok(event_log) => ui.small(format!("Writing to {}", event_log.path().display())),
+                                    Err(e) => ui.colored_label(egui::Color32::RED, format!("No event log: {}", e)),
+                                };
+
+                                ui.separator();
+                                ui.heading("Replay");
+                                ui.label("Event log file:");
+                                ui.text_edit_singleline(&mut self.replay_path_input);
+                                if ui.button("Load").clicked() && !self.replay_path_input.trim().is_empty() {
+                                    match Replay::open(&PathBuf::from(self.replay_path_input.trim())) {
+                                        Ok(replay) => {
+                                            self.replay = Some(replay);
+                                            self.replay_error = None;
+                                        }
+                                        Err(e) => self.replay_error = Some(e),
+                                    }
+                                }
+                                if let Some(error) = &self.replay_error {
+                                    ui.colored_label(egui::Color32::RED, error);
+                                }
+
+                                let mut back_to_live = false;
+                                if let Some(replay) = self.replay.as_mut() {
+                                    ui.label(format!("{} entries", replay.entries.len()));
+                                    let duration = replay.duration_millis();
+                                    ui.add(
+                                        egui::Slider::new(&mut replay.position_millis, 0..=duration)
+                                            .text("ms"),
+                                    );
+                                    ui.horizontal(|ui| {
+                                        let label = if replay.playing { "Pause" } else { "Play" };
+                                        if ui.button(label).clicked() {
+                                            if !replay.playing && replay.position_millis == duration {
+                                                replay.position_millis = 0;
+                                            }
+                                            replay.playing = !replay.playing;
+                                        }
+                                        if ui.button("Live").clicked() {
+                                            back_to_live = true;
+                                        }
+                                    });
+                                    if replay.playing {
+                                        let frame_millis = (ctx.input(|i| i.stable_dt) * 1000.0) as u64;
+                                        replay.advance(frame_millis);
+                                        ctx.request_repaint();
+                                    }
+                                }
+                                if back_to_live {
+                                    self.replay = None;
+                                }
                             });
 
                         egui::CentralPanel::default().show(ctx, |ui| {
-                            egui::ScrollArea::vertical().show(ui, |ui| {
+                            if let Some(replay) = &self.replay {
+                                ui.label(
+                                    egui::RichText::new(format!(
+                                        "Replaying {} at {:.1}s",
+                                        replay.path.display(),
+                                        replay.position_millis as f64 / 1000.0
+                                    ))
+                                    .color(egui::Color32::YELLOW),
+                                );
+                                ui.separator();
+                            }
+                            let logs = match &self.replay {
+                                Some(replay) => logs_handler::filtered_logs(
+                                    &replay.entries,
+                                    &self.log_filters,
+                                    Some(replay.position_millis),
+                                ),
+                                None => logs_handler::filtered_logs(&self.logs_vec, &self.log_filters, None),
+                            };
+                            egui::ScrollArea::vertical().stick_to_bottom(self.replay.is_some()).show(ui, |ui| {
                                 ui.set_width(ui.available_width());
-                                for log in logs_handler::filtered_logs(self) {
-                                    let mut text_parts: Vec<egui::RichText> = Vec::new();
-
-                                    if log.message.starts_with("[PACKET]") {
-                                        text_parts.push(
-                                            egui::RichText::new("[PACKET]")
-                                                .color(egui::Color32::GREEN),
-                                        );
-                                        text_parts.push(
-                                            egui::RichText::new(&log.message[7..])
-                                                .color(egui::Color32::WHITE),
-                                        );
-                                    } else if log.message.starts_with("[MESSAGE]") {
-                                        text_parts.push(
-                                            egui::RichText::new("[MESSAGE]")
-                                                .color(egui::Color32::BLUE),
-                                        );
-                                        text_parts.push(
-                                            egui::RichText::new(&log.message[9..])
-                                                .color(egui::Color32::WHITE),
-                                        );
-                                    }
+                                for log in logs {
+                                    let (tag, color) = match log.category {
+                                        LogCategory::Packet => ("[PACKET]", egui::Color32::GREEN),
+                                        LogCategory::Message => ("[MESSAGE]", egui::Color32::BLUE),
+                                        LogCategory::Command => ("[COMMAND]", egui::Color32::YELLOW),
+                                    };
 
                                     let formatted_log =
-                                        egui::RichText::new(format!("[{}] ", log.timestamp))
+                                        egui::RichText::new(format!("[{}] ", log.timestamp()))
                                             .color(egui::Color32::WHITE);
 
                                     ui.horizontal(|ui| {
                                         ui.label(formatted_log);
-                                        for part in text_parts {
-                                            ui.label(part);
-                                        }
+                                        ui.label(egui::RichText::new(tag).color(color));
+                                        ui.label(egui::RichText::new(&log.message).color(egui::Color32::WHITE));
                                     });
                                 }
                             });
                         });
                     }
+
+                    Screen::TraceScreen => {
+                        self.show_trace_screen(ctx);
+                    }
+
+                    Screen::StatsScreen => {
+                        self.show_stats_screen(ctx);
+                    }
+
+                    Screen::FaultsScreen => {
+                        self.show_faults_screen(ctx);
+                    }
                 }
             });
 
@@ -485,6 +1522,46 @@ impl eframe::App for MyApp {
                 self.show_popup(ctx, &name);
             }
 
+            // Changes that would break the network are only applied if forced
+            if let Some((edit, problems)) = self.unsafe_edit.clone() {
+                let mut decision = None;
+                egui::Window::new("Unsafe change")
+                    .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                    .collapsible(false)
+                    .resizable(false)
+                    .show(ctx, |ui| {
+                        ui.label(format!("{} would break the network:", edit.describe()));
+                        for problem in &problems {
+                            ui.colored_label(egui::Color32::YELLOW, format!("• {}", problem));
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.add(egui::Button::new("Force").fill(egui::Color32::DARK_RED)).clicked() {
+                                decision = Some(true);
+                            }
+                            if ui.button("Cancel").clicked() {
+                                decision = Some(false);
+                            }
+                        });
+                    });
+                if let Some(force) = decision {
+                    self.unsafe_edit = None;
+                    self.topology.editor_error = None;
+                    let (TopologyEdit::AddLink(node_id, _)
+                    | TopologyEdit::RemoveLink(node_id, _)
+                    | TopologyEdit::Crash(node_id)
+                    | TopologyEdit::SetPdr(node_id, _)) = edit;
+                    self.drone_command_errors.remove(&node_id);
+                    if force {
+                        let message = format!("Forcing \"{}\" despite: {}", edit.describe(), problems.join("; "));
+                        let entry = LogEntry::new(LogCategory::Command, "Forced", self.node_kind(node_id), message).node(node_id);
+                        logs_handler::push_log(self, entry);
+                        if let Err(e) = self.apply_edit(edit, true) {
+                            self.topology.editor_error = Some(e);
+                        }
+                    }
+                }
+            }
+
             if self.current_screen == Screen::NetworkScreen {
                 if self.current_screen == Screen::NetworkScreen && self.topology_needs_update {
                     self.topology.update_topology(
@@ -521,7 +1598,74 @@ impl eframe::App for MyApp {
                         ui.horizontal(|ui| {
                             ui.colored_label(egui::Color32::GREEN, " ● Server");
                         });
+                        ui.separator();
+                        for (name, packet_type) in [
+                            ("Fragment", "MsgFragment"),
+                            ("Ack", "Ack"),
+                            ("Nack", "Nack"),
+                            ("Flood request", "FloodRequest"),
+                            ("Flood response", "FloodResponse"),
+                        ] {
+                            ui.colored_label(packet_color(packet_type), format!(" • {}", name));
+                        }
+                        ui.horizontal(|ui| {
+                            ui.selectable_value(&mut self.topology.animation_speed, 0.0, "⏸");
+                            ui.selectable_value(&mut self.topology.animation_speed, 0.1, "0.1x");
+                            ui.selectable_value(&mut self.topology.animation_speed, 0.5, "0.5x");
+                            ui.selectable_value(&mut self.topology.animation_speed, 1.0, "1x");
+                        });
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            if ui.button("Auto layout").clicked() {
+                                self.topology.auto_layout();
+                            }
+                            if ui.button("Reset view").clicked() {
+                                self.topology.zoom = 1.0;
+                                self.topology.pan = egui::Vec2::ZERO;
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            if ui.button("Save positions").clicked() {
+                                self.layout_status = Some(
+                                    self.topology
+                                        .save_positions(&self.layout_path)
+                                        .map(|count| format!("Saved {} positions", count)),
+                                );
+                            }
+                            if ui.button("Load positions").clicked() {
+                                self.layout_status = Some(
+                                    self.topology
+                                        .load_positions(&self.layout_path)
+                                        .map(|count| format!("Loaded {} positions", count)),
+                                );
+                            }
+                        });
+                        match &self.layout_status {
+                            Some(Ok(status)) => {
+                                ui.small(status);
+                            }
+                            Some(Err(e)) => {
+                                ui.colored_label(egui::Color32::RED, e);
+                            }
+                            None => {}
+                        }
+                        ui.small("Shift+drag between nodes to link them, right-click a link or a drone to edit it");
+                        if !self.topology.highlighted_path.is_empty() {
+                            ui.separator();
+                            ui.colored_label(egui::Color32::ORANGE, " ━ Traced path");
+                            if ui.small_button("Clear trace").clicked() {
+                                self.topology.highlighted_path.clear();
+                                self.topology.highlighted_stops.clear();
+                            }
+                        }
+                        if self.topology.routing_overlay.is_some() {
+                            ui.separator();
+                            ui.colored_label(egui::Color32::LIGHT_BLUE, " ┅ Believed path");
+                            ui.colored_label(egui::Color32::RED, " ┅ Stale route");
+                        }
                     });
+
+                self.show_routing_state(ctx);
             }
         }
     }
@@ -532,6 +1676,23 @@ impl NetworkTopology {
         NetworkTopology {
             nodes: Vec::new(),
             connections: Vec::new(),
+            highlighted_path: Vec::new(),
+            highlighted_stops: Vec::new(),
+            packet_dots: VecDeque::new(),
+            drop_flashes: HashMap::new(),
+            flood_senders: HashMap::new(),
+            edge_traffic: HashMap::new(),
+            animation_speed: 1.0,
+            zoom: 1.0,
+            pan: egui::Vec2::ZERO,
+            saved_positions: HashMap::new(),
+            link_drag_from: None,
+            selected_edge: None,
+            pdr_inputs: HashMap::new(),
+            editor_error: None,
+            routing_overlay: None,
+            believed_paths: Vec::new(),
+            state_query: None,
         }
     }
 
@@ -541,8 +1702,11 @@ impl NetworkTopology {
         clients: &HashMap<NodeId, (Sender<ClientServerCommand>, Vec<NodeId>)>,
         servers: &HashMap<NodeId, (Sender<ClientServerCommand>, Vec<NodeId>, ServerType)>,
     ) {
-        // Keep track of existing node positions
+        // Keep track of existing node positions, the saved ones are used until nodes are moved
         let mut existing_positions = HashMap::new();
+        for (node_id, position) in &self.saved_positions {
+            existing_positions.insert(node_id.to_string(), *position);
+        }
         for node in &self.nodes {
             existing_positions.insert(node.id.clone(), node.position);
         }
@@ -573,7 +1737,21 @@ impl NetworkTopology {
         let mut node_positions = HashMap::new();
 
         // Position drones in a circle
+        let mut placed_by_default = Vec::new();
         for (i, (node_id, _)) in drones.iter().enumerate() {
+            // Try to maintain manually adjusted positions first
+            if let Some(pos) = existing_positions.get(&node_id.to_string()) {
+                node_positions.insert(*node_id, *pos);
+                self.nodes.push(Node {
+                    id: node_id.to_string(),
+                    position: *pos,
+                    is_client: false,
+                    is_server: false,
+                });
+                continue;
+            }
+
+            placed_by_default.push(self.nodes.len());
             let angle = i as f32 * drone_angle_step;
             let x = center.0 + drone_radius * angle.cos();
             let y = center.1 + drone_radius * angle.sin();
@@ -610,6 +1788,7 @@ impl NetworkTopology {
             }
 
             // Default positioning in left semicircle
+            placed_by_default.push(self.nodes.len());
             let angle = std::f32::consts::PI + angle_offset + (i as f32 * client_angle_step);
             let x = center.0 + client_radius * angle.cos();
             let y = center.1 + client_radius * angle.sin();
@@ -646,6 +1825,7 @@ impl NetworkTopology {
             }
 
             // Default positioning in right semicircle
+            placed_by_default.push(self.nodes.len());
             let angle = angle_offset + (i as f32 * server_angle_step);
             let x = center.0 + server_radius * angle.cos();
             let y = center.1 + server_radius * angle.sin();
@@ -695,6 +1875,246 @@ impl NetworkTopology {
                 }
             }
         }
+
+        // New nodes are spread out by the layout, around the ones already placed
+        if placed_by_default.len() == self.nodes.len() {
+            self.auto_layout();
+        } else if !placed_by_default.is_empty() {
+            self.relax_layout(&placed_by_default.into_iter().collect());
+        }
+    }
+
+    // Lays out every node again, keeping the attempt with the fewest crossing edges
+    pub fn auto_layout(&mut self) {
+        let movable: HashSet<usize> = (0..self.nodes.len()).collect();
+        let mut rng = rand::thread_rng();
+        let mut best: Option<(usize, Vec<(f32, f32)>)> = None;
+        for _ in 0..LAYOUT_ATTEMPTS {
+            for node in &mut self.nodes {
+                node.position = (
+                    LAYOUT_CENTER.0 + rng.gen_range(-0.5..0.5) * LAYOUT_SIZE.0,
+                    LAYOUT_CENTER.1 + rng.gen_range(-0.5..0.5) * LAYOUT_SIZE.1,
+                );
+            }
+            self.relax_layout(&movable);
+            let crossings = self.edge_crossings();
+            if best.as_ref().is_none_or(|(fewest, _)| crossings < *fewest) {
+                best = Some((crossings, self.nodes.iter().map(|node| node.position).collect()));
+            }
+        }
+        if let Some((_crossings, positions)) = best {
+            debug!("Topology laid out with {} crossing edges", _crossings);
+            for (node, position) in self.nodes.iter_mut().zip(positions) {
+                node.position = position;
+            }
+        }
+    }
+
+    // Fruchterman-Reingold: nodes push each other away, connections pull them together
+    fn relax_layout(&mut self, movable: &HashSet<usize>) {
+        let count = self.nodes.len();
+        if count == 0 {
+            return;
+        }
+        let ideal_distance = (LAYOUT_SIZE.0 * LAYOUT_SIZE.1 / count as f32).sqrt() * 0.75;
+        let mut temperature = LAYOUT_SIZE.0 / 10.0;
+        let cooling = temperature / LAYOUT_ITERATIONS as f32;
+
+        for _ in 0..LAYOUT_ITERATIONS {
+            let positions: Vec<egui::Vec2> = self
+                .nodes
+                .iter()
+                .map(|node| egui::vec2(node.position.0, node.position.1))
+                .collect();
+            let mut displacement = vec![egui::Vec2::ZERO; count];
+
+            for i in 0..count {
+                for j in (i + 1)..count {
+                    let delta = positions[i] - positions[j];
+                    let distance = delta.length().max(0.01);
+                    let force = delta / distance * ideal_distance * ideal_distance / distance;
+                    displacement[i] += force;
+                    displacement[j] -= force;
+                }
+            }
+            for &(i, j) in &self.connections {
+                let delta = positions[i] - positions[j];
+                let distance = delta.length().max(0.01);
+                let force = delta / distance * distance * distance / ideal_distance;
+                displacement[i] -= force;
+                displacement[j] += force;
+            }
+
+            for &i in movable {
+                let length = displacement[i].length();
+                if length < 0.01 {
+                    continue;
+                }
+                let step = displacement[i] / length * length.min(temperature);
+                let position = &mut self.nodes[i].position;
+                position.0 = (position.0 + step.x).clamp(
+                    LAYOUT_CENTER.0 - LAYOUT_SIZE.0 / 2.0,
+                    LAYOUT_CENTER.0 + LAYOUT_SIZE.0 / 2.0,
+                );
+                position.1 = (position.1 + step.y).clamp(
+                    LAYOUT_CENTER.1 - LAYOUT_SIZE.1 / 2.0,
+                    LAYOUT_CENTER.1 + LAYOUT_SIZE.1 / 2.0,
+                );
+            }
+            temperature = (temperature - cooling).max(1.0);
+        }
+    }
+
+    fn edge_crossings(&self) -> usize {
+        let point = |i: usize| egui::pos2(self.nodes[i].position.0, self.nodes[i].position.1);
+        let orientation = |a: egui::Pos2, b: egui::Pos2, c: egui::Pos2| {
+            ((b - a).x * (c - a).y - (b - a).y * (c - a).x).signum()
+        };
+        let mut crossings = 0;
+        for (n, &(a, b)) in self.connections.iter().enumerate() {
+            for &(c, d) in &self.connections[n + 1..] {
+                // Edges sharing a node don't cross
+                if a == c || a == d || b == c || b == d {
+                    continue;
+                }
+                let (pa, pb, pc, pd) = (point(a), point(b), point(c), point(d));
+                if orientation(pa, pb, pc) != orientation(pa, pb, pd)
+                    && orientation(pc, pd, pa) != orientation(pc, pd, pb)
+                {
+                    crossings += 1;
+                }
+            }
+        }
+        crossings
+    }
+
+    pub fn save_positions(&mut self, path: &Path) -> Result<usize, String> {
+        self.saved_positions = self
+            .nodes
+            .iter()
+            .filter_map(|node| Some((node.id.parse::<NodeId>().ok()?, node.position)))
+            .collect();
+        let json = serde_json::to_string_pretty(&self.saved_positions).map_err(|e| e.to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+        }
+        fs::write(path, json).map_err(|e| format!("failed to write {:?}: {}", path, e))?;
+        Ok(self.saved_positions.len())
+    }
+
+    pub fn load_positions(&mut self, path: &Path) -> Result<usize, String> {
+        let json = fs::read_to_string(path).map_err(|e| format!("failed to read {:?}: {}", path, e))?;
+        self.saved_positions = serde_json::from_str(&json).map_err(|e| format!("{:?} is not a layout: {}", path, e))?;
+        for node in &mut self.nodes {
+            if let Some(position) = node
+                .id
+                .parse::<NodeId>()
+                .ok()
+                .and_then(|node_id| self.saved_positions.get(&node_id))
+            {
+                node.position = *position;
+            }
+        }
+        Ok(self.saved_positions.len())
+    }
+
+    fn is_connected(&self, node1: NodeId, node2: NodeId) -> bool {
+        let (id1, id2) = (node1.to_string(), node2.to_string());
+        self.connections.iter().any(|&(idx1, idx2)| {
+            let (n1, n2) = (&self.nodes[idx1].id, &self.nodes[idx2].id);
+            (*n1 == id1 && *n2 == id2) || (*n1 == id2 && *n2 == id1)
+        })
+    }
+
+    fn neighbors(&self, node_id: NodeId) -> Vec<NodeId> {
+        self.nodes
+            .iter()
+            .filter_map(|node| node.id.parse::<NodeId>().ok())
+            .filter(|&other| other != node_id && self.is_connected(node_id, other))
+            .collect()
+    }
+
+    fn add_dot(&mut self, from: NodeId, to: NodeId, packet_type: &str) {
+        *self.edge_traffic.entry((from.min(to), from.max(to))).or_default() += 1.0;
+        self.packet_dots.push_back(PacketDot {
+            from,
+            to,
+            color: packet_color(packet_type),
+            progress: 0.0,
+        });
+        if self.packet_dots.len() > MAX_PACKET_DOTS {
+            self.packet_dots.pop_front();
+        }
+    }
+
+    // Starts a dot on the edge the packet was sent along
+    pub fn packet_sent(&mut self, packet: &Packet) {
+        let packet_type = match packet.pack_type {
+            PacketType::MsgFragment(_) => "MsgFragment",
+            PacketType::Ack(_) => "Ack",
+            PacketType::Nack(_) => "Nack",
+            PacketType::FloodRequest(_) => "FloodRequest",
+            PacketType::FloodResponse(_) => "FloodResponse",
+        };
+
+        // Flood requests aren't source routed, they go to every neighbor but the previous hop.
+        // A sender reports one event per neighbor, the first one animates them all
+        if let PacketType::FloodRequest(flood_request) = &packet.pack_type {
+            let mut path = flood_request.path_trace.iter().rev().map(|(id, _)| *id);
+            if let Some(sender) = path.next() {
+                let now = Instant::now();
+                let window = Duration::from_secs_f32(PACKET_TRAVEL_SECS);
+                self.flood_senders.retain(|_, seen_at| now.duration_since(*seen_at) < window);
+                let flood = (flood_request.initiator_id, flood_request.flood_id, sender);
+                if self.flood_senders.insert(flood, now).is_some() {
+                    return;
+                }
+                let previous_hop = path.next();
+                for neighbor in self.neighbors(sender) {
+                    if Some(neighbor) != previous_hop {
+                        self.add_dot(sender, neighbor, packet_type);
+                    }
+                }
+            }
+            return;
+        }
+
+        // The hop index already points at the node receiving the packet
+        let hops = &packet.routing_header.hops;
+        let hop_index = packet.routing_header.hop_index;
+        if let (Some(&from), Some(&to)) = (
+            hop_index.checked_sub(1).and_then(|i| hops.get(i)),
+            hops.get(hop_index),
+        ) {
+            self.add_dot(from, to, packet_type);
+        }
+    }
+
+    pub fn packet_dropped(&mut self, packet: &Packet) {
+        let routing_header = &packet.routing_header;
+        if let Some(&drone_id) = routing_header.hops.get(routing_header.hop_index) {
+            self.drop_flashes.insert(drone_id, DROP_FLASH_SECS);
+        }
+    }
+
+    // Moves the dots and fades the flashes and the edge traffic, scaled by the animation speed
+    fn animate(&mut self, dt: f32) {
+        let dt = dt * self.animation_speed;
+        for dot in self.packet_dots.iter_mut() {
+            dot.progress += dt / PACKET_TRAVEL_SECS;
+        }
+        self.packet_dots.retain(|dot| dot.progress < 1.0);
+
+        for time_left in self.drop_flashes.values_mut() {
+            *time_left -= dt;
+        }
+        self.drop_flashes.retain(|_, time_left| *time_left > 0.0);
+
+        let decay = (-dt / THROUGHPUT_WINDOW_SECS).exp();
+        for traffic in self.edge_traffic.values_mut() {
+            *traffic *= decay;
+        }
+        self.edge_traffic.retain(|_, traffic| *traffic > 0.01);
     }
 
     fn draw(
@@ -703,16 +2123,66 @@ impl NetworkTopology {
         client_tex: Option<&egui::TextureHandle>,
         server_tex: Option<&egui::TextureHandle>,
         drone_tex: Option<&egui::TextureHandle>,
-    ) {
-        let (response, painter) = ui.allocate_painter(ui.available_size(), egui::Sense::click());
+        drone_pdrs: &HashMap<NodeId, f32>,
+        down_links: &HashSet<(NodeId, NodeId)>,
+    ) -> Option<TopologyEdit> {
+        let (response, painter) = ui.allocate_painter(ui.available_size(), egui::Sense::click_and_drag());
+        let mut edit = None;
+
+        // Dragging the background pans, scrolling or pinching zooms around the pointer
+        if response.dragged() {
+            self.pan += response.drag_delta();
+        }
+        if let Some(pointer) = response.hover_pos() {
+            let (scroll, pinch) = ui.input(|i| (i.smooth_scroll_delta.y, i.zoom_delta()));
+            let zoom = (self.zoom * pinch * (scroll / 200.0).exp()).clamp(MIN_ZOOM, MAX_ZOOM);
+            if zoom != self.zoom {
+                let anchor = pointer - response.rect.min;
+                self.pan = anchor - (anchor - self.pan) * (zoom / self.zoom);
+                self.zoom = zoom;
+            }
+        }
+        let zoom = self.zoom;
+        let origin = response.rect.min + self.pan;
+        let to_screen = |position: (f32, f32)| origin + egui::vec2(position.0, position.1) * zoom;
+
+        // Right-clicking next to a connection opens its menu
+        if response.secondary_clicked() {
+            self.selected_edge = response.interact_pointer_pos().and_then(|pointer| {
+                self.connections
+                    .iter()
+                    .map(|&(idx1, idx2)| (&self.nodes[idx1], &self.nodes[idx2]))
+                    .filter(|(node1, node2)| {
+                        let (pos1, pos2) = (to_screen(node1.position), to_screen(node2.position));
+                        let along = (pointer - pos1).dot(pos2 - pos1) / (pos2 - pos1).length_sq().max(1.0);
+                        pointer.distance(pos1.lerp(pos2, along.clamp(0.0, 1.0))) < 8.0
+                    })
+                    .find_map(|(node1, node2)| Some((node1.id.parse().ok()?, node2.id.parse().ok()?)))
+            });
+        }
+        response.context_menu(|ui| match self.selected_edge {
+            Some((node1_id, node2_id)) => {
+                ui.label(format!("Link {} – {}", node1_id, node2_id));
+                if ui.button("Remove link").clicked() {
+                    edit = Some(TopologyEdit::RemoveLink(node1_id, node2_id));
+                    ui.close_menu();
+                }
+            }
+            None => ui.close_menu(),
+        });
+
+        self.animate(ui.input(|i| i.stable_dt).min(0.1));
+        if self.animation_speed > 0.0 && (!self.packet_dots.is_empty() || !self.drop_flashes.is_empty()) {
+            ui.ctx().request_repaint();
+        }
 
         // **Draw connections**
         for &(node1_idx, node2_idx) in &self.connections {
             let node1 = &self.nodes[node1_idx];
             let node2 = &self.nodes[node2_idx];
 
-            let pos1 = response.rect.min + egui::vec2(node1.position.0, node1.position.1);
-            let pos2 = response.rect.min + egui::vec2(node2.position.0, node2.position.1);
+            let pos1 = to_screen(node1.position);
+            let pos2 = to_screen(node2.position);
 
             let color = if node1.is_client || node2.is_client {
                 egui::Color32::RED
@@ -722,13 +2192,83 @@ impl NetworkTopology {
                 egui::Color32::LIGHT_GRAY
             };
 
-            painter.line_segment([pos1, pos2], egui::Stroke::new(2.0, color));
+            let link = match (node1.id.parse::<NodeId>(), node2.id.parse::<NodeId>()) {
+                (Ok(id1), Ok(id2)) => Some((id1.min(id2), id1.max(id2))),
+                _ => None,
+            };
+
+            // Links cut by a partition or a flap lose every packet, they're dashed until they're back
+            if link.is_some_and(|link| down_links.contains(&link)) {
+                painter.extend(egui::Shape::dashed_line(&[pos1, pos2], egui::Stroke::new(2.0, egui::Color32::DARK_GRAY), 6.0, 6.0));
+                continue;
+            }
+
+            // Thicker for the busiest connections
+            let traffic = link.and_then(|link| self.edge_traffic.get(&link).copied()).unwrap_or(0.0);
+            let width = 2.0 + traffic.sqrt().min(4.0) * 1.5;
+
+            painter.line_segment([pos1, pos2], egui::Stroke::new(width, color));
+        }
+
+        // **Draw traced path**
+        let position_of = |node_id: &NodeId| {
+            self.nodes
+                .iter()
+                .find(|node| node.id == node_id.to_string())
+                .map(|node| to_screen(node.position))
+        };
+        for hop in self.highlighted_path.windows(2) {
+            if let (Some(pos1), Some(pos2)) = (position_of(&hop[0]), position_of(&hop[1])) {
+                painter.arrow(pos1, pos2 - pos1, egui::Stroke::new(5.0, egui::Color32::ORANGE));
+            }
+        }
+        for node_id in &self.highlighted_stops {
+            if let Some(pos) = position_of(node_id) {
+                painter.circle_stroke(pos, 32.0 * zoom, egui::Stroke::new(4.0, egui::Color32::RED));
+            }
+        }
+
+        // **Draw believed paths**, hops that no longer exist are red
+        let mut drawn_hops = HashSet::new();
+        for hop in self.believed_paths.iter().flat_map(|path| path.windows(2)) {
+            if !drawn_hops.insert((hop[0].min(hop[1]), hop[0].max(hop[1]))) {
+                continue;
+            }
+            let stale = !self.is_connected(hop[0], hop[1]);
+            match (position_of(&hop[0]), position_of(&hop[1])) {
+                (Some(pos1), Some(pos2)) => {
+                    let color = if stale { egui::Color32::RED } else { egui::Color32::LIGHT_BLUE };
+                    painter.extend(egui::Shape::dashed_line(&[pos1, pos2], egui::Stroke::new(3.0, color), 8.0, 5.0));
+                }
+                // The other end was removed from the network
+                (Some(pos), None) | (None, Some(pos)) => {
+                    painter.circle_stroke(pos, 28.0 * zoom, egui::Stroke::new(3.0, egui::Color32::RED));
+                }
+                (None, None) => {}
+            }
+        }
+        if let Some(pos) = self.routing_overlay.as_ref().and_then(position_of) {
+            painter.circle_stroke(pos, 32.0 * zoom, egui::Stroke::new(3.0, egui::Color32::LIGHT_BLUE));
+        }
+
+        // **Draw packets**
+        for dot in &self.packet_dots {
+            if let (Some(pos1), Some(pos2)) = (position_of(&dot.from), position_of(&dot.to)) {
+                painter.circle_filled(pos1.lerp(pos2, dot.progress), 5.0, dot.color);
+            }
+        }
+        for (node_id, time_left) in &self.drop_flashes {
+            if let Some(pos) = position_of(node_id) {
+                let alpha = (255.0 * time_left / DROP_FLASH_SECS) as u8;
+                painter.circle_filled(pos, 34.0 * zoom, egui::Color32::from_rgba_unmultiplied(255, 0, 0, alpha));
+            }
         }
 
         // **Draw nodes**
+        let mut drop_target = None;
         for node in &mut self.nodes {
-            let icon_size = egui::Vec2::new(50.0, 50.0);
-            let center_pos = response.rect.min + egui::vec2(node.position.0, node.position.1);
+            let icon_size = egui::Vec2::new(50.0, 50.0) * zoom;
+            let center_pos = to_screen(node.position);
             let icon_rect = egui::Rect::from_center_size(center_pos, icon_size);
 
             // Enable click + drag
@@ -738,11 +2278,54 @@ impl NetworkTopology {
                 egui::Sense::click_and_drag(),
             );
 
-            // Handle dragging: update position based on mouse delta
-            if interact.dragged() {
+            let node_id = node.id.parse::<NodeId>().ok();
+
+            // Shift-dragging draws a new link, dragging alone moves the node
+            if interact.drag_started() && ui.input(|i| i.modifiers.shift) {
+                self.link_drag_from = node_id;
+            }
+            if interact.dragged() && self.link_drag_from.is_none() {
                 let delta = interact.drag_delta();
-                node.position.0 += delta.x;
-                node.position.1 += delta.y;
+                node.position.0 += delta.x / zoom;
+                node.position.1 += delta.y / zoom;
+            }
+            if let (Some(from), true) = (self.link_drag_from, node_id == self.link_drag_from) {
+                if let Some(pointer) = ui.ctx().pointer_latest_pos() {
+                    if interact.dragged() {
+                        painter.line_segment([center_pos, pointer], egui::Stroke::new(3.0, egui::Color32::YELLOW));
+                    }
+                    if interact.drag_stopped() {
+                        drop_target = Some((from, pointer));
+                    }
+                }
+            }
+
+            // Drones can be crashed or get a new PDR from their menu
+            if let Some(&pdr) = node_id.as_ref().and_then(|id| drone_pdrs.get(id)) {
+                let node_id = node_id.unwrap_or_default();
+                let pdr_input = self.pdr_inputs.entry(node_id).or_insert(pdr);
+                interact.context_menu(|ui| {
+                    ui.label(format!("Drone {}", node_id));
+                    ui.add(egui::Slider::new(pdr_input, 0.0..=1.0).text("PDR"));
+                    if ui.button("Set PDR").clicked() {
+                        edit = Some(TopologyEdit::SetPdr(node_id, *pdr_input));
+                        ui.close_menu();
+                    }
+                    if ui.button("Crash").clicked() {
+                        edit = Some(TopologyEdit::Crash(node_id));
+                        ui.close_menu();
+                    }
+                });
+            } else if let Some(node_id) = node_id {
+                // Clients and servers can show the paths they believe in
+                interact.context_menu(|ui| {
+                    let kind = if node.is_client { "Client" } else { "Server" };
+                    ui.label(format!("{} {}", kind, node_id));
+                    if ui.button("Show routing state").clicked() {
+                        self.state_query = Some(node_id);
+                        ui.close_menu();
+                    }
+                });
             }
 
             // Choose correct texture
@@ -779,5 +2362,34 @@ impl NetworkTopology {
                 egui::Color32::WHITE,
             );
         }
+
+        // A link dragged onto another node
+        if let Some((from, pointer)) = drop_target {
+            self.link_drag_from = None;
+            let target = self
+                .nodes
+                .iter()
+                .find(|node| to_screen(node.position).distance(pointer) < 25.0 * zoom)
+                .and_then(|node| node.id.parse::<NodeId>().ok());
+            if let Some(to) = target.filter(|&to| to != from) {
+                edit = Some(TopologyEdit::AddLink(from, to));
+            }
+        }
+        if !ui.input(|i| i.pointer.any_down()) {
+            self.link_drag_from = None;
+        }
+
+        // Rejected edits are shown over the graph until the next one
+        if let Some(error) = &self.editor_error {
+            painter.text(
+                response.rect.left_top() + egui::vec2(10.0, 10.0),
+                egui::Align2::LEFT_TOP,
+                format!("⚠ {}", error),
+                egui::FontId::proportional(16.0),
+                egui::Color32::RED,
+            );
+        }
+
+        edit
     }
 }
@@ -1,6 +1,9 @@
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use wg_2024::controller::{DroneEvent};
 use wg_2024::network::NodeId;
+use wg_2024::packet::{Packet, PacketType};
 use crate::client_server::network_core::{ClientEvent, ServerEvent};
 
 pub struct ClientsDownloadedData {
@@ -8,6 +11,9 @@ pub struct ClientsDownloadedData {
     known_text: HashMap<u64, bool>, // index -> has been downloaded?
     media: HashMap<(NodeId, NodeId), HashSet<u64>>, // (client, server) -> list of index
     known_media: HashMap<u64, bool>, // index -> has been downloaded?
+    text_served_by: HashMap<(NodeId, u64), NodeId>, // (client, index) -> replica that sent it
+    media_served_by: HashMap<(NodeId, u64), NodeId>, // (client, index) -> replica that sent it
+    replicas: HashMap<(NodeId, NodeId), HashMap<u64, Vec<NodeId>>>, // (client, server) -> index -> other servers holding it
 }
 
 impl ClientsDownloadedData {
@@ -17,6 +23,9 @@ impl ClientsDownloadedData {
             known_text: HashMap::new(),
             media: HashMap::new(),
             known_media: HashMap::new(),
+            text_served_by: HashMap::new(),
+            media_served_by: HashMap::new(),
+            replicas: HashMap::new(),
         }
     }
 
@@ -37,6 +46,10 @@ impl ClientsDownloadedData {
         client_server_data.insert(data_index);
         // Mark as downloaded (true)
         self.known_text.insert(data_index, true);
+        self.text_served_by.insert((client_id, data_index), server_id);
+    }
+    pub fn get_text_served_by(&self, client_id: NodeId, data_index: u64) -> Option<NodeId> {
+        self.text_served_by.get(&(client_id, data_index)).copied()
     }
     pub fn get_know_text_list(&self, client_id: NodeId, server_id: NodeId) -> Option<Vec<u64>> {
         // Get the HashSet for this client-server pair, regardless of download status
@@ -101,6 +114,17 @@ impl ClientsDownloadedData {
         client_server_data.insert(data_index);
         // Mark as downloaded (true)
         self.known_media.insert(data_index, true);
+        self.media_served_by.insert((client_id, data_index), server_id);
+    }
+    pub fn get_media_served_by(&self, client_id: NodeId, data_index: u64) -> Option<NodeId> {
+        self.media_served_by.get(&(client_id, data_index)).copied()
+    }
+
+    pub fn add_replicas(&mut self, client_id: NodeId, server_id: NodeId, replicas: HashMap<u64, Vec<NodeId>>) {
+        self.replicas.insert((client_id, server_id), replicas);
+    }
+    pub fn get_replicas(&self, client_id: NodeId, server_id: NodeId) -> Option<&HashMap<u64, Vec<NodeId>>> {
+        self.replicas.get(&(client_id, server_id))
     }
     pub fn get_know_media_list(&self, client_id: NodeId, server_id: NodeId) -> Option<Vec<u64>> {
         // Get the HashSet for this client-server pair, regardless of download status
@@ -165,6 +189,9 @@ impl ClientsDownloadedData {
 pub enum Screen {
     NetworkScreen,
     LogsScreen,
+    TraceScreen,
+    StatsScreen,
+    FaultsScreen,
 }
 
 #[derive(PartialEq)]
@@ -195,11 +222,115 @@ impl Default for LogFilters {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogCategory {
+    Packet,
+    Message,
+    Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeKind {
+    Drone,
+    Client,
+    Server,
+}
+
+// One line of the event log, also written as JSON to the event log file
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
-    pub(crate) timestamp: String,
+    pub(crate) utc_millis: i64,
+    pub(crate) elapsed_millis: u64, // since the simulation started
+    pub(crate) category: LogCategory,
+    pub(crate) kind: String, // name of the event variant
+    pub(crate) node_kind: NodeKind,
+    #[serde(default)]
+    pub(crate) node_id: Option<NodeId>,
+    #[serde(default)]
+    pub(crate) peer_id: Option<NodeId>, // the other end of a message or command
+    #[serde(default)]
+    pub(crate) session_id: Option<u64>,
+    #[serde(default)]
+    pub(crate) fragment_index: Option<u64>,
+    #[serde(default)]
+    pub(crate) packet_type: Option<String>,
+    #[serde(default)]
+    pub(crate) nack_type: Option<String>,
+    #[serde(default)]
+    pub(crate) route: Vec<NodeId>,
+    #[serde(default)]
+    pub(crate) hop_index: Option<usize>,
     pub(crate) message: String,
 }
 
+impl LogEntry {
+    // Times are filled in when the entry is logged
+    pub fn new(category: LogCategory, kind: &str, node_kind: NodeKind, message: String) -> Self {
+        Self {
+            utc_millis: 0,
+            elapsed_millis: 0,
+            category,
+            kind: kind.to_string(),
+            node_kind,
+            node_id: None,
+            peer_id: None,
+            session_id: None,
+            fragment_index: None,
+            packet_type: None,
+            nack_type: None,
+            route: Vec::new(),
+            hop_index: None,
+            message,
+        }
+    }
+
+    pub fn node(mut self, node_id: NodeId) -> Self {
+        self.node_id = Some(node_id);
+        self
+    }
+
+    pub fn peer(mut self, peer_id: NodeId) -> Self {
+        self.peer_id = Some(peer_id);
+        self
+    }
+
+    pub fn session(mut self, session_id: u64) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    pub fn packet(mut self, packet: &Packet) -> Self {
+        let (packet_type, fragment_index) = match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => ("MsgFragment", Some(fragment.fragment_index)),
+            PacketType::Ack(ack) => ("Ack", Some(ack.fragment_index)),
+            PacketType::Nack(nack) => {
+                self.nack_type = Some(format!("{:?}", nack.nack_type));
+                ("Nack", Some(nack.fragment_index))
+            }
+            PacketType::FloodRequest(_) => ("FloodRequest", None),
+            PacketType::FloodResponse(_) => ("FloodResponse", None),
+        };
+        self.packet_type = Some(packet_type.to_string());
+        self.fragment_index = fragment_index;
+        self.session_id = Some(packet.session_id);
+        self.route = packet.routing_header.hops.clone();
+        self.hop_index = Some(packet.routing_header.hop_index);
+        self.node_id = packet
+            .routing_header
+            .hops
+            .get(packet.routing_header.hop_index)
+            .copied();
+        self
+    }
+
+    // Shown in the local timezone of the machine looking at the logs
+    pub fn timestamp(&self) -> String {
+        DateTime::<Utc>::from_timestamp_millis(self.utc_millis)
+            .map(|time| time.with_timezone(&Local).format("%d-%m-%y %H:%M:%S").to_string())
+            .unwrap_or_default()
+    }
+}
+
 pub enum Event{
     Drone(DroneEvent),
     Client(ClientEvent),
@@ -211,4 +342,31 @@ pub struct Node {
     pub(crate) position: (f32, f32),
     pub(crate) is_client: bool,
     pub(crate) is_server: bool,
-}
\ No newline at end of file
+}
+// A packet moving along an edge of the topology graph
+pub struct PacketDot {
+    pub(crate) from: NodeId,
+    pub(crate) to: NodeId,
+    pub(crate) color: egui::Color32, // by packet type
+    pub(crate) progress: f32, // 0 at `from`, 1 at `to`
+}
+
+// Change asked for on the topology graph, applied through the simulation controller
+#[derive(Clone, Copy)]
+pub enum TopologyEdit {
+    AddLink(NodeId, NodeId),
+    RemoveLink(NodeId, NodeId),
+    Crash(NodeId),
+    SetPdr(NodeId, f32),
+}
+
+impl TopologyEdit {
+    pub fn describe(&self) -> String {
+        match self {
+            TopologyEdit::AddLink(node1_id, node2_id) => format!("Link {} to {}", node1_id, node2_id),
+            TopologyEdit::RemoveLink(node1_id, node2_id) => format!("Unlink {} from {}", node1_id, node2_id),
+            TopologyEdit::Crash(drone_id) => format!("Crash Drone {}", drone_id),
+            TopologyEdit::SetPdr(drone_id, pdr) => format!("Set the PDR of Drone {} to {:.2}", drone_id, pdr),
+        }
+    }
+}
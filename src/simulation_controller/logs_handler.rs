@@ -1,160 +1,234 @@
 use wg_2024::controller::DroneEvent;
+use wg_2024::packet::Packet;
 
 use crate::simulation_controller::gui_structs::*;
 
 use crate::client_server::network_core::{ClientEvent, ServerEvent};
 use crate::simulation_controller::gui::MyApp;
-use chrono::{DateTime, Utc};
-use chrono_tz::Europe::Rome;
+use chrono::Utc;
 
 //Function to log events/commands from drones, clients and server.
 pub fn logs(app: &mut MyApp, event: Event) {
-    let current_time: DateTime<Utc> = Utc::now(); //Get current time.
-    let local_time = current_time.with_timezone(&Rome); //Convert to Italian time.
-    let formatted_time = local_time.format("%d-%m-%y %H:%M:%S").to_string(); //Format as string.
-
-    let message = match event {
+    let entry = match event {
         Event::Drone(drone_event) => match drone_event {
-            DroneEvent::PacketSent(packet) => {
-                format!(
-                    "[PACKET] Sent to Drone {}.",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
-                )
-            }
-            DroneEvent::PacketDropped(packet) => {
-                format!(
-                    "[PACKET] Dropped by Drone {}",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
-                )
-            }
-            DroneEvent::ControllerShortcut(packet) => {
-                format!(
-                    "[PACKET] Routed through Controller by Drone {}.",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
-                )
-            }
+            DroneEvent::PacketSent(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "PacketSent",
+                NodeKind::Drone,
+                format!("Sent to Drone {}.", current_hop(&packet)),
+            )
+            .packet(&packet),
+            DroneEvent::PacketDropped(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "PacketDropped",
+                NodeKind::Drone,
+                format!("Dropped by Drone {}", current_hop(&packet)),
+            )
+            .packet(&packet),
+            DroneEvent::ControllerShortcut(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "ControllerShortcut",
+                NodeKind::Drone,
+                format!("Routed through Controller by Drone {}.", current_hop(&packet)),
+            )
+            .packet(&packet),
         },
 
         Event::Client(client_event) => match client_event {
-            ClientEvent::PacketSent(packet) => {
-                format!(
-                    "[PACKET] Sent by Client {}",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
+            ClientEvent::PacketSent(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "PacketSent",
+                NodeKind::Client,
+                format!("Sent by Client {}", current_hop(&packet)),
+            )
+            .packet(&packet),
+            ClientEvent::PacketReceived(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "PacketReceived",
+                NodeKind::Client,
+                format!("Received by Client: {}.", current_hop(&packet)),
+            )
+            .packet(&packet),
+            ClientEvent::MessageSent { from, to, content } => LogEntry::new(
+                LogCategory::Message,
+                "MessageSent",
+                NodeKind::Client,
+                format!("Sent by Client: {} to {}, content: {:?}", from, to, content),
+            )
+            .node(from)
+            .peer(to),
+            ClientEvent::MessageReceived { receiver, content } => LogEntry::new(
+                LogCategory::Message,
+                "MessageReceived",
+                NodeKind::Client,
+                format!("Received by Client: {}, content: {:?}", receiver, content),
+            )
+            .node(receiver),
+            ClientEvent::BrokenDroneDetected(drone_id) => LogEntry::new(
+                LogCategory::Message,
+                "BrokenDroneDetected",
+                NodeKind::Client,
+                format!("Found Broken Drone, id: {}", drone_id),
+            )
+            .peer(drone_id),
+            ClientEvent::IntegrityCheckFailed { session_id, route } => {
+                let client_id = route.last().copied().unwrap_or_default();
+                let mut entry = LogEntry::new(
+                    LogCategory::Message,
+                    "IntegrityCheckFailed",
+                    NodeKind::Client,
+                    format!("Corrupted message received by Client: {}, session: {}, route: {:?}", client_id, session_id, route),
                 )
+                .node(client_id)
+                .session(session_id);
+                entry.route = route;
+                entry
             }
-            ClientEvent::PacketReceived(packet) => {
+            ClientEvent::TextResolved { client_id, server_id, text_id, images, missing } => LogEntry::new(
+                LogCategory::Message,
+                "TextResolved",
+                NodeKind::Client,
+                if missing.is_empty() {
+                    format!("Text {} from Server {} fully resolved by Client: {}, images: {:?}", text_id, server_id, client_id, images)
+                } else {
+                    format!("Text {} from Server {} resolved by Client: {} without images {:?} of {:?}", text_id, server_id, client_id, missing, images)
+                },
+            )
+            .node(client_id)
+            .peer(server_id),
+            ClientEvent::NodeState(state) => LogEntry::new(
+                LogCategory::Message,
+                "NodeState",
+                NodeKind::Client,
                 format!(
-                    "[PACKET] Received by Client: {}.",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
-                )
-            }
-            ClientEvent::MessageSent { from, to, content } => {
-                format!("[MESSAGE] Sent by Client: {} to {}, content: {:?}", from, to, content)
-            }
-            ClientEvent::MessageReceived { receiver, content } => {
-                format!("[MESSAGE] Received by Client: {}, content: {:?}", receiver, content)
-            }
-            ClientEvent::BrokenDroneDetected(drone_id) => {
-                format!("[MESSAGE] Found Broken Drone, id: {}", drone_id)
-            }
+                    "State of Client: {}, routes: {}, registered servers: {:?}, assembler backlog: {}",
+                    state.node_id,
+                    state.routes.len(),
+                    state.registered_servers,
+                    state.assembler_backlog
+                ),
+            )
+            .node(state.node_id),
         },
 
         Event::Server(server_event) => match server_event {
-            ServerEvent::PacketSent(packet) => {
-                format!(
-                    "[PACKET] Sent by Server: {}",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
+            ServerEvent::PacketSent(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "PacketSent",
+                NodeKind::Server,
+                format!("Sent by Server: {}", current_hop(&packet)),
+            )
+            .packet(&packet),
+            ServerEvent::PacketReceived(packet) => LogEntry::new(
+                LogCategory::Packet,
+                "PacketReceived",
+                NodeKind::Server,
+                format!("Received by Server: {}", current_hop(&packet)),
+            )
+            .packet(&packet),
+            ServerEvent::MessageSent { from, to, content } => LogEntry::new(
+                LogCategory::Message,
+                "MessageSent",
+                NodeKind::Server,
+                format!("Sent by Server: {} to {}, content: {:?}", from, to, content),
+            )
+            .node(from)
+            .peer(to),
+            ServerEvent::MessageReceived { receiver, content } => LogEntry::new(
+                LogCategory::Message,
+                "MessageReceived",
+                NodeKind::Server,
+                format!("Received by Server: {}, content: {:?}", receiver, content),
+            )
+            .node(receiver),
+            ServerEvent::IntegrityCheckFailed { session_id, route } => {
+                let server_id = route.last().copied().unwrap_or_default();
+                let mut entry = LogEntry::new(
+                    LogCategory::Message,
+                    "IntegrityCheckFailed",
+                    NodeKind::Server,
+                    format!("Corrupted message received by Server: {}, session: {}, route: {:?}", server_id, session_id, route),
                 )
+                .node(server_id)
+                .session(session_id);
+                entry.route = route;
+                entry
             }
-            ServerEvent::PacketReceived(packet) => {
+            ServerEvent::PendingDeliveries { server_id, queued } => LogEntry::new(
+                LogCategory::Message,
+                "PendingDeliveries",
+                NodeKind::Server,
+                format!("Messages queued by Server: {} for unreachable clients: {:?}", server_id, queued),
+            )
+            .node(server_id),
+            ServerEvent::NodeState(state) => LogEntry::new(
+                LogCategory::Message,
+                "NodeState",
+                NodeKind::Server,
                 format!(
-                    "[PACKET] Received by Server: {}",
-                    packet
-                        .routing_header
-                        .hops
-                        .get(packet.routing_header.hop_index)
-                        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
-                        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
-                )
-            }
-            ServerEvent::MessageSent { from, to, content } => {
-                format!("[MESSAGE] Sent by Server: {} to {}, content: {:?}", from, to, content)
-            }
-            ServerEvent::MessageReceived { receiver, content } => {
-                format!("[MESSAGE] Received by Server: {}, content: {:?}", receiver, content)
-            }
+                    "State of Server: {}, routes: {}, registered clients: {:?}, assembler backlog: {}",
+                    state.node_id,
+                    state.routes.len(),
+                    state.registered_clients,
+                    state.assembler_backlog
+                ),
+            )
+            .node(state.node_id),
         },
     };
 
-    //Add log entry
-    app.logs_vec.push(LogEntry {
-        timestamp: formatted_time,
-        message,
-    });
+    push_log(app, entry);
+}
+
+// Stamps the entry, keeps it for the Logs page and appends it to the event log file
+pub fn push_log(app: &mut MyApp, mut entry: LogEntry) {
+    entry.utc_millis = Utc::now().timestamp_millis();
+    entry.elapsed_millis = app.started_at.elapsed().as_millis() as u64;
+    if let Ok(event_log) = app.event_log.as_mut() {
+        event_log.append(&entry);
+    }
+    app.logs_vec.push(entry);
+}
+
+fn current_hop(packet: &Packet) -> String {
+    packet
+        .routing_header
+        .hops
+        .get(packet.routing_header.hop_index)
+        .map(|&hop| hop.to_string()) // Convert u8 to String if it exists.
+        .unwrap_or_else(|| "None".to_string()) // Handle the None case.
 }
 
-pub fn filtered_logs(app: &mut MyApp) -> Vec<&LogEntry> {
-    app.logs_vec
+// Entries up to `until` (milliseconds since the start of the run) that pass the filters
+pub fn filtered_logs<'a>(
+    entries: &'a [LogEntry],
+    filters: &LogFilters,
+    until: Option<u64>,
+) -> Vec<&'a LogEntry> {
+    let search_text = filters.search_text.to_lowercase();
+    entries
         .iter()
+        .filter(|log| until.is_none_or(|until| log.elapsed_millis <= until))
         .filter(|log| {
             // Filter by log type
-            let matches_type = match log.message.split_whitespace().next() {
-                Some("[PACKET]") => app.log_filters.show_packet_events,
-                Some("[MESSAGE]") => app.log_filters.show_command_events,
-                _ => true,
+            let matches_type = match log.category {
+                LogCategory::Packet => filters.show_packet_events,
+                LogCategory::Message => filters.show_command_events,
+                LogCategory::Command => true,
             };
 
             // Filter by component
-            let matches_component = if log.message.contains("Drone") {
-                app.log_filters.show_drones
-            } else if log.message.contains("Client") {
-                app.log_filters.show_clients
-            } else if log.message.contains("Server") {
-                app.log_filters.show_servers
-            } else {
-                true
+            let matches_component = match log.node_kind {
+                NodeKind::Drone => filters.show_drones,
+                NodeKind::Client => filters.show_clients,
+                NodeKind::Server => filters.show_servers,
             };
 
             // Filter by search text
-            let matches_search = app.log_filters.search_text.is_empty()
-                || log
-                    .message
-                    .to_lowercase()
-                    .contains(&app.log_filters.search_text.to_lowercase())
-                || log
-                    .timestamp
-                    .to_lowercase()
-                    .contains(&app.log_filters.search_text.to_lowercase());
+            let matches_search = search_text.is_empty()
+                || log.message.to_lowercase().contains(&search_text)
+                || log.kind.to_lowercase().contains(&search_text)
+                || log.timestamp().to_lowercase().contains(&search_text);
 
             matches_type && matches_component && matches_search
         })
@@ -1,5 +1,10 @@
 pub mod simulation_controller;
+pub mod fault_injection;
+pub mod batch;
 mod gui;
 mod gui_structs;
 mod popup_handler;
-mod logs_handler;
\ No newline at end of file
+mod logs_handler;
+mod event_log;
+mod packet_trace;
+mod stats;
\ No newline at end of file
@@ -0,0 +1,165 @@
+use crate::simulation_controller::gui_structs::{LogEntry, NodeKind};
+use std::collections::HashMap;
+use wg_2024::network::NodeId;
+
+// A fragment is identified by who sent it, its session and its index
+pub type TraceKey = (NodeId, u64, u64);
+
+// Every logged hop of one fragment, with the Acks and Nacks sent back for it
+pub struct FragmentTrace<'a> {
+    pub key: TraceKey,
+    pub route: Vec<NodeId>, // source routing header of the first time it was sent
+    pub events: Vec<&'a LogEntry>,
+}
+
+impl FragmentTrace<'_> {
+    pub fn start_millis(&self) -> u64 {
+        self.events.first().map_or(0, |event| event.elapsed_millis)
+    }
+
+    pub fn end_millis(&self) -> u64 {
+        self.events.last().map_or(0, |event| event.elapsed_millis)
+    }
+
+    fn is_fragment(event: &LogEntry) -> bool {
+        event.packet_type.as_deref() == Some("MsgFragment")
+    }
+
+    // Time the destination got the fragment, if it ever did
+    pub fn delivered_at(&self) -> Option<u64> {
+        let destination = *self.route.last()?;
+        self.events
+            .iter()
+            .find(|event| {
+                Self::is_fragment(event)
+                    && event.kind == "PacketReceived"
+                    && event.node_kind != NodeKind::Drone
+                    && event.node_id == Some(destination)
+            })
+            .map(|event| event.elapsed_millis)
+    }
+
+    pub fn latency_millis(&self) -> Option<u64> {
+        self.delivered_at()
+            .map(|delivered_at| delivered_at - self.start_millis())
+    }
+
+    // How many times the origin sent the fragment, resends included
+    pub fn attempts(&self) -> usize {
+        self.events
+            .iter()
+            .filter(|event| {
+                Self::is_fragment(event)
+                    && event.kind == "PacketSent"
+                    && event.node_kind != NodeKind::Drone
+            })
+            .count()
+    }
+
+    pub fn nacks(&self) -> Vec<&LogEntry> {
+        self.events
+            .iter()
+            .filter(|event| event.packet_type.as_deref() == Some("Nack"))
+            .copied()
+            .collect()
+    }
+
+    // Nodes that stopped the fragment: a Nack starts at the node that sent it back
+    pub fn stopped_at(&self) -> Vec<NodeId> {
+        let mut stopped_at: Vec<NodeId> = Vec::new();
+        for event in &self.events {
+            let node = match event.packet_type.as_deref() {
+                Some("Nack") if event.kind == "PacketSent" => event.route.first().copied(),
+                Some("MsgFragment") if event.kind == "PacketDropped" => event.node_id,
+                _ => None,
+            };
+            if let Some(node) = node {
+                if !stopped_at.contains(&node) {
+                    stopped_at.push(node);
+                }
+            }
+        }
+        stopped_at
+    }
+
+    // Hops the fragment actually went through
+    pub fn path(&self) -> Vec<NodeId> {
+        if self.delivered_at().is_some() {
+            return self.route.clone();
+        }
+        let last_hop = match self
+            .stopped_at()
+            .iter()
+            .filter_map(|node| self.route.iter().position(|hop| hop == node))
+            .max()
+        {
+            Some(position) => position,
+            None => self
+                .events
+                .iter()
+                .filter(|event| Self::is_fragment(event))
+                .filter_map(|event| event.hop_index)
+                .max()
+                .unwrap_or(0),
+        };
+        self.route
+            .iter()
+            .take(last_hop.min(self.route.len().saturating_sub(1)) + 1)
+            .copied()
+            .collect()
+    }
+
+    pub fn status(&self) -> String {
+        if let Some(latency) = self.latency_millis() {
+            return format!("delivered in {} ms", latency);
+        }
+        match self.stopped_at().last() {
+            Some(node) => format!("stopped at {}", node),
+            None => "in flight or lost".to_string(),
+        }
+    }
+}
+
+// Groups the packet events of the log by fragment, oldest first
+pub fn build_traces<'a>(entries: &[&'a LogEntry]) -> Vec<FragmentTrace<'a>> {
+    let mut traces: HashMap<TraceKey, FragmentTrace> = HashMap::new();
+    for entry in entries.iter().copied() {
+        let (Some(packet_type), Some(session_id), Some(fragment_index)) = (
+            entry.packet_type.as_deref(),
+            entry.session_id,
+            entry.fragment_index,
+        ) else {
+            continue;
+        };
+        // Acks and Nacks travel back to the origin, so it is the last hop of their route
+        let origin = match packet_type {
+            "MsgFragment" => entry.route.first(),
+            "Ack" | "Nack" => entry.route.last(),
+            _ => None,
+        };
+        let Some(&origin) = origin else {
+            continue;
+        };
+
+        let key = (origin, session_id, fragment_index);
+        let trace = traces.entry(key).or_insert_with(|| FragmentTrace {
+            key,
+            route: Vec::new(),
+            events: Vec::new(),
+        });
+        if trace.route.is_empty() && packet_type == "MsgFragment" {
+            trace.route = entry.route.clone();
+        }
+        trace.events.push(entry);
+    }
+
+    let mut traces: Vec<FragmentTrace> = traces
+        .into_values()
+        .filter(|trace| !trace.route.is_empty())
+        .collect();
+    for trace in &mut traces {
+        trace.events.sort_by_key(|event| event.elapsed_millis);
+    }
+    traces.sort_by_key(|trace| (trace.start_millis(), trace.key));
+    traces
+}
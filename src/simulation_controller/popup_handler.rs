@@ -1,8 +1,9 @@
-use crate::client_server::network_core::{ContentType, ServerType};
+use crate::client_server::content_server::content_file_path;
+use crate::client_server::network_core::{default_room, Attachment, ContentType, ServerType};
+use crate::message::message::Presence;
 use crate::simulation_controller::gui::MyApp;
 use crate::simulation_controller::gui_structs::*;
-use chrono::{DateTime, Utc};
-use chrono_tz::Europe::Rome;
+use chrono::{DateTime, Local, Utc};
 use eframe::egui;
 use std::path::Path;
 use wg_2024::network::NodeId;
@@ -26,10 +27,6 @@ pub fn show_popup(app: &mut MyApp, ctx: &egui::Context, name: &str) {
         }
     }
 
-    let current_time: DateTime<Utc> = Utc::now();
-    let italian_time = current_time.with_timezone(&Rome);
-    let formatted_time = italian_time.format("%d-%m-%y %H:%M:%S").to_string();
-
     // Store the is_open value in a local variable
     let mut is_open = *app.open_popups.get(name).unwrap_or(&false);
 
@@ -48,7 +45,7 @@ pub fn show_popup(app: &mut MyApp, ctx: &egui::Context, name: &str) {
             if let Ok(node_id) = node_id_str.parse::<NodeId>() {
                 // Handle Drones controls
                 if name.starts_with("Drone") {
-                    show_drone_controls(app, ui, node_id, name, &formatted_time);
+                    show_drone_controls(app, ui, node_id);
                 }
                 // Handle Clients controls
                 else if name.starts_with("Client") {
@@ -68,8 +65,6 @@ fn show_drone_controls(
     app: &mut MyApp,
     ui: &mut egui::Ui,
     node_id: NodeId,
-    name: &str,
-    formatted_time: &str,
 ) {
     let drop_rate = app
         .simulation_controller
@@ -85,6 +80,9 @@ fn show_drone_controls(
     if let Some(drop_rate) = drop_rate {
         ui.label(format!("Current PDR: {:.2}%", drop_rate * 100.0));
 
+        // Commands are applied once the inputs borrowed from the app are released
+        let mut edit = None;
+
         // Handle Set Packet Drop Rate
         let entry = app.slider_temp_pdrs.entry(node_id).or_insert(drop_rate);
 
@@ -92,19 +90,8 @@ fn show_drone_controls(
             ui.label("New Drop Rate:");
             ui.add(egui::Slider::new(entry, 0.0..=1.0).text(""));
 
-            if ui.button("Update").clicked() {
-                if (*entry - drop_rate).abs() > f32::EPSILON {
-                    app.simulation_controller
-                        .handle_set_packet_drop_rate(node_id, *entry);
-                    app.logs_vec.push(LogEntry {
-                        timestamp: formatted_time.to_string(),
-                        message: format!(
-                            "[COMMAND] Updated PDR of Drone {} to {:.2}%",
-                            node_id,
-                            *entry * 100.0
-                        ),
-                    });
-                }
+            if ui.button("Update").clicked() && (*entry - drop_rate).abs() > f32::EPSILON {
+                edit = Some(TopologyEdit::SetPdr(node_id, *entry));
             }
         });
 
@@ -113,61 +100,83 @@ fn show_drone_controls(
             ui.text_edit_singleline(input_text);
         });
 
+        let mut invalid_input = false;
         ui.horizontal(|ui| {
             if ui.button("Add Sender").clicked() {
                 match input_text.parse::<NodeId>() {
                     Ok(sender_id) => {
-                        if app.simulation_controller.handle_add_sender(node_id, sender_id) {
-                            app.topology_needs_update = true;
-                        }
-                        let message = format!("[COMMAND] Added sender {} to {}", sender_id, name);
-                        app.logs_vec.push(LogEntry {
-                            timestamp: formatted_time.to_string(),
-                            message,
-                        });
+                        edit = Some(TopologyEdit::AddLink(node_id, sender_id));
                         *input_text = String::new();
                     }
-                    Err(_) => println!("Invalid input!"),
+                    Err(_) => invalid_input = true,
                 }
             }
 
             if ui.button("Remove Sender").clicked() {
                 match input_text.parse::<NodeId>() {
                     Ok(sender_id) => {
-                        if app.simulation_controller.handle_remove_sender(node_id, sender_id) {
-                            app.topology_needs_update = true;
-                        }
-                        let message =
-                            format!("[COMMAND] Removed sender {} from {}", sender_id, name);
-                        app.logs_vec.push(LogEntry {
-                            timestamp: formatted_time.to_string(),
-                            message,
-                        });
+                        edit = Some(TopologyEdit::RemoveLink(node_id, sender_id));
                         *input_text = String::new();
                     }
-                    Err(_) => println!("Invalid input!"),
+                    Err(_) => invalid_input = true,
                 }
             }
         });
 
         // Handle Crash button
         if ui.button("Crash").clicked() {
-            if let Some((_, _, _)) = app.simulation_controller.get_drones().get(&node_id) {
-                let message = format!("[COMMAND] Crashing {}", name);
-                app.logs_vec.push(LogEntry {
-                    timestamp: formatted_time.to_string(),
-                    message,
-                });
-                app.simulation_controller.handle_crash(node_id);
-
-                // Close the popup by removing its entry
-                app.open_popups.remove(name);
+            edit = Some(TopologyEdit::Crash(node_id));
+        }
 
-                // Also clean up any related state
-                app.slider_temp_pdrs.remove(&node_id);
-                app.drone_text_inputs.remove(&node_id);
+        let result = match edit {
+            Some(edit) => {
+                let crashing = matches!(edit, TopologyEdit::Crash(_));
+                match app.apply_edit(edit, false) {
+                    Ok(()) if crashing => return, // the popup was closed with the drone
+                    result => Some(result),
+                }
             }
+            None if invalid_input => Some(Err("Invalid input, type a node id".to_string())),
+            None => None,
+        };
+        match result {
+            Some(Ok(())) => {
+                app.drone_command_errors.remove(&node_id);
+            }
+            Some(Err(e)) => {
+                app.drone_command_errors.insert(node_id, e);
+            }
+            None => {}
+        }
+        if let Some(error) = app.drone_command_errors.get(&node_id) {
+            ui.colored_label(egui::Color32::RED, error);
         }
+
+        show_snooping_view(app, ui, node_id);
+    }
+}
+
+// What a malicious drone on the path would see, encrypted bodies stay unreadable
+fn show_snooping_view(app: &mut MyApp, ui: &mut egui::Ui, node_id: NodeId) {
+    let mut snooping = app.snooped_fragments.contains_key(&node_id);
+    if ui.checkbox(&mut snooping, "Snooping drone view").changed() {
+        if snooping {
+            app.snooped_fragments.insert(node_id, Default::default());
+        } else {
+            app.snooped_fragments.remove(&node_id);
+        }
+    }
+
+    if let Some(snooped) = app.snooped_fragments.get(&node_id) {
+        egui::ScrollArea::vertical()
+            .id_salt(format!("snooped_fragments_{}", node_id))
+            .max_height(200.0)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for fragment in snooped {
+                    ui.label(egui::RichText::new(fragment).monospace());
+                }
+            });
     }
 }
 
@@ -216,12 +225,12 @@ fn show_client_controls(
                         selected_server.clone()
                     })
                     .show_ui(ui, |ui| {
-                        // Get all server IDs and their types
-                        let servers = app.simulation_controller.get_servers();
+                        // Only the servers this client completed a handshake with, in a version it can talk
+                        let servers = app.server_capabilities.get(&node_id);
 
                         // Filter to only CommunicationServers
-                        for (server_id, (_, _, server_type)) in servers {
-                            if let ServerType::CommunicationServer = server_type {
+                        for (server_id, capabilities) in servers.into_iter().flatten().filter(|(_, c)| c.is_compatible()) {
+                            if let ServerType::CommunicationServer = capabilities.server_type {
                                 let server_id_str = format!("Server {}", server_id);
                                 if ui
                                     .selectable_label(
@@ -253,6 +262,10 @@ fn show_client_controls(
 
                 // After client has registered to server then "Client List" button is displayed.
                 if registered_servers.contains(&server_id_sel) {
+                    if ui.button("Unregister").clicked() {
+                        app.simulation_controller
+                            .handle_unregistration_request(node_id, server_id_sel);
+                    }
                     if ui.button("Client List").clicked(){
                         // Set the client list popup to open for this client
                         app.client_list_popups.insert(node_id, true);
@@ -261,8 +274,80 @@ fn show_client_controls(
                 }
             });
 
+            let is_registered = app
+                .registered_servers
+                .get(&node_id)
+                .is_some_and(|servers| servers.contains(&server_id_sel));
+            let selected_room = app
+                .selected_room
+                .entry(node_id)
+                .or_insert_with(default_room)
+                .clone();
+            let rooms = app
+                .chat_rooms
+                .get(&node_id)
+                .and_then(|servers| servers.get(&server_id_sel))
+                .cloned()
+                .unwrap_or_default();
+            let is_member = rooms
+                .iter()
+                .any(|room| room.name == selected_room && room.members.contains(&node_id));
+
+            if is_registered {
+                ui.horizontal(|ui| {
+                    // Dropdown menu for the rooms of the selected server
+                    egui::ComboBox::from_id_salt(("chat_room", node_id))
+                        .width(100.0)
+                        .selected_text(&selected_room)
+                        .show_ui(ui, |ui| {
+                            for room in &rooms {
+                                let joined = if room.members.contains(&node_id) { " (joined)" } else { "" };
+                                if ui
+                                    .selectable_label(
+                                        selected_room == room.name,
+                                        format!("{}{}", room.name, joined),
+                                    )
+                                    .on_hover_text(format!("Members: {:?}", room.members))
+                                    .clicked()
+                                {
+                                    app.selected_room.insert(node_id, room.name.clone());
+                                }
+                            }
+                        });
+
+                    if ui.button("⟳").on_hover_text("Refresh rooms").clicked() {
+                        app.simulation_controller.handle_room_list_request(node_id, server_id_sel);
+                    }
+                    if is_member {
+                        if ui.button("Leave").clicked() {
+                            app.simulation_controller.handle_leave_room(node_id, server_id_sel, selected_room.clone());
+                        }
+                    } else if ui.button("Join").clicked() {
+                        app.simulation_controller.handle_join_room(node_id, server_id_sel, selected_room.clone());
+                    }
+
+                    let room_input = app.new_room_inputs.entry(node_id).or_default();
+                    ui.add(
+                        egui::TextEdit::singleline(room_input)
+                            .desired_width(80.0)
+                            .hint_text("New room"),
+                    );
+                    if ui.button("Create").clicked() && !room_input.trim().is_empty() {
+                        app.simulation_controller.handle_create_room(node_id, server_id_sel, room_input.trim().to_string());
+                        room_input.clear();
+                    }
+                });
+            }
+
+            show_server_capabilities(app, ui, node_id, server_id_sel);
+
             ui.separator();
 
+            // Edit/delete buttons are handled after the list is drawn
+            let mut start_editing: Option<(u64, String)> = None;
+            let mut delete_message: Option<u64> = None;
+            let mut load_attachment: Option<Attachment> = None;
+
             // Message history
             egui::ScrollArea::vertical()
                 .stick_to_right(true)
@@ -274,15 +359,60 @@ fn show_client_controls(
 
                     if let Some(servers) = app.registered_servers.get(&node_id) {
                         if servers.contains(&server_id_sel) {
-                            display_message = String::from("");
+                            display_message = if is_member {
+                                String::from("")
+                            } else {
+                                format!("Join {} to read and send its messages", selected_room)
+                            };
                             if let Some(message_list) =
-                                app.chatrooms_messages.get_mut(&server_id_sel)
+                                app.chatrooms_messages.get(&server_id_sel)
                             {
-                                for chat_message in message_list {
-                                    if chat_message.content.starts_with("Client"){
-                                        ui.label(format!("{}", chat_message.content));
-                                    }else {
-                                        ui.label(format!("Client {}: {}", chat_message.sender_id, chat_message.content));
+                                let room_messages = message_list
+                                    .iter()
+                                    .filter(|m| is_member && m.room == selected_room);
+                                for chat_message in room_messages {
+                                    let time = format_chat_time(chat_message.stored_at);
+                                    let is_notice = chat_message.content.starts_with("Client");
+                                    ui.horizontal(|ui| {
+                                        let label = if chat_message.deleted {
+                                            ui.weak(format!("[{}] Client {}: message deleted", time, chat_message.sender_id))
+                                        } else if is_notice {
+                                            ui.label(format!("[{}] {}", time, chat_message.content))
+                                        } else {
+                                            let edited = if chat_message.edited { " (edited)" } else { "" };
+                                            let via = match chat_message.origin {
+                                                Some((origin_server, _)) => format!(" [via Server {}]", origin_server),
+                                                None => String::new(),
+                                            };
+                                            ui.label(format!("[{}] Client {}{}: {}{}", time, chat_message.sender_id, via, chat_message.content, edited))
+                                        };
+                                        let relayed = match chat_message.origin {
+                                            Some((origin_server, origin_id)) => format!("\nRelayed from Server {} (message {} there)", origin_server, origin_id),
+                                            None => String::new(),
+                                        };
+                                        label.on_hover_text(format!(
+                                            "Message {}\nSent: {}\nStored by the server: {}{}",
+                                            chat_message.id,
+                                            chat_message.sent_at.map(format_chat_time).unwrap_or_else(|| "unknown".to_string()),
+                                            time,
+                                            relayed
+                                        ));
+
+                                        // Clients can only change their own messages
+                                        if chat_message.sender_id == node_id && !chat_message.deleted && !is_notice {
+                                            if ui.small_button("Edit").clicked() {
+                                                start_editing = Some((chat_message.id, chat_message.content.clone()));
+                                            }
+                                            if ui.small_button("Delete").clicked() {
+                                                delete_message = Some(chat_message.id);
+                                            }
+                                        }
+                                    });
+
+                                    if let (Some(attachment), false) = (chat_message.attachment, chat_message.deleted) {
+                                        if show_chat_attachment(app, ui, node_id, server_id_sel, attachment) {
+                                            load_attachment = Some(attachment);
+                                        }
                                     }
                                 }
                             }
@@ -291,10 +421,22 @@ fn show_client_controls(
                     ui.label(display_message);
                 });
 
+            if let Some((message_id, content)) = start_editing {
+                app.chat_editing.insert(node_id, message_id);
+                app.client_message_inputs.insert(node_id, content);
+            }
+            if let Some(message_id) = delete_message {
+                app.simulation_controller.handle_delete_chat_message(node_id, server_id_sel, message_id);
+            }
+            if let Some(attachment) = load_attachment {
+                app.simulation_controller.handle_attachment_request(node_id, server_id_sel, attachment);
+            }
+
             ui.separator();
 
-            // Message input
+            // Message input, also used to edit a message
             ui.horizontal(|ui| {
+                let editing = app.chat_editing.get(&node_id).copied();
                 let text_input = app.client_message_inputs.entry(node_id).or_default();
                 ui.add(
                     egui::TextEdit::singleline(text_input)
@@ -304,11 +446,55 @@ fn show_client_controls(
 
                 ui.add_space(2.0);
 
-                if ui.button("Send").clicked() {
-                    app.simulation_controller.handle_send_chat_message(node_id, server_id_sel, text_input.parse().unwrap());
-                    text_input.clear();
+                match editing {
+                    Some(message_id) => {
+                        if ui.button("Save").clicked() {
+                            app.simulation_controller.handle_edit_chat_message(node_id, server_id_sel, message_id, text_input.clone());
+                            text_input.clear();
+                            app.chat_editing.remove(&node_id);
+                        } else if ui.button("Cancel").clicked() {
+                            text_input.clear();
+                            app.chat_editing.remove(&node_id);
+                        }
+                    }
+                    None => {
+                        if ui.button("Send").clicked() {
+                            app.simulation_controller.handle_send_chat_message(node_id, server_id_sel, selected_room.clone(), text_input.parse().unwrap());
+                            text_input.clear();
+                        }
+                    }
+                }
+            });
+
+            // Image upload, the message text is used as caption
+            ui.horizontal(|ui| {
+                let path_input = app.attachment_path_inputs.entry(node_id).or_default();
+                ui.add(
+                    egui::TextEdit::singleline(path_input)
+                        .desired_width(ui.available_width() - 70.0)
+                        .hint_text("Image file path"),
+                );
+
+                ui.add_space(2.0);
+
+                if ui.button("Attach").clicked() && !path_input.trim().is_empty() {
+                    let path = path_input.trim().to_string();
+                    match std::fs::read(&path) {
+                        Ok(data) => {
+                            let caption = app.client_message_inputs.remove(&node_id).unwrap_or_default();
+                            app.simulation_controller.handle_send_chat_attachment(node_id, server_id_sel, selected_room.clone(), caption, data);
+                            app.attachment_errors.remove(&node_id);
+                            path_input.clear();
+                        }
+                        Err(e) => {
+                            app.attachment_errors.insert(node_id, format!("Can't read {}: {}", path, e));
+                        }
+                    }
                 }
             });
+            if let Some(error) = app.attachment_errors.get(&node_id) {
+                ui.colored_label(egui::Color32::RED, error);
+            }
         }
         ClientPopupScreen::Content => {
             let selected_server_images = app.selected_server.entry(node_id).or_default();
@@ -325,12 +511,12 @@ fn show_client_controls(
                         selected_server_images.clone()
                     })
                     .show_ui(ui, |ui| {
-                        // Get all server IDs and their types
-                        let servers = app.simulation_controller.get_servers();
+                        // Only the servers this client completed a handshake with, in a version it can talk
+                        let servers = app.server_capabilities.get(&node_id);
 
                         // Filter to only ContentServers
-                        for (server_id, (_, _, server_type)) in servers {
-                            if let ServerType::ContentServer(content_type) = server_type {
+                        for (server_id, capabilities) in servers.into_iter().flatten().filter(|(_, c)| c.is_compatible()) {
+                            if let ServerType::ContentServer(content_type) = &capabilities.server_type {
                                 let content_type_str = match content_type {
                                     ContentType::Media => "Media",
                                     ContentType::Text => "Text",
@@ -365,6 +551,7 @@ fn show_client_controls(
                     selected_type_option = Some(ContentType::Text);
                 }
             }
+            show_server_capabilities(app, ui, node_id, selected_server_id);
             ui.separator();
             
             if let Some(selected_type) = selected_type_option {
@@ -386,9 +573,37 @@ fn show_client_controls(
                                     ui.label(format!("{:?}", index_list));
                                 }
                             }
+                            show_replicas(app, ui, node_id, selected_server_id);
                         }
                         ui.separator();
 
+                        // Image upload section
+                        ui.label("Upload Image:");
+                        ui.horizontal(|ui| {
+                            let path_input = app.upload_path_inputs.entry(node_id).or_default();
+                            ui.add(
+                                egui::TextEdit::singleline(path_input)
+                                    .desired_width(ui.available_width() - 100.0)
+                                    .hint_text("Image file path"),
+                            );
+
+                            if ui.button("Upload Image").clicked() && selected_server_id > 0 && !path_input.trim().is_empty() {
+                                let path = path_input.trim().to_string();
+                                match std::fs::read(&path) {
+                                    Ok(data) => {
+                                        app.simulation_controller.handle_upload_media(node_id, selected_server_id, data);
+                                        app.upload_status.remove(&node_id);
+                                        path_input.clear();
+                                    }
+                                    Err(e) => {
+                                        app.upload_status.insert(node_id, Err(format!("Can't read {}: {}", path, e)));
+                                    }
+                                }
+                            }
+                        });
+                        show_upload_status(app, ui, node_id);
+                        ui.separator();
+
                         // Image request section
                         ui.label("Request Specific Image:");
                         ui.horizontal(|ui| {
@@ -406,10 +621,9 @@ fn show_client_controls(
                         });
                         ui.separator();
 
-                        // Display requested images grid
+                        // Display requested images grid, from whichever replica answered
                         ui.label("Requested Images:");
-                        // app.clients_downloaded_data.get_all_know_data(node_id)
-                        if let Some(image_ids) = app.clients_downloaded_data.get_know_media(node_id, selected_server_id) {
+                        if let Some(image_ids) = app.clients_downloaded_data.get_all_know_media(node_id) {
                             if image_ids.is_empty() {
                                 ui.label("No images have been requested yet.");
                             } else {
@@ -420,8 +634,7 @@ fn show_client_controls(
                                     .spacing([10.0, 10.0])
                                     .show(ui, |ui| {
                                         for (i, image_id) in image_ids.iter().enumerate() {
-                                            let image_path =
-                                                format!("server_content/media_files/{}.jpg", image_id);
+                                            let image_path = content_file_path(&ContentType::Media, *image_id);
 
                                             if let Ok(image) = image::open(&Path::new(&image_path)) {
                                                 let image_buffer = image.to_rgba8();
@@ -435,10 +648,15 @@ fn show_client_controls(
                                                     ),
                                                     egui::TextureOptions::default(),
                                                 );
-                                                ui.add(
-                                                    egui::Image::new(&texture)
-                                                        .fit_to_exact_size(egui::vec2(100.0, 100.0)),
-                                                );
+                                                ui.vertical(|ui| {
+                                                    ui.add(
+                                                        egui::Image::new(&texture)
+                                                            .fit_to_exact_size(egui::vec2(100.0, 100.0)),
+                                                    );
+                                                    if let Some(server_id) = app.clients_downloaded_data.get_media_served_by(node_id, *image_id) {
+                                                        ui.small(format!("Image {} from Server {}", image_id, server_id));
+                                                    }
+                                                });
                                             } else {
                                                 ui.label(format!("Image {} not found", image_id));
                                             }
@@ -470,7 +688,25 @@ fn show_client_controls(
                                     ui.label(format!("{:?}", index_list));
                                 }
                             }
+                            show_replicas(app, ui, node_id, selected_server_id);
+                        }
+                        ui.separator();
+
+                        // Text upload section
+                        ui.label("Upload Text:");
+                        let text_input = app.upload_text_inputs.entry(node_id).or_default();
+                        ui.add(
+                            egui::TextEdit::multiline(text_input)
+                                .desired_width(ui.available_width())
+                                .desired_rows(4)
+                                .hint_text("Text to publish, [image_N] references are allowed"),
+                        );
+                        if ui.button("Upload Text").clicked() && selected_server_id > 0 && !text_input.trim().is_empty() {
+                            app.simulation_controller.handle_upload_text(node_id, selected_server_id, text_input.clone());
+                            app.upload_status.remove(&node_id);
+                            text_input.clear();
                         }
+                        show_upload_status(app, ui, node_id);
                         ui.separator();
 
                         // Text request section
@@ -490,10 +726,9 @@ fn show_client_controls(
                         });
                         ui.separator();
 
-                        // Display requested files
+                        // Display requested files, from whichever replica answered
                         ui.label("Requested Texts:");
-                        // app.clients_downloaded_data.get_all_know_data(node_id)
-                        if let Some(files) = app.clients_downloaded_data.get_know_text(node_id, selected_server_id) {
+                        if let Some(files) = app.clients_downloaded_data.get_all_know_text(node_id) {
                             if files.is_empty() {
                                 ui.label("No Texts have been requested yet.");
                             } else {
@@ -501,8 +736,17 @@ fn show_client_controls(
                                     .max_height(200.0)
                                     .show(ui, |ui| {
                                         for file_id in files {
-                                            let file_path = format!("server_content/text_files/{}", file_id);
-                                            ui.collapsing(format!("File {}", file_id), |ui| {
+                                            let file_path = content_file_path(&ContentType::Text, file_id);
+                                            let mut title = match app.clients_downloaded_data.get_text_served_by(node_id, file_id) {
+                                                Some(server_id) => format!("File {} (from Server {})", file_id, server_id),
+                                                None => format!("File {}", file_id),
+                                            };
+                                            match app.resolved_texts.get(&node_id).and_then(|texts| texts.get(&file_id)) {
+                                                Some(missing) if missing.is_empty() => title.push_str(" ✔ images loaded"),
+                                                Some(missing) => title.push_str(&format!(" ⚠ images {:?} not found", missing)),
+                                                None => {}
+                                            }
+                                            ui.collapsing(title, |ui| {
                                                 // Read the content from the file
                                                 match std::fs::read_to_string(&file_path) {
                                                     Ok(content) => {
@@ -547,10 +791,7 @@ fn show_client_controls(
                                                                                 if let Some(media_id) = app.clients_downloaded_data
                                                                                     .get_know_media_with_id(node_id, *server_id, *image_id)
                                                                                 {
-                                                                                    let image_path = format!(
-                                                                                        "server_content/media_files/{}.jpg",
-                                                                                        media_id
-                                                                                    );
+                                                                                    let image_path = content_file_path(&ContentType::Media, media_id);
 
                                                                                     if let Ok(image) = image::open(&Path::new(&image_path)) {
                                                                                         let image_buffer = image.to_rgba8();
@@ -639,8 +880,19 @@ fn show_client_controls(
                 if app.registered_clients.is_empty() {
                     ui.label("No clients registered");
                 } else {
-                    for client_id in &app.registered_clients {
-                        ui.label(format!("Client {}", client_id));
+                    let queued = app.pending_deliveries.get(&server_id_sel);
+                    let mut clients: Vec<_> = app.registered_clients.iter().collect();
+                    clients.sort_by_key(|(client_id, _)| **client_id);
+                    for (client_id, presence) in clients {
+                        // Messages the server is still holding for the client
+                        let waiting = match queued.and_then(|q| q.get(client_id)) {
+                            Some(count) => format!(", {} queued", count),
+                            None => String::new(),
+                        };
+                        match presence {
+                            Presence::Online => ui.label(format!("🟢 Client {} (online{})", client_id, waiting)),
+                            Presence::Offline => ui.weak(format!("⚫ Client {} (offline{})", client_id, waiting)),
+                        };
                     }
                 }
 
@@ -651,3 +903,109 @@ fn show_client_controls(
             });
     }
 }
+
+// Other servers advertised as holding the files of this one
+fn show_replicas(app: &MyApp, ui: &mut egui::Ui, node_id: NodeId, server_id: NodeId) {
+    let Some(replicas) = app.clients_downloaded_data.get_replicas(node_id, server_id) else {
+        return;
+    };
+    let mut replicated: Vec<_> = replicas
+        .iter()
+        .filter(|(_, holders)| !holders.is_empty())
+        .collect();
+    if replicated.is_empty() {
+        ui.weak("No replicas of these files");
+        return;
+    }
+    replicated.sort_by_key(|(file_id, _)| **file_id);
+    ui.collapsing("Replicas", |ui| {
+        for (file_id, holders) in replicated {
+            let holders: Vec<String> = holders.iter().map(|h| format!("Server {}", h)).collect();
+            ui.label(format!("{}: also on {}", file_id, holders.join(", ")));
+        }
+    });
+}
+
+// Result of the last upload of the client to a content server
+fn show_upload_status(app: &MyApp, ui: &mut egui::Ui, node_id: NodeId) {
+    match app.upload_status.get(&node_id) {
+        Some(Ok(file_id)) => {
+            ui.colored_label(egui::Color32::GREEN, format!("Uploaded as file {}", file_id));
+        }
+        Some(Err(reason)) => {
+            ui.colored_label(egui::Color32::RED, format!("Upload failed: {}", reason));
+        }
+        None => {}
+    }
+}
+
+// Shows the image of a chat message, or a button to fetch it; true if the button was clicked
+fn show_chat_attachment(app: &MyApp, ui: &mut egui::Ui, node_id: NodeId, server_id: NodeId, attachment: Attachment) -> bool {
+    match attachment {
+        Attachment::Upload(attachment_id) => {
+            let fetched = app
+                .fetched_attachments
+                .get(&node_id)
+                .is_some_and(|fetched| fetched.contains(&(server_id, attachment_id)));
+            if let (true, Some(texture)) = (fetched, app.chat_attachments.get(&(server_id, attachment_id))) {
+                ui.add(egui::Image::new(texture).max_size(egui::vec2(150.0, 150.0)));
+                return false;
+            }
+        }
+        Attachment::Media(image_id) => {
+            // Same files the content tab shows
+            let fetched = app
+                .clients_downloaded_data
+                .get_all_know_media(node_id)
+                .is_some_and(|media| media.contains(&image_id));
+            let image_path = content_file_path(&ContentType::Media, image_id);
+            if let (true, Ok(image)) = (fetched, image::open(Path::new(&image_path))) {
+                let image_buffer = image.to_rgba8();
+                let (width, height) = image_buffer.dimensions();
+                let texture = ui.ctx().load_texture(
+                    format!("image_{}", image_id),
+                    egui::ColorImage::from_rgba_unmultiplied(
+                        [width as usize, height as usize],
+                        &image_buffer.into_raw(),
+                    ),
+                    egui::TextureOptions::default(),
+                );
+                ui.add(egui::Image::new(&texture).max_size(egui::vec2(150.0, 150.0)));
+                return false;
+            }
+        }
+    }
+    ui.small_button("📎 Load image").clicked()
+}
+
+fn format_chat_time(millis: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(millis)
+        .map(|time| time.with_timezone(&Local).format("%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn show_server_capabilities(app: &MyApp, ui: &mut egui::Ui, node_id: NodeId, server_id: NodeId) {
+    // What was agreed in the handshake between this client and the selected server
+    if let Some(capabilities) = app
+        .server_capabilities
+        .get(&node_id)
+        .and_then(|servers| servers.get(&server_id))
+    {
+        let mut features: Vec<String> = capabilities
+            .features
+            .iter()
+            .map(|feature| format!("{:?}", feature))
+            .collect();
+        features.sort();
+        ui.label(format!(
+            "Protocol v{}{}, features: {}",
+            capabilities.protocol_version,
+            if capabilities.is_compatible() { "" } else { " (too old)" },
+            if features.is_empty() {
+                "none".to_string()
+            } else {
+                features.join(", ")
+            }
+        ));
+    }
+}
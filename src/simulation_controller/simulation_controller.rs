@@ -1,14 +1,33 @@
 use crate::client_server::network_core::{
-    ClientEvent, ClientServerCommand, ServerEvent, ServerType,
+    Attachment, ClientEvent, ClientServerCommand, ServerEvent, ServerType,
 };
+use crate::simulation_controller::fault_injection::FaultInjector;
 use crate::simulation_controller::gui::MyApp;
 use crossbeam_channel::{Receiver, Sender};
 use eframe::egui;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
 use wg_2024::controller::{DroneCommand, DroneEvent};
 use wg_2024::network::NodeId;
 use wg_2024::packet::Packet;
 
+// Same limits check_toml_validity enforces on the config
+const MIN_CLIENT_DRONES: usize = 1;
+const MAX_CLIENT_DRONES: usize = 2;
+const MIN_SERVER_DRONES: usize = 2;
+
+// Why a topology change was refused
+pub enum TopologyError {
+    Invalid(String),     // the change makes no sense, e.g. linking a node to itself
+    Unsafe(Vec<String>), // the change breaks the network, it can still be forced
+}
+
+// What is wrong with a network, compared before and after a change
+struct TopologyProblems {
+    broken_rules: HashSet<String>,
+    drone_groups: usize, // drones reachable from each other, 1 if none is cut off
+}
+
 pub struct SimulationController {
     drones: HashMap<NodeId, (Sender<DroneCommand>, Vec<NodeId>, f32)>,
     clients: HashMap<NodeId, (Sender<ClientServerCommand>, Vec<NodeId>)>,
@@ -17,6 +36,7 @@ pub struct SimulationController {
     client_event_recv: Receiver<ClientEvent>,
     server_event_recv: Receiver<ServerEvent>,
     packet_channels: HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)>,
+    faults: FaultInjector, // proxies of every link, new links get one too
 }
 
 impl SimulationController {
@@ -28,6 +48,7 @@ impl SimulationController {
         client_event_recv: Receiver<ClientEvent>,
         server_event_recv: Receiver<ServerEvent>,
         packet_channels: HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)>,
+        faults: FaultInjector,
     ) -> Self {
         SimulationController {
             drones,
@@ -37,6 +58,7 @@ impl SimulationController {
             client_event_recv,
             server_event_recv,
             packet_channels,
+            faults,
         }
     }
     
@@ -45,7 +67,8 @@ impl SimulationController {
         
         if let Some((sender, _, _)) = self.drones.get(&from_id) {
             if let Some((packet_sender, _)) = self.packet_channels.get(&to_id) {
-                match sender.send(DroneCommand::AddSender(to_id, packet_sender.clone())) {
+                let proxy = self.faults.proxy(from_id, to_id, packet_sender.clone());
+                match sender.send(DroneCommand::AddSender(to_id, proxy)) {
                     Ok(_) => success = true,
                     Err(_) => return false,
                 }
@@ -53,7 +76,8 @@ impl SimulationController {
         }
         else if let Some((sender, _)) = self.clients.get(&from_id) {
             if let Some((packet_sender, _)) = self.packet_channels.get(&to_id) {
-                match sender.send(ClientServerCommand::AddDrone(to_id, packet_sender.clone())) {
+                let proxy = self.faults.proxy(from_id, to_id, packet_sender.clone());
+                match sender.send(ClientServerCommand::AddDrone(to_id, proxy)) {
                     Ok(_) => success = true,
                     Err(_) => return false,
                 }
@@ -61,7 +85,8 @@ impl SimulationController {
         }
         else if let Some((sender, _, _)) = self.servers.get(&from_id) {
             if let Some((packet_sender, _)) = self.packet_channels.get(&to_id) {
-                match sender.send(ClientServerCommand::AddDrone(to_id, packet_sender.clone())) {
+                let proxy = self.faults.proxy(from_id, to_id, packet_sender.clone());
+                match sender.send(ClientServerCommand::AddDrone(to_id, proxy)) {
                     Ok(_) => success = true,
                     Err(_) => return false,
                 }
@@ -136,7 +161,173 @@ impl SimulationController {
         }
     }
     
-    pub fn handle_add_sender(&mut self, node1_id: NodeId, node2_id: NodeId) -> bool {
+    fn neighbors_of(&self, node_id: NodeId) -> Option<&Vec<NodeId>> {
+        self.drones
+            .get(&node_id)
+            .map(|(_, neighbors, _)| neighbors)
+            .or_else(|| self.clients.get(&node_id).map(|(_, neighbors)| neighbors))
+            .or_else(|| self.servers.get(&node_id).map(|(_, neighbors, _)| neighbors))
+    }
+
+    // Analyses the neighbour lists as they would be after crashing `crashed` and changing the links
+    fn topology_problems(
+        &self,
+        crashed: Option<NodeId>,
+        removed_links: &[(NodeId, NodeId)],
+        added_links: &[(NodeId, NodeId)],
+    ) -> TopologyProblems {
+        let mut graph: HashMap<NodeId, HashSet<NodeId>> = HashMap::new();
+        let neighbor_lists = self
+            .drones
+            .iter()
+            .map(|(id, (_, neighbors, _))| (id, neighbors))
+            .chain(self.clients.iter().map(|(id, (_, neighbors))| (id, neighbors)))
+            .chain(self.servers.iter().map(|(id, (_, neighbors, _))| (id, neighbors)));
+        for (&node_id, neighbors) in neighbor_lists {
+            if Some(node_id) == crashed {
+                continue;
+            }
+            let node_neighbors = graph.entry(node_id).or_default();
+            node_neighbors.extend(neighbors.iter().filter(|&&id| Some(id) != crashed));
+        }
+        for &(node1_id, node2_id) in removed_links {
+            graph.entry(node1_id).or_default().remove(&node2_id);
+            graph.entry(node2_id).or_default().remove(&node1_id);
+        }
+        for &(node1_id, node2_id) in added_links {
+            graph.entry(node1_id).or_default().insert(node2_id);
+            graph.entry(node2_id).or_default().insert(node1_id);
+        }
+
+        let is_drone = |id: &NodeId| self.drones.contains_key(id) && Some(*id) != crashed;
+        let drone_count = |id: &NodeId| graph.get(id).map_or(0, |neighbors| neighbors.iter().filter(|n| is_drone(n)).count());
+        let mut broken_rules = HashSet::new();
+
+        // Drone-count rules
+        for client_id in self.clients.keys() {
+            let count = drone_count(client_id);
+            if count < MIN_CLIENT_DRONES {
+                broken_rules.insert(format!("Client {} has no drone", client_id));
+            } else if count > MAX_CLIENT_DRONES {
+                broken_rules.insert(format!(
+                    "Client {} has {} drones, at most {} are allowed",
+                    client_id, count, MAX_CLIENT_DRONES
+                ));
+            }
+        }
+        for server_id in self.servers.keys() {
+            let count = drone_count(server_id);
+            if count < MIN_SERVER_DRONES {
+                broken_rules.insert(format!(
+                    "Server {} has {} drone(s), at least {} are needed",
+                    server_id, count, MIN_SERVER_DRONES
+                ));
+            }
+        }
+
+        // Drones reachable from a node, only drones forward packets
+        let reachable_drones = |start: &NodeId| {
+            let mut reached: HashSet<NodeId> = HashSet::new();
+            let mut queue: VecDeque<NodeId> = graph
+                .get(start)
+                .into_iter()
+                .flatten()
+                .copied()
+                .filter(|id| is_drone(id))
+                .collect();
+            while let Some(drone_id) = queue.pop_front() {
+                if reached.insert(drone_id) {
+                    queue.extend(graph[&drone_id].iter().copied().filter(|id| is_drone(id)));
+                }
+            }
+            reached
+        };
+
+        // Every client should reach every server
+        for client_id in self.clients.keys() {
+            let reached = reachable_drones(client_id);
+            for (server_id, neighbors) in self.servers.keys().map(|id| (id, &graph[id])) {
+                if !neighbors.iter().any(|id| reached.contains(id)) {
+                    broken_rules.insert(format!("Client {} can't reach Server {}", client_id, server_id));
+                }
+            }
+        }
+
+        // Drones split into groups that can't reach each other
+        let mut grouped: HashSet<NodeId> = HashSet::new();
+        let mut drone_groups = 0;
+        for drone_id in graph.keys().filter(|id| is_drone(id)) {
+            if grouped.contains(drone_id) {
+                continue;
+            }
+            drone_groups += 1;
+            grouped.insert(*drone_id);
+            grouped.extend(reachable_drones(drone_id));
+        }
+
+        TopologyProblems {
+            broken_rules,
+            drone_groups: drone_groups.max(1),
+        }
+    }
+
+    // Refuses changes that add problems to the network, unless forced
+    fn check_safety(
+        &self,
+        force: bool,
+        crashed: Option<NodeId>,
+        removed_links: &[(NodeId, NodeId)],
+        added_links: &[(NodeId, NodeId)],
+    ) -> Result<(), TopologyError> {
+        let before = self.topology_problems(None, &[], &[]);
+        let after = self.topology_problems(crashed, removed_links, added_links);
+
+        let mut problems: Vec<String> = after
+            .broken_rules
+            .difference(&before.broken_rules)
+            .cloned()
+            .collect();
+        problems.sort();
+        if after.drone_groups > before.drone_groups {
+            problems.push(format!(
+                "The drones are split into {} groups that can't reach each other",
+                after.drone_groups
+            ));
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if force {
+            debug!("Forcing a topology change despite: {:?}", problems);
+            return Ok(());
+        }
+        Err(TopologyError::Unsafe(problems))
+    }
+
+    // Rules shared by every way of linking two nodes, from the popups or the topology editor
+    fn check_new_link(&self, node1_id: NodeId, node2_id: NodeId) -> Result<(), String> {
+        if node1_id == node2_id {
+            return Err(format!("Node {} can't be linked to itself", node1_id));
+        }
+        let neighbors = self
+            .neighbors_of(node1_id)
+            .ok_or_else(|| format!("Node {} doesn't exist", node1_id))?;
+        if self.neighbors_of(node2_id).is_none() {
+            return Err(format!("Node {} doesn't exist", node2_id));
+        }
+        if neighbors.contains(&node2_id) {
+            return Err(format!("Nodes {} and {} are already linked", node1_id, node2_id));
+        }
+        if !self.drones.contains_key(&node1_id) && !self.drones.contains_key(&node2_id) {
+            return Err("Clients and servers can only be linked to drones".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn handle_add_sender(&mut self, node1_id: NodeId, node2_id: NodeId, force: bool) -> Result<(), TopologyError> {
+        self.check_new_link(node1_id, node2_id).map_err(TopologyError::Invalid)?;
+        self.check_safety(force, None, &[], &[(node1_id, node2_id)])?;
         let node1_added_node2 = self.try_add_connection(node1_id, node2_id);
         debug!("did {} added {}? {}", node1_id, node2_id, node1_added_node2);
         let node2_added_node1 = self.try_add_connection(node2_id, node1_id);
@@ -148,12 +339,20 @@ impl SimulationController {
             self.update_neighbor_list(node2_id, node1_id, true);
 
             self.start_flood_request_for_all();
-            return true;
+            return Ok(());
         }
-        false
+        Err(TopologyError::Invalid(format!("Nodes {} and {} couldn't be linked", node1_id, node2_id)))
     }
     
-    pub fn handle_remove_sender(&mut self, node1_id: NodeId, node2_id: NodeId) -> bool {
+    pub fn handle_remove_sender(&mut self, node1_id: NodeId, node2_id: NodeId, force: bool) -> Result<(), TopologyError> {
+        let linked = self
+            .neighbors_of(node1_id)
+            .ok_or_else(|| TopologyError::Invalid(format!("Node {} doesn't exist", node1_id)))?
+            .contains(&node2_id);
+        if !linked {
+            return Err(TopologyError::Invalid(format!("Nodes {} and {} aren't linked", node1_id, node2_id)));
+        }
+        self.check_safety(force, None, &[(node1_id, node2_id)], &[])?;
         let node1_removed_node2 = self.try_remove_connection(node1_id, node2_id);
         debug!("did {} removed {}? {}", node1_id, node2_id, node1_removed_node2);
         let node2_removed_node1 = self.try_remove_connection(node2_id, node1_id);
@@ -164,9 +363,9 @@ impl SimulationController {
             self.update_neighbor_list(node2_id, node1_id, false);
             
             self.start_flood_request_for_all();
-            return true;
+            return Ok(());
         }
-        false
+        Err(TopologyError::Invalid(format!("Nodes {} and {} couldn't be unlinked", node1_id, node2_id)))
     }
 
     pub fn handle_set_packet_drop_rate(&mut self, drone_sender_id: NodeId, drop_rate: f32) {
@@ -176,23 +375,27 @@ impl SimulationController {
         }
     }
 
-    pub fn handle_crash(&mut self, drone_sender_id: NodeId) {
+    pub fn handle_crash(&mut self, drone_sender_id: NodeId, force: bool) -> Result<(), TopologyError> {
         // Get the drone's data before removing it
-        if let Some((drone_sender, neighbors, _)) = self.drones.get(&drone_sender_id).cloned() {
-            debug!("Crashing drone {} with {} neighbors...", drone_sender_id, neighbors.len());
+        let Some((drone_sender, neighbors, _)) = self.drones.get(&drone_sender_id).cloned() else {
+            return Err(TopologyError::Invalid(format!("Node {} isn't a drone", drone_sender_id)));
+        };
+        self.check_safety(force, Some(drone_sender_id), &[], &[])?;
+        debug!("Crashing drone {} with {} neighbors...", drone_sender_id, neighbors.len());
+
+        // Remove connections from all neighbors to the crashing drone
+        for &neighbor_id in &neighbors {
+            self.try_remove_connection(neighbor_id, drone_sender_id);
+        }
 
-            // Remove connections from all neighbors to the crashing drone
-            for &neighbor_id in &neighbors {
-                self.try_remove_connection(neighbor_id, drone_sender_id);
-            }
+        // Send the Crash command to the drone and remove it
+        drone_sender.send(DroneCommand::Crash).unwrap();
+        self.drones.remove(&drone_sender_id);
 
-            // Send the Crash command to the drone and remove it
-            drone_sender.send(DroneCommand::Crash).unwrap();
-            self.drones.remove(&drone_sender_id);
+        // Initialize another flooding
+        self.start_flood_request_for_all();
 
-            // Initialize another flooding
-            self.start_flood_request_for_all();
-        }
+        Ok(())
     }
 
     pub fn handle_broken_drone(&mut self, drone_id: NodeId) {
@@ -267,6 +470,10 @@ impl SimulationController {
         &self.server_event_recv
     }
 
+    pub fn get_fault_injector(&self) -> &FaultInjector {
+        &self.faults
+    }
+
     pub fn get_packet_channels(&self) -> &HashMap<NodeId, (Sender<Packet>, Receiver<Packet>)> {
         &self.packet_channels
     }
@@ -281,6 +488,27 @@ impl SimulationController {
         }
     }
 
+    // Stops every node, the controller can be dropped once they're gone. A crashed drone only
+    // stops when nobody can send it packets anymore, so its links are removed first, like
+    // handle_crash does: that closes the link proxies holding the neighbors' channels
+    pub fn shutdown(&mut self) {
+        for (sender, neighbors, _) in self.drones.values() {
+            for &neighbor_id in neighbors {
+                sender.send(DroneCommand::RemoveSender(neighbor_id)).ok();
+            }
+            sender.send(DroneCommand::Crash).ok();
+        }
+        self.packet_channels.clear();
+
+        for (sender, _) in self.clients.values() {
+            sender.send(ClientServerCommand::Shutdown).ok();
+        }
+
+        for (sender, _, _) in self.servers.values() {
+            sender.send(ClientServerCommand::Shutdown).ok();
+        }
+    }
+
     pub fn handle_registration_request(&self, client_id: NodeId, server_id: NodeId) {
         if let Some((client_sender, _)) = self.clients.get(&client_id) {
             client_sender
@@ -289,6 +517,14 @@ impl SimulationController {
         }
     }
 
+    pub fn handle_unregistration_request(&self, client_id: NodeId, server_id: NodeId) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::UnregistrationRequest(server_id))
+                .unwrap();
+        }
+    }
+
     pub fn handle_client_list_request(&self, client_id: NodeId, server_id: NodeId) {
         if let Some((client_sender, _)) = self.clients.get(&client_id) {
             client_sender
@@ -297,10 +533,74 @@ impl SimulationController {
         }
     }
 
-    pub fn handle_send_chat_message(&self, client_id: NodeId, server_id: NodeId, message: String) {
+    pub fn handle_send_chat_message(&self, client_id: NodeId, server_id: NodeId, room: String, message: String) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::SendChatMessage(server_id, room, message))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_send_chat_attachment(&self, client_id: NodeId, server_id: NodeId, room: String, caption: String, data: Vec<u8>) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::SendChatAttachment(server_id, room, caption, data))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_attachment_request(&self, client_id: NodeId, server_id: NodeId, attachment: Attachment) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::RequestAttachment(server_id, attachment))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_room_list_request(&self, client_id: NodeId, server_id: NodeId) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::RoomListRequest(server_id))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_create_room(&self, client_id: NodeId, server_id: NodeId, room: String) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::CreateRoom(server_id, room))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_join_room(&self, client_id: NodeId, server_id: NodeId, room: String) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::JoinRoom(server_id, room))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_leave_room(&self, client_id: NodeId, server_id: NodeId, room: String) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::LeaveRoom(server_id, room))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_edit_chat_message(&self, client_id: NodeId, server_id: NodeId, message_id: u64, message: String) {
         if let Some((client_sender, _)) = self.clients.get(&client_id) {
             client_sender
-                .send(ClientServerCommand::SendChatMessage(server_id, message))
+                .send(ClientServerCommand::EditChatMessage(server_id, message_id, message))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_delete_chat_message(&self, client_id: NodeId, server_id: NodeId, message_id: u64) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::DeleteChatMessage(server_id, message_id))
                 .unwrap();
         }
     }
@@ -337,6 +637,22 @@ impl SimulationController {
         }
     }
     
+    pub fn handle_upload_text(&self, client_id: NodeId, server_id: NodeId, text: String) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::UploadText(server_id, text))
+                .unwrap();
+        }
+    }
+
+    pub fn handle_upload_media(&self, client_id: NodeId, server_id: NodeId, data: Vec<u8>) {
+        if let Some((client_sender, _)) = self.clients.get(&client_id) {
+            client_sender
+                .send(ClientServerCommand::UploadMedia(server_id, data))
+                .unwrap();
+        }
+    }
+
     pub fn handle_print_all_node_data_command(&self, node_id: NodeId) {
         if let Some((client_sender, _)) = self.clients.get(&node_id) {
             client_sender
@@ -350,9 +666,29 @@ impl SimulationController {
                 .unwrap();
         }
     }
+
+    // The answer arrives as a NodeState client or server event
+    pub fn handle_query_node_state(&self, node_id: NodeId) {
+        if let Some((client_sender, _)) = self.clients.get(&node_id) {
+            client_sender
+                .send(ClientServerCommand::QueryNodeState)
+                .unwrap();
+        }
+
+        if let Some((server_sender, _, _)) = self.servers.get(&node_id) {
+            server_sender
+                .send(ClientServerCommand::QueryNodeState)
+                .unwrap();
+        }
+    }
 }
 
-pub fn simulation_controller_main(sc: SimulationController) -> Result<(), eframe::Error> {
+pub fn simulation_controller_main(
+    sc: SimulationController,
+    event_log_dir: PathBuf,
+    layout_path: PathBuf,
+    scenario_path: PathBuf,
+) -> Result<(), eframe::Error> {
     // Setup Client and Server
     sc.start_flood_request_for_all();
 
@@ -362,6 +698,6 @@ pub fn simulation_controller_main(sc: SimulationController) -> Result<(), eframe
     eframe::run_native(
         "Rustaceans Wit Attitudes",
         native_options,
-        Box::new(|_cc| Ok(Box::new(MyApp::new(sc)))),
+        Box::new(move |_cc| Ok(Box::new(MyApp::new(sc, &event_log_dir, layout_path, scenario_path)))),
     )
 }
@@ -0,0 +1,441 @@
+#[cfg(feature = "debug")]
+use crate::debug;
+
+use crate::client_server::network_core::{ClientEvent, ServerEvent};
+use crate::message::message::{
+    ChatResponse, MediaResponseForMessageContent, MessageContent, Request, ServerTypeResponse,
+    TextResponse,
+};
+use chrono::Utc;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use wg_2024::controller::DroneEvent;
+use wg_2024::network::NodeId;
+use wg_2024::packet::{Packet, PacketType};
+
+// A request still unanswered after this long counts as failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// The plots get one point per interval and keep the last MAX_SAMPLES
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+pub const MAX_SAMPLES: usize = 120;
+// Fragments and floods are remembered for as long as the plots go back, a retransmission or
+// flood older than that is counted as new
+const SEEN_RETENTION: Duration = Duration::from_secs(SAMPLE_INTERVAL.as_secs() * MAX_SAMPLES as u64);
+
+#[derive(Default)]
+pub struct DroneStats {
+    pub forwarded: u64, // fragments sent on to the next hop
+    pub dropped: u64,
+}
+
+impl DroneStats {
+    pub fn drop_rate(&self) -> Option<f64> {
+        let handled = self.forwarded + self.dropped;
+        (handled > 0).then(|| self.dropped as f64 / handled as f64)
+    }
+}
+
+#[derive(Default)]
+pub struct RequestStats {
+    pub sent: u64,
+    pub succeeded: u64,
+    pub failed: u64, // error answers and timeouts
+    total_latency_millis: u64,
+    answered: u64,
+}
+
+impl RequestStats {
+    pub fn success_rate(&self) -> Option<f64> {
+        let finished = self.succeeded + self.failed;
+        (finished > 0).then(|| self.succeeded as f64 / finished as f64)
+    }
+
+    pub fn average_latency_millis(&self) -> Option<f64> {
+        (self.answered > 0).then(|| self.total_latency_millis as f64 / self.answered as f64)
+    }
+}
+
+#[derive(Default)]
+pub struct ServerStats {
+    pub bytes_served: u64,
+    pub fragments_sent: u64,
+}
+
+// What happened in the network during one SAMPLE_INTERVAL
+#[derive(Default, Clone)]
+pub struct Sample {
+    pub elapsed_secs: f64,
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub retransmissions: u64,
+    pub flood_packets: u64,
+    pub bytes_served: u64,
+    pub requests_succeeded: u64,
+    pub requests_failed: u64,
+}
+
+// A request a client is waiting an answer for
+struct PendingRequest {
+    family: &'static str,
+    request_type: String,
+    sent_at: Instant,
+}
+
+// Which requests a response can be the answer to
+struct Answer {
+    family: Option<&'static str>,   // None for errors, they can answer anything
+    request_types: &'static [&'static str], // empty for any request of the family
+    succeeded: bool,
+}
+
+// Counters fed by the same events shown in the Logs page
+pub struct NetworkStats {
+    pub drones: HashMap<NodeId, DroneStats>,
+    pub requests: HashMap<(NodeId, String), RequestStats>, // (client, request type)
+    pub servers: HashMap<NodeId, ServerStats>,
+    pub floods_started: u64,
+    pub flood_packets: u64,
+    pub retransmissions: u64,
+    pub samples: VecDeque<Sample>,
+    pending: HashMap<NodeId, Vec<PendingRequest>>, // oldest first
+    sent_fragments: HashMap<(NodeId, u64, u64), Instant>, // (origin, session, fragment index) -> last sent
+    seen_floods: HashMap<(NodeId, u64), Instant>,          // (initiator, flood id) -> last seen
+    current: Sample,
+    started_at: Instant,
+    sampled_at: Instant,
+}
+
+impl NetworkStats {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            drones: HashMap::new(),
+            requests: HashMap::new(),
+            servers: HashMap::new(),
+            floods_started: 0,
+            flood_packets: 0,
+            retransmissions: 0,
+            samples: VecDeque::new(),
+            pending: HashMap::new(),
+            sent_fragments: HashMap::new(),
+            seen_floods: HashMap::new(),
+            current: Sample::default(),
+            started_at: now,
+            sampled_at: now,
+        }
+    }
+
+    pub fn record_drone_event(&mut self, event: &DroneEvent) {
+        match event {
+            DroneEvent::PacketSent(packet) => match &packet.pack_type {
+                PacketType::MsgFragment(_) => {
+                    // The hop index already points at the next hop
+                    let routing_header = &packet.routing_header;
+                    let forwarded_by = routing_header
+                        .hop_index
+                        .checked_sub(1)
+                        .and_then(|i| routing_header.hops.get(i));
+                    if let Some(&drone_id) = forwarded_by {
+                        self.drones.entry(drone_id).or_default().forwarded += 1;
+                        self.current.forwarded += 1;
+                    }
+                }
+                PacketType::FloodRequest(_) => self.count_flood_packet(),
+                _ => {}
+            },
+            DroneEvent::PacketDropped(packet) => {
+                if let PacketType::MsgFragment(_) = packet.pack_type {
+                    let routing_header = &packet.routing_header;
+                    if let Some(&drone_id) = routing_header.hops.get(routing_header.hop_index) {
+                        self.drones.entry(drone_id).or_default().dropped += 1;
+                        self.current.dropped += 1;
+                    }
+                }
+            }
+            DroneEvent::ControllerShortcut(_) => {}
+        }
+    }
+
+    pub fn record_client_event(&mut self, event: &ClientEvent) {
+        match event {
+            ClientEvent::PacketSent(packet) => self.record_sent_packet(packet),
+            ClientEvent::MessageSent { from, content, .. } => self.request_sent(*from, content),
+            ClientEvent::MessageReceived { receiver, content } => {
+                self.response_received(*receiver, content)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record_server_event(&mut self, event: &ServerEvent) {
+        if let ServerEvent::PacketSent(packet) = event {
+            self.record_sent_packet(packet);
+            if let (PacketType::MsgFragment(fragment), Some(&server_id)) =
+                (&packet.pack_type, packet.routing_header.hops.first())
+            {
+                let server = self.servers.entry(server_id).or_default();
+                server.bytes_served += fragment.length as u64;
+                server.fragments_sent += 1;
+                self.current.bytes_served += fragment.length as u64;
+            }
+        }
+    }
+
+    // Times out old requests and closes the current sample, called once per frame
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        for (client_id, pending) in self.pending.iter_mut() {
+            pending.retain(|request| {
+                if now.duration_since(request.sent_at) < REQUEST_TIMEOUT {
+                    return true;
+                }
+                let label = format!("{} {}", request.family, request.request_type);
+                self.requests.entry((*client_id, label)).or_default().failed += 1;
+                self.current.requests_failed += 1;
+                false
+            });
+        }
+
+        if now.duration_since(self.sampled_at) >= SAMPLE_INTERVAL {
+            let mut sample = std::mem::take(&mut self.current);
+            sample.elapsed_secs = now.duration_since(self.started_at).as_secs_f64();
+            self.samples.push_back(sample);
+            if self.samples.len() > MAX_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.sent_fragments
+                .retain(|_, sent_at| now.duration_since(*sent_at) < SEEN_RETENTION);
+            self.seen_floods
+                .retain(|_, seen_at| now.duration_since(*seen_at) < SEEN_RETENTION);
+            self.sampled_at = now;
+        }
+    }
+
+    // Fragments and floods started by clients and servers
+    fn record_sent_packet(&mut self, packet: &Packet) {
+        match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => {
+                let Some(&origin) = packet.routing_header.hops.first() else {
+                    return;
+                };
+                if self
+                    .sent_fragments
+                    .insert((origin, packet.session_id, fragment.fragment_index), Instant::now())
+                    .is_some()
+                {
+                    self.retransmissions += 1;
+                    self.current.retransmissions += 1;
+                }
+            }
+            PacketType::FloodRequest(flood_request) => {
+                if self
+                    .seen_floods
+                    .insert((flood_request.initiator_id, flood_request.flood_id), Instant::now())
+                    .is_none()
+                {
+                    self.floods_started += 1;
+                }
+                self.count_flood_packet();
+            }
+            _ => {}
+        }
+    }
+
+    fn count_flood_packet(&mut self) {
+        self.flood_packets += 1;
+        self.current.flood_packets += 1;
+    }
+
+    fn request_sent(&mut self, client_id: NodeId, content: &MessageContent) {
+        let (family, request_type) = match content {
+            MessageContent::ServerTypeRequest(request) => ("ServerType", request.request_type()),
+            MessageContent::TextRequest(request) => ("Text", request.request_type()),
+            MessageContent::MediaRequest(request) => ("Media", request.request_type()),
+            MessageContent::ChatRequest(request) => ("Chat", request.request_type()),
+            _ => return,
+        };
+        // Nothing answers these
+        if request_type == "Heartbeat" || request_type == "Relay" {
+            return;
+        }
+
+        let label = format!("{} {}", family, request_type);
+        self.requests.entry((client_id, label)).or_default().sent += 1;
+        self.pending
+            .entry(client_id)
+            .or_default()
+            .push(PendingRequest {
+                family,
+                request_type,
+                sent_at: Instant::now(),
+            });
+    }
+
+    // Closes the oldest pending request the response can answer
+    fn response_received(&mut self, client_id: NodeId, content: &MessageContent) {
+        let Some(answer) = Self::answer(content) else {
+            return;
+        };
+        let Some(pending) = self.pending.get_mut(&client_id) else {
+            return;
+        };
+        let Some(position) = pending.iter().position(|request| {
+            answer.family.is_none_or(|family| family == request.family)
+                && (answer.request_types.is_empty()
+                    || answer.request_types.contains(&request.request_type.as_str()))
+        }) else {
+            debug!("Client {} got an answer to no pending request", client_id);
+            return;
+        };
+
+        let request = pending.remove(position);
+        let label = format!("{} {}", request.family, request.request_type);
+        let stats = self.requests.entry((client_id, label)).or_default();
+        stats.answered += 1;
+        stats.total_latency_millis += request.sent_at.elapsed().as_millis() as u64;
+        if answer.succeeded {
+            stats.succeeded += 1;
+            self.current.requests_succeeded += 1;
+        } else {
+            stats.failed += 1;
+            self.current.requests_failed += 1;
+        }
+    }
+
+    fn answer(content: &MessageContent) -> Option<Answer> {
+        let answer = |family, request_types, succeeded| {
+            Some(Answer {
+                family: Some(family),
+                request_types,
+                succeeded,
+            })
+        };
+        match content {
+            MessageContent::ServerTypeResponse(response) => match response {
+                ServerTypeResponse::ServerType(_) => answer("ServerType", &["GetServerType"], true),
+                ServerTypeResponse::Handshake { .. } => answer("ServerType", &["Handshake"], true),
+            },
+            MessageContent::TextResponse(response) => match response {
+                TextResponse::TextList(_) => answer("Text", &["TextList"], true),
+                TextResponse::Text(_) => answer("Text", &["Text"], true),
+                TextResponse::NotFound(_) => answer("Text", &["Text"], false),
+                TextResponse::Uploaded(_) => answer("Text", &["Upload"], true),
+                TextResponse::UploadRejected(_) => answer("Text", &["Upload"], false),
+                TextResponse::Replicas(_) => answer("Text", &["Replicas"], true),
+            },
+            MessageContent::MediaResponse(response) => match response {
+                MediaResponseForMessageContent::MediaList(_) => answer("Media", &["MediaList"], true),
+                MediaResponseForMessageContent::Media(_) => answer("Media", &["Media"], true),
+                MediaResponseForMessageContent::NotFound(_) => answer("Media", &["Media"], false),
+                MediaResponseForMessageContent::Uploaded(_) => answer("Media", &["Upload"], true),
+                MediaResponseForMessageContent::UploadRejected(_) => answer("Media", &["Upload"], false),
+                MediaResponseForMessageContent::Replicas(_) => answer("Media", &["Replicas"], true),
+            },
+            MessageContent::ChatResponse(response) => match response {
+                ChatResponse::ClientList(_) => answer("Chat", &["ClientList"], true),
+                ChatResponse::MessageFrom { .. } => None, // pushed by the server, not an answer
+                ChatResponse::MessageSent(_) => answer("Chat", &["SendMessage"], true),
+                ChatResponse::ClientNotRegistered => answer("Chat", &[], false),
+                ChatResponse::ClientRegistered(_) => answer("Chat", &["Register"], true),
+                ChatResponse::ClientUnregistered(_) => answer("Chat", &["Unregister"], true),
+                ChatResponse::History(_) => answer("Chat", &["History"], true),
+                ChatResponse::MessageEdited(_) => answer("Chat", &["Edit"], true),
+                ChatResponse::MessageDeleted(_) => answer("Chat", &["Delete"], true),
+                ChatResponse::EditRejected(_) => answer("Chat", &["Edit", "Delete"], false),
+                ChatResponse::RoomList(_) => answer("Chat", &["ListRooms"], true),
+                ChatResponse::RoomJoined(_) => answer("Chat", &["JoinRoom", "CreateRoom"], true),
+                ChatResponse::RoomLeft(_) => answer("Chat", &["LeaveRoom"], true),
+                ChatResponse::RoomNotFound(_) => answer("Chat", &["JoinRoom", "LeaveRoom"], false),
+                ChatResponse::NotInRoom(_) => answer("Chat", &["LeaveRoom", "SendMessage", "History"], false),
+                ChatResponse::AttachmentUploaded { .. } => answer("Chat", &["UploadAttachment"], true),
+                ChatResponse::AttachmentRejected { .. } => answer("Chat", &["UploadAttachment"], false),
+                ChatResponse::Attachment(_, _) => answer("Chat", &["GetAttachment"], true),
+                ChatResponse::AttachmentNotFound(_) => answer("Chat", &["GetAttachment"], false),
+            },
+            MessageContent::ErrorResponse(_) => Some(Answer {
+                family: None,
+                request_types: &[],
+                succeeded: false,
+            }),
+            _ => None,
+        }
+    }
+
+    // Writes the totals and the time series next to the event logs
+    pub fn export_csv(&self, dir: &Path, pdrs: &HashMap<NodeId, f32>) -> Result<(PathBuf, PathBuf), String> {
+        fs::create_dir_all(dir).map_err(|e| format!("failed to create {:?}: {}", dir, e))?;
+        let now = Utc::now().format("%Y%m%d_%H%M%S");
+        let totals_path = dir.join(format!("stats_{}.csv", now));
+        let series_path = dir.join(format!("stats_{}_series.csv", now));
+
+        let mut totals = String::from("kind,node_id,request_type,metric,value\n");
+        let mut drone_ids: Vec<&NodeId> = self.drones.keys().chain(pdrs.keys()).collect();
+        drone_ids.sort();
+        drone_ids.dedup();
+        for drone_id in drone_ids {
+            let stats = self.drones.get(drone_id);
+            let forwarded = stats.map_or(0, |stats| stats.forwarded);
+            let dropped = stats.map_or(0, |stats| stats.dropped);
+            totals.push_str(&format!("drone,{},,forwarded,{}\n", drone_id, forwarded));
+            totals.push_str(&format!("drone,{},,dropped,{}\n", drone_id, dropped));
+            if let Some(drop_rate) = stats.and_then(DroneStats::drop_rate) {
+                totals.push_str(&format!("drone,{},,observed_drop_rate,{:.4}\n", drone_id, drop_rate));
+            }
+            if let Some(pdr) = pdrs.get(drone_id) {
+                totals.push_str(&format!("drone,{},,configured_pdr,{}\n", drone_id, pdr));
+            }
+        }
+
+        let mut requests: Vec<(&(NodeId, String), &RequestStats)> = self.requests.iter().collect();
+        requests.sort_by(|a, b| a.0.cmp(b.0));
+        for ((client_id, request_type), stats) in requests {
+            let row = |metric: &str, value: String| {
+                format!("client,{},{},{},{}\n", client_id, request_type, metric, value)
+            };
+            totals.push_str(&row("sent", stats.sent.to_string()));
+            totals.push_str(&row("succeeded", stats.succeeded.to_string()));
+            totals.push_str(&row("failed", stats.failed.to_string()));
+            if let Some(success_rate) = stats.success_rate() {
+                totals.push_str(&row("success_rate", format!("{:.4}", success_rate)));
+            }
+            if let Some(latency) = stats.average_latency_millis() {
+                totals.push_str(&row("average_latency_ms", format!("{:.1}", latency)));
+            }
+        }
+
+        let mut servers: Vec<(&NodeId, &ServerStats)> = self.servers.iter().collect();
+        servers.sort_by_key(|(server_id, _)| **server_id);
+        for (server_id, stats) in servers {
+            totals.push_str(&format!("server,{},,bytes_served,{}\n", server_id, stats.bytes_served));
+            totals.push_str(&format!("server,{},,fragments_sent,{}\n", server_id, stats.fragments_sent));
+        }
+
+        totals.push_str(&format!("network,,,floods_started,{}\n", self.floods_started));
+        totals.push_str(&format!("network,,,flood_packets,{}\n", self.flood_packets));
+        totals.push_str(&format!("network,,,retransmissions,{}\n", self.retransmissions));
+
+        let mut series = String::from(
+            "elapsed_secs,forwarded,dropped,retransmissions,flood_packets,bytes_served,requests_succeeded,requests_failed\n",
+        );
+        for sample in &self.samples {
+            series.push_str(&format!(
+                "{:.1},{},{},{},{},{},{},{}\n",
+                sample.elapsed_secs,
+                sample.forwarded,
+                sample.dropped,
+                sample.retransmissions,
+                sample.flood_packets,
+                sample.bytes_served,
+                sample.requests_succeeded,
+                sample.requests_failed
+            ));
+        }
+
+        fs::write(&totals_path, totals).map_err(|e| format!("failed to write {:?}: {}", totals_path, e))?;
+        fs::write(&series_path, series).map_err(|e| format!("failed to write {:?}: {}", series_path, e))?;
+        debug!("Exported the network stats to {:?} and {:?}", totals_path, series_path);
+        Ok((totals_path, series_path))
+    }
+}